@@ -0,0 +1,33 @@
+use ktc32_emu::emulator::{Emulator, Engine};
+use std::time::Instant;
+
+// LUI r1 0x100; loop: ADDI r2 r2 1; ADDI r1 r1 -1; BNQ r1 r0 -12
+const PROGRAM: [u32; 4] = [0x0100_0072, 0x0001_10a0, 0xffff_0860, 0xfff4_0067];
+
+fn bench(name: &str, engine: Engine) {
+    let program = PROGRAM
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect::<Vec<_>>();
+    let mut emu = Emulator::new(program);
+    emu.memory.init();
+    emu.trace = false;
+    emu.engine = engine;
+
+    let start = Instant::now();
+    emu.run().unwrap();
+    let elapsed = start.elapsed();
+
+    println!(
+        "{} : {} instructions in {:.3} s, {:.1} M instructions/s",
+        name,
+        emu.instret,
+        elapsed.as_secs_f64(),
+        emu.instret as f64 / elapsed.as_secs_f64() / 1_000_000.0
+    );
+}
+
+fn main() {
+    bench("interpreter", Engine::Interpreter);
+    bench("threaded", Engine::Threaded);
+}
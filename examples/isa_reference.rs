@@ -0,0 +1,4 @@
+// Prints the generated ISA reference, see docs/isa.md
+fn main() {
+    print!("{}", ktc32_emu::emulator::isa::reference());
+}
@@ -0,0 +1,387 @@
+use crate::emulator::encoder::encode_in;
+use crate::emulator::memory::ByteOrder;
+use crate::emulator::{Emulator, Type};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+#[allow(non_upper_case_globals)]
+pub mod reg {
+    pub const r0: u8 = 0;
+    pub const r1: u8 = 1;
+    pub const r2: u8 = 2;
+    pub const r3: u8 = 3;
+    pub const r4: u8 = 4;
+    pub const r5: u8 = 5;
+    pub const r6: u8 = 6;
+    pub const r7: u8 = 7;
+    pub const r8: u8 = 8;
+    pub const r9: u8 = 9;
+    pub const r10: u8 = 10;
+    pub const r11: u8 = 11;
+    pub const r12: u8 = 12;
+    pub const r13: u8 = 13;
+    pub const r14: u8 = 14;
+    pub const r15: u8 = 15;
+    pub const r16: u8 = 16;
+    pub const r17: u8 = 17;
+    pub const r18: u8 = 18;
+    pub const r19: u8 = 19;
+    pub const r20: u8 = 20;
+    pub const r21: u8 = 21;
+    pub const r22: u8 = 22;
+    pub const r23: u8 = 23;
+    pub const r24: u8 = 24;
+    pub const r25: u8 = 25;
+    pub const r26: u8 = 26;
+    pub const r27: u8 = 27;
+    pub const r28: u8 = 28;
+    pub const r29: u8 = 29;
+    pub const r30: u8 = 30;
+    pub const r31: u8 = 31;
+}
+
+// instruction whose immediate is resolved from a label at build time
+#[derive(Debug)]
+struct Item {
+    inst: Type,
+    pc: u32,
+    target: Option<String>,
+}
+
+// Builds a program image, e.g.
+// Asm::new().addi(r1, r0, 5).label("loop").addi(r1, r1, -1).bnq(r1, r0, "loop").build()
+#[derive(Debug, Default)]
+pub struct Asm {
+    items: Vec<Item>,
+    labels: HashMap<String, u32>,
+    duplicates: Vec<String>,
+    pc: u32,
+    byte_order: ByteOrder,
+}
+
+impl Asm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    // byte order of the built program and of the memory of emulator()
+    pub fn byte_order(&mut self, order: ByteOrder) -> &mut Self {
+        self.byte_order = order;
+        self
+    }
+
+    pub fn label(&mut self, name: &str) -> &mut Self {
+        if self.labels.insert(name.to_string(), self.pc).is_some() {
+            self.duplicates.push(name.to_string());
+        }
+        self
+    }
+
+    pub fn inst(&mut self, inst: Type) -> &mut Self {
+        self.push(inst, None)
+    }
+
+    fn push(&mut self, inst: Type, target: Option<&str>) -> &mut Self {
+        let length = match inst {
+            Type::RFormat { .. } | Type::I16Format { .. } => 2,
+            Type::I32Format { .. } | Type::JFormat { .. } => 4,
+        };
+        self.items.push(Item {
+            inst,
+            pc: self.pc,
+            target: target.map(String::from),
+        });
+        self.pc += length;
+        self
+    }
+
+    fn r(&mut self, mnemonic: &'static str, rd: u8, rs: u8) -> &mut Self {
+        self.inst(Type::RFormat { mnemonic, rd, rs })
+    }
+
+    fn i16(&mut self, mnemonic: &'static str, rd: u8, imm: u8) -> &mut Self {
+        self.inst(Type::I16Format { mnemonic, rd, imm })
+    }
+
+    fn i32(&mut self, mnemonic: &'static str, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.inst(Type::I32Format {
+            mnemonic,
+            rd,
+            rs,
+            imm,
+        })
+    }
+
+    fn branch(&mut self, mnemonic: &'static str, rd: u8, rs: u8, label: &str) -> &mut Self {
+        let inst = Type::I32Format {
+            mnemonic,
+            rd,
+            rs,
+            imm: 0,
+        };
+        self.push(inst, Some(label))
+    }
+
+    pub fn mov(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("MOV", rd, rs)
+    }
+    pub fn add(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("ADD", rd, rs)
+    }
+    pub fn sub(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("SUB", rd, rs)
+    }
+    pub fn and(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("AND", rd, rs)
+    }
+    pub fn or(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("OR", rd, rs)
+    }
+    pub fn xor(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("XOR", rd, rs)
+    }
+    pub fn sll(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("SLL", rd, rs)
+    }
+    pub fn srl(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("SRL", rd, rs)
+    }
+    pub fn sra(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("SRA", rd, rs)
+    }
+    pub fn slt(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("SLT", rd, rs)
+    }
+    pub fn sltu(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("SLTU", rd, rs)
+    }
+    pub fn mul(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("MUL", rd, rs)
+    }
+    pub fn mulh(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("MULH", rd, rs)
+    }
+    pub fn mulhu(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("MULHU", rd, rs)
+    }
+    pub fn div(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("DIV", rd, rs)
+    }
+    pub fn divu(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("DIVU", rd, rs)
+    }
+    pub fn rem(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("REM", rd, rs)
+    }
+    pub fn remu(&mut self, rd: u8, rs: u8) -> &mut Self {
+        self.r("REMU", rd, rs)
+    }
+
+    pub fn slli(&mut self, rd: u8, imm: u8) -> &mut Self {
+        self.i16("SLLI", rd, imm)
+    }
+    pub fn srli(&mut self, rd: u8, imm: u8) -> &mut Self {
+        self.i16("SRLI", rd, imm)
+    }
+    pub fn srai(&mut self, rd: u8, imm: u8) -> &mut Self {
+        self.i16("SRAI", rd, imm)
+    }
+    pub fn trap(&mut self, imm: u8) -> &mut Self {
+        self.i16("TRAP", 0, imm)
+    }
+
+    pub fn addi(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("ADDI", rd, rs, imm)
+    }
+    pub fn andi(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("ANDI", rd, rs, imm)
+    }
+    pub fn ori(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("ORI", rd, rs, imm)
+    }
+    pub fn xori(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("XORI", rd, rs, imm)
+    }
+    pub fn slti(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("SLTI", rd, rs, imm)
+    }
+    pub fn sltiu(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("SLTIU", rd, rs, imm)
+    }
+
+    pub fn beq(&mut self, rd: u8, rs: u8, label: &str) -> &mut Self {
+        self.branch("BEQ", rd, rs, label)
+    }
+    pub fn bnq(&mut self, rd: u8, rs: u8, label: &str) -> &mut Self {
+        self.branch("BNQ", rd, rs, label)
+    }
+    pub fn bne(&mut self, rd: u8, rs: u8, label: &str) -> &mut Self {
+        self.bnq(rd, rs, label)
+    }
+    pub fn blt(&mut self, rd: u8, rs: u8, label: &str) -> &mut Self {
+        self.branch("BLT", rd, rs, label)
+    }
+    pub fn bge(&mut self, rd: u8, rs: u8, label: &str) -> &mut Self {
+        self.branch("BGE", rd, rs, label)
+    }
+    pub fn bltu(&mut self, rd: u8, rs: u8, label: &str) -> &mut Self {
+        self.branch("BLTU", rd, rs, label)
+    }
+    pub fn bgeu(&mut self, rd: u8, rs: u8, label: &str) -> &mut Self {
+        self.branch("BGEU", rd, rs, label)
+    }
+
+    pub fn jalr(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("JALR", rd, rs, imm)
+    }
+    pub fn jal(&mut self, rd: u8, label: &str) -> &mut Self {
+        let inst = Type::JFormat {
+            mnemonic: "JAL",
+            rd,
+            imm: 0,
+        };
+        self.push(inst, Some(label))
+    }
+
+    pub fn lb(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("LB", rd, rs, imm)
+    }
+    pub fn lh(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("LH", rd, rs, imm)
+    }
+    pub fn lbu(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("LBU", rd, rs, imm)
+    }
+    pub fn lhu(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("LHU", rd, rs, imm)
+    }
+    pub fn lw(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("LW", rd, rs, imm)
+    }
+    pub fn lui(&mut self, rd: u8, imm: i32) -> &mut Self {
+        self.i32("LUI", rd, 0, imm)
+    }
+
+    // stores write rd to rs + imm
+    pub fn sb(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("SB", rd, rs, imm)
+    }
+    pub fn sh(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("SH", rd, rs, imm)
+    }
+    pub fn sw(&mut self, rd: u8, rs: u8, imm: i32) -> &mut Self {
+        self.i32("SW", rd, rs, imm)
+    }
+
+    // load a 32-bit constant with LUI and ADDI
+    pub fn li(&mut self, rd: u8, value: u32) -> &mut Self {
+        let low = value as u16 as i16 as i32;
+        let high = value.wrapping_sub(low as u32) >> 16;
+        self.lui(rd, high as u16 as i16 as i32).addi(rd, rd, low)
+    }
+
+    pub fn j(&mut self, label: &str) -> &mut Self {
+        self.jal(0, label)
+    }
+
+    // jump to itself, see Emulator::run_until_halt
+    pub fn halt(&mut self) -> &mut Self {
+        self.inst(Type::JFormat {
+            mnemonic: "JAL",
+            rd: 0,
+            imm: -4,
+        })
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>> {
+        if let Some(name) = self.duplicates.first() {
+            return Err(anyhow!("label {} is defined more than once", name));
+        }
+
+        let mut program = Vec::new();
+        for item in &self.items {
+            let mut inst = item.inst;
+            if let Some(label) = &item.target {
+                let target = self
+                    .labels
+                    .get(label)
+                    .ok_or_else(|| anyhow!("label {} is not defined", label))?;
+                let offset = target.wrapping_sub(item.pc + 4) as i32;
+                match &mut inst {
+                    Type::I32Format { imm, .. } | Type::JFormat { imm, .. } => *imm = offset,
+                    _ => {}
+                }
+            }
+            let bytes = encode_in(&inst, self.byte_order)
+                .map_err(|error| anyhow!("0x{:08x} {}: {}", item.pc, inst.mnemonic(), error))?;
+            program.extend_from_slice(&bytes);
+        }
+        Ok(program)
+    }
+
+    // emulator with the program loaded and tracing off
+    pub fn emulator(&self) -> Result<Emulator> {
+        let mut emu = Emulator::new(self.build()?);
+        emu.memory.byte_order = self.byte_order;
+        emu.memory.init();
+        emu.trace = false;
+        Ok(emu)
+    }
+}
+
+#[macro_export]
+macro_rules! assert_reg {
+    ($emu:expr, $reg:expr, $expected:expr) => {
+        assert_eq!(
+            $emu.cpu.get_reg($reg),
+            $expected,
+            "r{} = 0x{:08x}",
+            $reg,
+            $emu.cpu.get_reg($reg)
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reg::*;
+    use super::*;
+
+    #[test]
+    fn resolves_forward_and_backward_labels() {
+        let mut emu = Asm::new()
+            .addi(r1, r0, 5)
+            .j("loop")
+            .addi(r2, r0, 100)
+            .label("loop")
+            .addi(r2, r2, 1)
+            .addi(r1, r1, -1)
+            .bne(r1, r0, "loop")
+            .halt()
+            .emulator()
+            .unwrap();
+        emu.run_until_halt(1000).unwrap();
+        assert_reg!(emu, r1, 0);
+        assert_reg!(emu, r2, 5);
+    }
+
+    #[test]
+    fn reports_label_errors() {
+        assert!(Asm::new().j("nowhere").build().is_err());
+        assert!(Asm::new().label("a").label("a").build().is_err());
+        assert!(Asm::new().addi(r1, r0, 0x8000).build().is_err());
+    }
+
+    #[test]
+    fn li_loads_any_constant() {
+        for value in [0, 0x7FFF, 0x8000, 0xFFFF_8000, 0x1234_5678, 0xFFFF_FFFF] {
+            let mut emu = Asm::new().li(r3, value).halt().emulator().unwrap();
+            emu.run_until_halt(10).unwrap();
+            assert_reg!(emu, r3, value);
+        }
+    }
+}
@@ -0,0 +1,738 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::emulator::{memory, Emulator};
+use crate::script;
+
+// source files may source other files up to this depth
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Finish,
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    pub vars: BTreeMap<String, u32>,
+    // reading from the prompt, missing arguments are asked for
+    interactive: bool,
+    depth: usize,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn repl(&mut self, emu: &mut Emulator) -> Result<()> {
+        loop {
+            let line = match prompt("> ")? {
+                Some(line) => line,
+                None => return Ok(()),
+            };
+
+            let result = if is_block(&line) {
+                // read the rest of the block before running any of it
+                let mut text = line;
+                while block_end(&lines(&text))?.is_none() {
+                    match prompt("... ")? {
+                        Some(line) => {
+                            text.push('\n');
+                            text.push_str(&line);
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                self.run_script(emu, "<stdin>", &text)
+            } else {
+                self.interactive = true;
+                let result = self.command(emu, &line);
+                self.interactive = false;
+                result
+            };
+
+            match result {
+                Ok(Flow::Finish) => return Ok(()),
+                Ok(Flow::Continue) => {}
+                Err(error) => println!("{:#}", error),
+            }
+        }
+    }
+
+    pub fn source(&mut self, emu: &mut Emulator, path: &Path) -> Result<Flow> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read file '{}'", path.display()))?;
+        self.run_script(emu, &path.display().to_string(), &text)
+    }
+
+    // name is only used in error locations
+    pub fn run_script(&mut self, emu: &mut Emulator, name: &str, text: &str) -> Result<Flow> {
+        if self.depth >= MAX_DEPTH {
+            return Err(anyhow!("{} is sourced too deeply", name));
+        }
+        self.depth += 1;
+        let interactive = std::mem::replace(&mut self.interactive, false);
+        let flow = self.run_block(emu, name, &lines(text));
+        self.interactive = interactive;
+        self.depth -= 1;
+        flow
+    }
+
+    fn run_block(
+        &mut self,
+        emu: &mut Emulator,
+        name: &str,
+        lines: &[(usize, &str)],
+    ) -> Result<Flow> {
+        let mut i = 0;
+        while i < lines.len() {
+            let (number, line) = lines[i];
+            let location = || format!("{}:{}: {}", name, number, line);
+            let flow = if is_block(line) {
+                let (end, other) = block_end(&lines[i..])
+                    .with_context(location)?
+                    .ok_or_else(|| anyhow!("missing end"))
+                    .with_context(location)?;
+                let (keyword, condition) =
+                    line.split_once(char::is_whitespace).unwrap_or((line, ""));
+                let body = &lines[i + 1..i + other.unwrap_or(end)];
+                let flow = if keyword == "if" {
+                    if self.eval(emu, condition).with_context(location)? != 0 {
+                        self.run_block(emu, name, body)?
+                    } else if let Some(other) = other {
+                        self.run_block(emu, name, &lines[i + other + 1..i + end])?
+                    } else {
+                        Flow::Continue
+                    }
+                } else {
+                    let mut flow = Flow::Continue;
+                    while flow == Flow::Continue
+                        && self.eval(emu, condition).with_context(location)? != 0
+                    {
+                        flow = self.run_block(emu, name, body)?;
+                    }
+                    flow
+                };
+                i += end + 1;
+                flow
+            } else {
+                i += 1;
+                self.command(emu, line).with_context(location)?
+            };
+            if flow == Flow::Finish {
+                return Ok(Flow::Finish);
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    pub fn command(&mut self, emu: &mut Emulator, line: &str) -> Result<Flow> {
+        let mut args = line.split_whitespace();
+        let command = match args.next() {
+            Some(command) if !command.starts_with('#') => command,
+            _ => return Ok(Flow::Continue),
+        };
+        let rest = line[command.len()..].trim();
+
+        match command {
+            "run" => {
+                emu.run().with_context(|| "stop emulator".to_string())?;
+                if let Some(code) = emu.exit_code {
+                    print_uart(emu);
+                    println!("exited with code {}", code);
+                }
+            }
+            "s" => emu.step().with_context(|| "stop emulator".to_string())?,
+            "step" => {
+                let n = self.arg(emu, args.next(), "num > ", "invalid num")?;
+                for _i in 0..n {
+                    emu.step().with_context(|| "stop emulator".to_string())?;
+                }
+            }
+            "rs" | "reverse-step" => {
+                if emu.reverse_step() {
+                    println!(" pc : 0x{:08x}", emu.cpu.pc);
+                } else {
+                    println!("no more history");
+                }
+            }
+            "rc" | "reverse-continue" => {
+                if emu.reverse_continue() {
+                    println!(" pc : 0x{:08x}", emu.cpu.pc);
+                } else {
+                    println!("no more history");
+                }
+            }
+            "cy" | "cycles" => {
+                println!(
+                    "cycles : {}  instructions : {}  CPI : {:.3}",
+                    emu.cycles,
+                    emu.instret,
+                    emu.cpi()
+                );
+            }
+            "b" | "breakpoint" => {
+                emu.break_point =
+                    self.arg(emu, args.next(), "break point address > ", "invalid num")?;
+            }
+            "hart" => match args.next() {
+                Some(arg) => {
+                    let n = self.eval(emu, arg).map_err(|_| anyhow!("invalid num"))?;
+                    emu.select(n as usize)?;
+                    println!("hart {}  pc : 0x{:08x}", n, emu.cpu.pc);
+                }
+                None => print_harts(emu),
+            },
+            "m" | "mem" => {
+                let n = self.address(emu, args.next())?;
+                let size = self.size(emu, args.next())?;
+                print_mem(emu, n, size)?;
+            }
+            "wm" | "writemem" => {
+                let n = self.address(emu, args.next())?;
+                let d = self.arg(emu, args.next(), "data > ", "invalid data")?;
+                let size = self.size(emu, args.next())?;
+                if size < 4 && d >> (8 * size) != 0 {
+                    return Err(anyhow!("invalid data"));
+                }
+                emu.memory.peek_data(n, size)?;
+                let bytes = emu.memory.byte_order.bytes(d, size);
+                for (i, byte) in (0..).zip(bytes) {
+                    emu.memory.store(n + i, byte);
+                }
+                print_mem(emu, n, size)?;
+            }
+            "r" | "reg" => {
+                let n = self.register(emu, args.next())?;
+                println!("register[{}] = 0x{:08x}", n, emu.cpu.get_reg(n));
+            }
+            "wr" | "writereg" => {
+                let n = self.register(emu, args.next())?;
+                let d = self.arg(emu, args.next(), "data > ", "invalid data")?;
+                emu.cpu.set_reg(n, d);
+                println!("register[{}] = 0x{:08x}", n, emu.cpu.get_reg(n));
+            }
+            "set" => {
+                let name = args
+                    .next()
+                    .ok_or_else(|| anyhow!("usage: set <name> <expression>"))?;
+                let name = name.strip_prefix('$').unwrap_or(name);
+                if !is_identifier(name) || register(name).is_some() || name == "pc" {
+                    return Err(anyhow!("{} can not be used as a variable name", name));
+                }
+                let expr = rest
+                    .split_once(char::is_whitespace)
+                    .map_or("", |(_, expr)| expr);
+                let value = self.eval(emu, expr)?;
+                self.vars.insert(name.to_string(), value);
+            }
+            "echo" => {
+                let words = args
+                    .map(|word| self.interpolate(emu, word))
+                    .collect::<Result<Vec<_>>>()?;
+                println!("{}", words.join(" "));
+            }
+            "assert" => {
+                if self.eval(emu, rest)? == 0 {
+                    return Err(anyhow!("assertion failed: {}", rest));
+                }
+            }
+            "source" => {
+                if rest.is_empty() {
+                    return Err(anyhow!("usage: source <file>"));
+                }
+                return self.source(emu, Path::new(rest));
+            }
+            "rhai" => {
+                if rest.is_empty() {
+                    return Err(anyhow!("usage: rhai <file>"));
+                }
+                script::run_file(emu, Path::new(rest))?;
+            }
+            "h" | "help" => help(),
+            "finish" => {
+                println!("finish emulator");
+                return Ok(Flow::Finish);
+            }
+            _ => return Err(anyhow!("command not found {}", command)),
+        }
+
+        print_uart(emu);
+        Ok(Flow::Continue)
+    }
+
+    // argument value, asked for at the prompt when it is missing
+    fn arg(&self, emu: &Emulator, arg: Option<&str>, ask: &str, invalid: &str) -> Result<u32> {
+        let text = match arg {
+            Some(arg) => arg.to_string(),
+            None if self.interactive => prompt(ask)?.unwrap_or_default(),
+            None => return Err(anyhow!("missing argument, {}", invalid)),
+        };
+        self.eval(emu, &text).map_err(|_| anyhow!("{}", invalid))
+    }
+
+    fn address(&self, emu: &Emulator, arg: Option<&str>) -> Result<u32> {
+        let n = self.arg(emu, arg, "address > ", "invalid address")?;
+        if n >= memory::MEMORY_SIZE {
+            return Err(anyhow!("invalid address"));
+        }
+        Ok(n)
+    }
+
+    // access size in bytes for m and wm, 1 when omitted
+    fn size(&self, emu: &Emulator, arg: Option<&str>) -> Result<u32> {
+        let Some(arg) = arg else {
+            return Ok(1);
+        };
+        match self.eval(emu, arg) {
+            Ok(size @ (1 | 2 | 4)) => Ok(size),
+            _ => Err(anyhow!("invalid size, expected 1, 2 or 4")),
+        }
+    }
+
+    fn register(&self, emu: &Emulator, arg: Option<&str>) -> Result<u8> {
+        let n = self.arg(emu, arg, "register num > ", "invalid num")?;
+        if n >= 32 {
+            return Err(anyhow!("invalid num"));
+        }
+        Ok(n as u8)
+    }
+
+    // integer expression over numbers, registers, pc and variables, nonzero is true
+    pub fn eval(&self, emu: &Emulator, expr: &str) -> Result<u32> {
+        let mut parser = Parser {
+            tokens: tokenize(expr)?,
+            pos: 0,
+            emu,
+            vars: &self.vars,
+        };
+        let value = parser.expr(0)?;
+        match parser.tokens.get(parser.pos) {
+            Some(token) => Err(anyhow!("unexpected {} in {}", token, expr)),
+            None => Ok(value),
+        }
+    }
+
+    // $name is replaced by its value, registers and pc in hex
+    fn interpolate(&self, emu: &Emulator, word: &str) -> Result<String> {
+        let name = match word.strip_prefix('$') {
+            Some(name) => name,
+            None => return Ok(word.to_string()),
+        };
+        let end = name
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(name.len());
+        let (name, suffix) = name.split_at(end);
+        let value = if name == "pc" || register(name).is_some() {
+            format!("0x{:08x}", value(emu, &self.vars, name)?)
+        } else {
+            value(emu, &self.vars, name)?.to_string()
+        };
+        Ok(format!("{}{}", value, suffix))
+    }
+}
+
+pub fn print_uart(emu: &mut Emulator) {
+    if !emu.memory.uart.is_empty() {
+        print!("{}", String::from_utf8_lossy(&emu.memory.uart));
+        emu.memory.uart.clear();
+    }
+}
+
+// size bytes in the memory's byte order
+fn print_mem(emu: &Emulator, address: u32, size: u32) -> Result<()> {
+    let data = emu.memory.peek_data(address, size)?;
+    let width = 2 * size as usize;
+    println!("mem[{}] = 0x{:0width$x}", address, data);
+    Ok(())
+}
+
+// None at the end of input
+fn prompt(text: &str) -> Result<Option<String>> {
+    print!("{}", text);
+    io::stdout().flush().unwrap();
+    let mut input = String::new();
+    let read = io::stdin()
+        .read_line(&mut input)
+        .with_context(|| "failed to read command".to_string())?;
+    Ok((read > 0).then(|| input.trim().to_string()))
+}
+
+fn lines(text: &str) -> Vec<(usize, &str)> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .collect()
+}
+
+fn keyword(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+fn is_block(line: &str) -> bool {
+    matches!(keyword(line), "if" | "while")
+}
+
+// indices of the matching end and else of the block starting at lines[0],
+// None when the end has not been written yet
+fn block_end(lines: &[(usize, &str)]) -> Result<Option<(usize, Option<usize>)>> {
+    let mut depth = 0;
+    let mut other = None;
+    for (i, (_, line)) in lines.iter().enumerate().skip(1) {
+        match keyword(line) {
+            "if" | "while" => depth += 1,
+            "end" if depth == 0 => return Ok(Some((i, other))),
+            "end" => depth -= 1,
+            "else" if depth == 0 => {
+                if keyword(lines[0].1) != "if" || other.is_some() {
+                    return Err(anyhow!("unexpected else"));
+                }
+                other = Some(i);
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
+
+fn help() {
+    println!("run           : continue to execute until break point");
+    println!();
+    println!("s, step [num] : step execute");
+    println!();
+    println!("rs, reverse-step : undo the last executed instruction");
+    println!();
+    println!("rc, reverse-continue : undo instructions back to the breakpoint");
+    println!();
+    println!("cy, cycles    : display cycle count and CPI");
+    println!();
+    println!("b, breakpoint [address] : set breakpoint");
+    println!();
+    println!("hart [num]    : list harts or select the one to step and inspect");
+    println!();
+    println!("m, mem [address] [size] : display 1, 2 or 4 bytes of memory");
+    println!();
+    println!("wm, writemem [address] [data] [size] : write 1, 2 or 4 bytes to memory");
+    println!();
+    println!("r, reg [num]  : display data in register");
+    println!();
+    println!("wr, writereg [num] [data] : write data to register");
+    println!();
+    println!("set name expr : assign a variable, e.g. set n r1 + 4");
+    println!();
+    println!("echo words    : print words, $name is replaced by its value");
+    println!();
+    println!("assert expr   : stop the script when expr is zero");
+    println!();
+    println!("if expr / else / end, while expr / end : run commands conditionally");
+    println!();
+    println!("source file   : run commands from a file");
+    println!();
+    println!("rhai file     : run a Rhai script");
+    println!();
+    println!("h, help       : show this message");
+    println!();
+    println!("finish        : finish emulator");
+}
+
+fn print_harts(emu: &Emulator) {
+    for n in 0..emu.harts.len() {
+        let current = if n == emu.hart { "*" } else { " " };
+        let halted = if emu.harts[n].halted { "  halted" } else { "" };
+        println!(
+            "{} hart {}  pc : 0x{:08x}  break point : 0x{:08x}{}",
+            current,
+            n,
+            emu.hart_cpu(n).pc,
+            emu.hart_break_point(n),
+            halted
+        );
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn register(name: &str) -> Option<u8> {
+    let num = name.strip_prefix('r')?.parse::<u8>().ok()?;
+    (num < 32 && !name[1..].starts_with('+')).then_some(num)
+}
+
+fn value(emu: &Emulator, vars: &BTreeMap<String, u32>, name: &str) -> Result<u32> {
+    if name == "pc" {
+        return Ok(emu.cpu.pc);
+    }
+    if let Some(num) = register(name) {
+        return Ok(emu.cpu.get_reg(num));
+    }
+    vars.get(name)
+        .copied()
+        .ok_or_else(|| anyhow!("{} is not defined", name))
+}
+
+const OPERATORS: [&str; 22] = [
+    "==", "!=", "<=", ">=", "<<", ">>", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "&", "|",
+    "^", "!", "~", "(", ")",
+];
+
+fn tokenize(expr: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut rest = expr.trim_start();
+    while !rest.is_empty() {
+        let len = if let Some(operator) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            operator.len()
+        } else {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(rest.len());
+            if len == 0 {
+                return Err(anyhow!("unexpected {} in {}", rest, expr));
+            }
+            len
+        };
+        tokens.push(rest[..len].to_string());
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<String>,
+    pos: usize,
+    emu: &'a Emulator,
+    vars: &'a BTreeMap<String, u32>,
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    let precedence = match op {
+        "||" => 1,
+        "&&" => 2,
+        "==" | "!=" => 3,
+        "<" | "<=" | ">" | ">=" => 4,
+        "|" => 5,
+        "^" => 6,
+        "&" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        _ => return None,
+    };
+    Some(precedence)
+}
+
+impl Parser<'_> {
+    fn next(&mut self) -> Result<String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("expression is incomplete"))?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    // binary operators bind tighter as precedence rises, all are left associative
+    fn expr(&mut self, min: u8) -> Result<u32> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.tokens.get(self.pos).cloned() {
+            let precedence = match precedence(&op) {
+                Some(precedence) if precedence >= min => precedence,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.expr(precedence + 1)?;
+            lhs = match op.as_str() {
+                "||" => u32::from(lhs != 0 || rhs != 0),
+                "&&" => u32::from(lhs != 0 && rhs != 0),
+                "==" => u32::from(lhs == rhs),
+                "!=" => u32::from(lhs != rhs),
+                "<" => u32::from(lhs < rhs),
+                "<=" => u32::from(lhs <= rhs),
+                ">" => u32::from(lhs > rhs),
+                ">=" => u32::from(lhs >= rhs),
+                "|" => lhs | rhs,
+                "^" => lhs ^ rhs,
+                "&" => lhs & rhs,
+                "<<" => lhs.wrapping_shl(rhs),
+                ">>" => lhs.wrapping_shr(rhs),
+                "+" => lhs.wrapping_add(rhs),
+                "-" => lhs.wrapping_sub(rhs),
+                "*" => lhs.wrapping_mul(rhs),
+                "/" => lhs
+                    .checked_div(rhs)
+                    .ok_or_else(|| anyhow!("division by zero"))?,
+                _ => lhs
+                    .checked_rem(rhs)
+                    .ok_or_else(|| anyhow!("division by zero"))?,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<u32> {
+        let token = self.next()?;
+        match token.as_str() {
+            "-" => Ok(self.unary()?.wrapping_neg()),
+            "!" => Ok(u32::from(self.unary()? == 0)),
+            "~" => Ok(!self.unary()?),
+            "(" => {
+                let value = self.expr(0)?;
+                match self.next()?.as_str() {
+                    ")" => Ok(value),
+                    token => Err(anyhow!("expected ) but found {}", token)),
+                }
+            }
+            _ => operand(self.emu, self.vars, &token),
+        }
+    }
+}
+
+fn operand(emu: &Emulator, vars: &BTreeMap<String, u32>, token: &str) -> Result<u32> {
+    let number = if let Some(hex) = token.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(bin) = token.strip_prefix("0b") {
+        u32::from_str_radix(bin, 2)
+    } else if token.starts_with(|c: char| c.is_ascii_digit()) {
+        token.parse::<u32>()
+    } else {
+        return value(emu, vars, token.strip_prefix('$').unwrap_or(token));
+    };
+    number.map_err(|_| anyhow!("invalid number {}", token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+
+    fn emulator() -> Emulator {
+        // r1 counts down from 3, r2 accumulates 10 per iteration
+        Asm::new()
+            .li(r1, 3)
+            .label("loop")
+            .addi(r2, r2, 10)
+            .addi(r1, r1, -1)
+            .bnq(r1, r0, "loop")
+            .halt()
+            .emulator()
+            .unwrap()
+    }
+
+    #[test]
+    fn evaluates_expressions() {
+        let mut emu = emulator();
+        emu.cpu.set_reg(1, 7);
+        let mut debugger = Debugger::new();
+        debugger.vars.insert("n".to_string(), 3);
+        let eval = |expr| debugger.eval(&emu, expr).unwrap();
+        assert_eq!(eval("1 + 2 * 3"), 7);
+        assert_eq!(eval("(1 + 2) * 3"), 9);
+        assert_eq!(eval("r1 == 7 && $n > 2"), 1);
+        assert_eq!(eval("r1-n"), 4);
+        assert_eq!(eval("0x10 | 0b1 << 2"), 0x14);
+        assert_eq!(eval("-1"), u32::MAX);
+        assert_eq!(eval("!r0"), 1);
+        assert_eq!(eval("pc"), 0);
+        assert!(debugger.eval(&emu, "1 +").is_err());
+        assert!(debugger.eval(&emu, "r32").is_err());
+        assert!(debugger.eval(&emu, "1 / r0").is_err());
+    }
+
+    #[test]
+    fn loops_over_registers() {
+        let mut emu = emulator();
+        let mut debugger = Debugger::new();
+        let script = "
+            # step to the halt after the loop
+            set steps 0
+            while pc != 0x14
+                s
+                set steps steps + 1
+            end
+            if r2 == 30
+                set result 1
+            else
+                set result 2
+            end
+            assert steps == 11
+        ";
+        assert_eq!(
+            debugger.run_script(&mut emu, "loop", script).unwrap(),
+            Flow::Continue
+        );
+        assert_eq!(debugger.vars["result"], 1);
+    }
+
+    #[test]
+    fn commands_take_arguments() {
+        let mut emu = emulator();
+        let mut debugger = Debugger::new();
+        let script = "b 0x10\nwr 5 0x1234\nwm 0x200 0xff\nrun\nfinish\nwr 5 0";
+        assert_eq!(
+            debugger.run_script(&mut emu, "args", script).unwrap(),
+            Flow::Finish
+        );
+        assert_eq!(emu.break_point, 0x10);
+        assert_eq!(emu.cpu.pc, 0x10);
+        assert_eq!(emu.cpu.get_reg(5), 0x1234);
+        assert_eq!(emu.memory.memory_array[0x200], 0xff);
+    }
+
+    #[test]
+    fn errors_report_the_line() {
+        let mut emu = emulator();
+        let mut debugger = Debugger::new();
+        for (script, message) in [
+            ("s\nassert r1 == 4", "test:2: assert r1 == 4"),
+            ("wr 32 1", "test:1: wr 32 1"),
+            ("m", "test:1: m"),
+            ("if 1\ns", "test:1: if 1"),
+            ("else", "test:1: else"),
+            ("set r1 4", "test:1: set r1 4"),
+        ] {
+            let error = debugger.run_script(&mut emu, "test", script).unwrap_err();
+            assert_eq!(error.to_string(), message);
+        }
+    }
+
+    #[test]
+    fn memory_commands_use_the_byte_order() {
+        let mut emu = emulator();
+        emu.memory.byte_order = memory::ByteOrder::Big;
+        let mut debugger = Debugger::new();
+        let script = "wm 0x200 0x1234 2\nwm 0x202 0xabcd 2\nm 0x200 4";
+        debugger.run_script(&mut emu, "sized", script).unwrap();
+        assert_eq!(
+            emu.memory.memory_array[0x200..0x204],
+            [0x12, 0x34, 0xab, 0xcd]
+        );
+        assert_eq!(emu.memory.peek_data(0x200, 4).unwrap(), 0x1234_abcd);
+        for script in ["m 0x200 3", "wm 0x200 0x100 1", "m 0x2ff 2"] {
+            assert!(
+                debugger.run_script(&mut emu, "test", script).is_err(),
+                "{}",
+                script
+            );
+        }
+    }
+
+    #[test]
+    fn hart_selects_what_commands_inspect() {
+        let mut emu = emulator();
+        emu.trace = false;
+        emu.add_hart();
+        let mut debugger = Debugger::new();
+        let script = "step 3\nhart 1\nwr 1 7\nhart\nhart 0\nassert r1 == 3";
+        debugger.run_script(&mut emu, "harts", script).unwrap();
+        assert_eq!(emu.hart_cpu(1).get_reg(r1), 7);
+        assert!(debugger.run_script(&mut emu, "test", "hart 2").is_err());
+    }
+}
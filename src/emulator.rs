@@ -1,43 +1,180 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use std::fmt;
+pub mod coverage;
 pub mod cpu;
+pub mod custom;
+pub mod encoder;
+pub mod hart;
+pub mod history;
+pub mod hooks;
+pub mod icache;
+pub mod isa;
 pub mod memory;
+pub mod profiler;
+pub mod replay;
+pub mod semihost;
+pub mod symbols;
+pub mod syscall;
+pub mod threaded;
+pub mod timing;
 
 use cpu::Cpu;
-use memory::Memory;
+use custom::Instruction;
+use hart::Hart;
+use history::{History, Record};
+use hooks::Hook;
+use icache::{Decoded, ICache};
+use isa::{op, Category, Profile, Spec};
+use memory::{Memory, MemoryError, MemoryErrorKind};
+use profiler::Profiler;
+use semihost::Semihost;
+use symbols::Symbols;
+use syscall::Syscalls;
+use threaded::Threaded;
+use timing::TimingModel;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(clippy::enum_variant_names)]
 pub enum Type {
     RFormat {
-        mnemonic: String,
+        mnemonic: &'static str,
         rd: u8,
         rs: u8,
     },
     I16Format {
-        mnemonic: String,
+        mnemonic: &'static str,
         rd: u8,
         imm: u8,
     },
     I32Format {
-        mnemonic: String,
+        mnemonic: &'static str,
         rd: u8,
         rs: u8,
         imm: i32,
     },
     JFormat {
-        mnemonic: String,
+        mnemonic: &'static str,
         rd: u8,
         imm: i32,
     },
 }
 
+impl Type {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Type::RFormat { mnemonic, .. }
+            | Type::I16Format { mnemonic, .. }
+            | Type::I32Format { mnemonic, .. }
+            | Type::JFormat { mnemonic, .. } => mnemonic,
+        }
+    }
+}
+
 pub const CHECK_32BIT_INST: u32 = 0x0000_0020;
 
+// instruction word at pc and its length, a 16-bit instruction may end at
+// the last byte of memory
+pub fn fetch_word(memory: &mut Memory, pc: u32) -> Result<(u32, u32), MemoryError> {
+    // 16-bit parcels in memory order, the first one holds the opcode
+    let word_16 = memory.read_data_16bit(pc)? as u32;
+    if (CHECK_32BIT_INST & word_16) == 32 {
+        let high = memory.read_data_16bit(pc.wrapping_add(2))? as u32;
+        Ok((word_16 | high << 16, 4))
+    } else {
+        Ok((word_16, 2))
+    }
+}
+
+// an unused opcode, or one of an extension the profile does not implement
+#[derive(Debug, Clone, Copy)]
+pub struct IllegalInstruction {
+    pub inst: Type,
+    pub isa: Profile,
+}
+
+impl fmt::Display for IllegalInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "illegal instruction {} for ISA {}", self.inst, self.isa)
+    }
+}
+
+impl std::error::Error for IllegalInstruction {}
+
+pub fn illegal(inst: &Type, isa: Profile) -> anyhow::Error {
+    IllegalInstruction { inst: *inst, isa }.into()
+}
+
+fn misaligned(address: u32, size: u32) -> MemoryError {
+    MemoryError {
+        addr: address,
+        size,
+        kind: MemoryErrorKind::Misaligned,
+    }
+}
+
+// errors that stop execution at the failing instruction, which does not
+// retire; others are printed and execution goes on
+fn stops(error: &anyhow::Error) -> bool {
+    error.is::<IllegalInstruction>()
+        || matches!(
+            error.downcast_ref::<MemoryError>(),
+            Some(error) if error.kind == MemoryErrorKind::Misaligned
+        )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    Interpreter,
+    // translates basic blocks, used by run when nothing observes single steps
+    Threaded,
+}
+
+// what happens to halfword and word accesses that are not naturally aligned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    // they behave like aligned ones
+    Allow,
+    // they fail, as do instruction fetches from odd addresses
+    Trap,
+    // they become one bus access per byte, like on the FPGA bus
+    Split,
+}
+
 #[derive(Debug)]
 pub struct Emulator {
     pub memory: Memory,
     pub cpu: Cpu,
     pub break_point: u32,
+    pub history: History,
+    pub profiler: Option<Profiler>,
+    pub symbols: Symbols,
+    pub timing: Option<Box<dyn TimingModel>>,
+    pub instret: u64,
+    pub cycles: u64,
+    pub icache: ICache,
+    pub trace: bool,
+    pub engine: Engine,
+    pub threaded: Threaded,
+    pub alignment: Alignment,
+    // extensions execute implements, the decoder and disassembler know all
+    pub isa: Profile,
+    pub hooks: Vec<Box<dyn Hook>>,
+    // plugin instructions, see add_instruction
+    pub instructions: Vec<Box<dyn Instruction>>,
+    // every hart, cpu and break_point belong to harts[hart], see hart.rs
+    pub harts: Vec<Hart>,
+    pub hart: usize,
+    // instructions a hart runs before the next one's turn, by hart id; the
+    // last entry applies to the remaining harts
+    pub quantum: Vec<u64>,
+    // instructions run in the current turn
+    slice: u64,
+    // services TRAP semihost::TRAP when set
+    pub semihost: Option<Semihost>,
+    // services TRAP syscall::TRAP when set
+    pub syscalls: Option<Syscalls>,
+    // set when the program asked to stop, run returns at the next step
+    pub exit_code: Option<u32>,
 }
 
 impl Emulator {
@@ -47,456 +184,1037 @@ impl Emulator {
             memory: Memory::new(program),
             cpu: Cpu::new(),
             break_point: bread_point,
+            history: History::new(0),
+            profiler: None,
+            symbols: Symbols::new(),
+            timing: None,
+            instret: 0,
+            cycles: 0,
+            icache: ICache::new(),
+            trace: true,
+            engine: Engine::Interpreter,
+            threaded: Threaded::new(),
+            alignment: Alignment::Allow,
+            isa: Profile::BASE,
+            hooks: Vec::new(),
+            instructions: Vec::new(),
+            harts: vec![Hart::new(bread_point)],
+            hart: 0,
+            quantum: vec![1],
+            slice: 0,
+            semihost: None,
+            syscalls: None,
+            exit_code: None,
+        }
+    }
+
+    // hooks are called in the order they were added
+    pub fn add_hook(&mut self, hook: Box<dyn Hook>) {
+        self.hooks.push(hook);
+    }
+
+    // decodes words the ISA table leaves unknown, fails when the pattern
+    // clashes with the ISA or an instruction added before
+    pub fn add_instruction(&mut self, inst: Box<dyn Instruction>) -> Result<()> {
+        custom::check(&self.instructions, inst.as_ref())?;
+        self.instructions.push(inst);
+        self.icache = ICache::new();
+        Ok(())
+    }
+
+    // why run uses the interpreter even with Engine::Threaded, None when the
+    // threaded engine can run
+    pub fn threaded_blocker(&self) -> Option<&'static str> {
+        if self.trace {
+            Some("instructions are traced")
+        } else if self.history.budget != 0 {
+            Some("history for reverse execution is kept")
+        } else if self.profiler.is_some() {
+            Some("the profiler is on")
+        } else if self.timing.is_some() {
+            Some("a timing model is set")
+        } else if !self.hooks.is_empty() {
+            Some("hooks are installed")
+        } else if !self.instructions.is_empty() {
+            Some("plugin instructions are added")
+        } else if self.harts.len() > 1 {
+            Some("there are several harts")
+        } else if self.memory.inputs.mode != replay::Mode::Off {
+            Some("inputs are recorded or replayed")
+        } else if self.alignment != Alignment::Allow {
+            Some("the alignment policy is not allow")
+        } else {
+            None
         }
     }
 
     pub fn run(&mut self) -> Result<()> {
+        if self.engine == Engine::Threaded && self.threaded_blocker().is_none() {
+            return self.run_threaded();
+        }
+
+        // step without the bookkeeping that is switched off
+        let plain = self.history.budget == 0
+            && self.harts.len() == 1
+            && self.memory.inputs.mode == replay::Mode::Off;
         loop {
-            self.step()?;
-            if self.cpu.pc == self.break_point || self.cpu.pc >= memory::MEMORY_SIZE {
+            if plain {
+                self.execute_next()?;
+            } else {
+                self.step()?;
+            }
+            if self.cpu.pc == self.break_point
+                || self.cpu.pc >= memory::MEMORY_SIZE
+                || self.exit_code.is_some()
+            {
                 break;
             }
         }
         Ok(())
     }
 
-    pub fn step(&mut self) -> Result<()> {
-        let word_32 = self.memory.read_data(self.cpu.pc)?;
-        let word_16 = (word_32 & 0x0000FFFF) as u16;
-        let current_pc = self.cpu.pc;
+    // run until every hart jumps to itself or one reaches its break point
+    pub fn run_until_halt(&mut self, max_steps: u64) -> Result<()> {
+        for _ in 0..max_steps {
+            let pc = self.cpu.pc;
+            self.step()?;
+            if self.halted(pc) || self.cpu.pc == self.break_point || self.exit_code.is_some() {
+                return Ok(());
+            }
+        }
+        Err(anyhow!("program did not halt within {} steps", max_steps))
+    }
 
-        if (CHECK_32BIT_INST & word_32) == 32 {
-            self.cpu.pc += 4;
-
-            let inst = Self::decode_32(word_32);
-            match &inst {
-                Type::I32Format {
-                    mnemonic,
-                    rd,
-                    rs,
-                    imm,
-                } => match mnemonic.as_str() {
-                    "LUI" => {
-                        println!(
-                            " pc : 0x{:08x} inst : 0b{:032b} {} r{}  {}",
-                            current_pc, word_32, mnemonic, rd, imm
-                        )
-                    }
-                    _ => {
-                        println!(
-                            " pc : 0x{:08x} inst : 0b{:032b} {} r{} r{} {}",
-                            current_pc, word_32, mnemonic, rd, rs, imm
-                        )
+    fn run_threaded(&mut self) -> Result<()> {
+        self.sync_code();
+        self.threaded.prepare(self.break_point, self.isa);
+        let mut index = self
+            .threaded
+            .lookup(&mut self.memory, self.cpu.pc, self.break_point)?;
+        loop {
+            let block = &self.threaded.blocks[index];
+            let mut pc = block.start;
+            for (next_pc, op) in &block.ops {
+                self.cpu.pc = *next_pc;
+                match op(&mut self.cpu, &mut self.memory) {
+                    Ok(()) => {}
+                    Err(error) if stops(&error) => {
+                        self.cpu.pc = pc;
+                        return Err(error);
                     }
-                },
-                Type::JFormat { mnemonic, rd, imm } => {
-                    println!(
-                        " pc : 0x{:08x} inst : 0b{:032b} {} r{}  {}",
-                        current_pc, word_32, mnemonic, rd, imm
-                    )
+                    Err(error) => println!("{}", error),
                 }
-                _ => {}
-            }
-            match self.execute(&inst) {
-                Ok(_) => {}
-                Err(error) => {
-                    println!("{}", error)
+                pc = *next_pc;
+                self.instret += 1;
+                if !self.memory.code_writes.is_empty() {
+                    break;
                 }
             }
+            if self.threaded.blocks[index].trap && self.memory.code_writes.is_empty() {
+                self.step()?;
+            }
+
+            if self.cpu.pc == self.break_point
+                || self.cpu.pc >= memory::MEMORY_SIZE
+                || self.exit_code.is_some()
+            {
+                return Ok(());
+            }
+            index = if self.memory.code_writes.is_empty() {
+                self.threaded
+                    .next(&mut self.memory, index, self.cpu.pc, self.break_point)?
+            } else {
+                self.sync_code();
+                self.threaded
+                    .lookup(&mut self.memory, self.cpu.pc, self.break_point)?
+            };
+        }
+    }
+
+    // drop decoded instructions whose bytes were written
+    fn sync_code(&mut self) {
+        if self.memory.code_writes.is_empty() {
+            return;
+        }
+        while let Some(address) = self.memory.code_writes.pop() {
+            self.icache.invalidate(address);
+        }
+        self.threaded.flush();
+    }
+
+    pub fn step(&mut self) -> Result<()> {
+        let pc = self.cpu.pc;
+        self.memory.inputs.instret = self.instret;
+        let result = if self.history.budget == 0 {
+            self.execute_next()
         } else {
-            self.cpu.pc += 2;
+            self.execute_recorded()
+        };
+        if result.is_ok() && self.harts.len() > 1 {
+            self.schedule(pc);
+        }
+        result.and(self.memory.inputs.check(self.instret))
+    }
 
-            let inst = Self::decode_16(word_16);
+    fn execute_recorded(&mut self) -> Result<()> {
+        let (slice, halted) = (self.slice, self.harts[self.hart].halted);
+        let pc = self.cpu.pc;
+        let cycles = self.cycles;
+        let timing = self.timing.as_ref().and_then(|timing| timing.state());
+        let brk = self.syscalls.as_ref().map_or(0, |syscalls| syscalls.brk);
+        let instret = self.instret;
+        let register = self.cpu.register;
+        let exit_code = self.exit_code;
+        self.memory.journal = Some(Vec::new());
+        let result = self.execute_next();
+        let memory = self.memory.journal.take().unwrap_or_default();
+        // nothing to undo when the instruction did not retire
+        if self.instret == instret {
+            return result;
+        }
+        self.history.push(Record {
+            hart: self.hart,
+            slice,
+            halted,
+            pc,
+            cycles,
+            timing,
+            brk,
+            register,
+            memory,
+            exit_code,
+        });
+        result
+    }
 
-            match &inst {
-                Type::RFormat { mnemonic, rd, rs } => {
-                    println!(
-                        " pc : 0x{:08x} inst : 0b{:016b} {} r{} r{}",
-                        current_pc, word_16, mnemonic, rd, rs
-                    );
+    // undo the last retired instruction, returns false when no history is left
+    pub fn reverse_step(&mut self) -> bool {
+        match self.history.pop() {
+            Some(record) => {
+                self.switch(record.hart);
+                self.slice = record.slice;
+                self.harts[self.hart].halted = record.halted;
+                for (address, data) in record.memory.into_iter().rev() {
+                    self.memory.restore(address, data);
                 }
-                Type::I16Format { mnemonic, rd, imm } => {
-                    println!(
-                        " pc : 0x{:08x} inst : 0b{:016b} {} r{} {}",
-                        current_pc, word_16, mnemonic, rd, imm
-                    );
+                self.cpu.register = record.register;
+                self.cpu.pc = record.pc;
+                self.cycles = record.cycles;
+                self.exit_code = record.exit_code;
+                if let (Some(timing), Some(state)) = (self.timing.as_mut(), record.timing) {
+                    timing.restore(state);
                 }
-                _ => {}
+                if let Some(syscalls) = self.syscalls.as_mut() {
+                    syscalls.brk = record.brk;
+                }
+                self.instret -= 1;
+                self.memory.inputs.rewind(self.instret);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // undo instructions until the previous break point hit
+    pub fn reverse_continue(&mut self) -> bool {
+        let mut moved = false;
+        while self.reverse_step() {
+            moved = true;
+            if self.cpu.pc == self.break_point {
+                break;
+            }
+        }
+        moved
+    }
+
+    fn execute_next(&mut self) -> Result<()> {
+        self.sync_code();
+
+        let current_pc = self.cpu.pc;
+        // a split fetch reads the same bytes, only trapping changes anything
+        if !current_pc.is_multiple_of(2) && self.alignment == Alignment::Trap {
+            return Err(misaligned(current_pc, 2).into());
+        }
+        let decoded = match self.icache.get(current_pc) {
+            Some(decoded) => *decoded,
+            None => self.fetch(current_pc)?,
+        };
+        let inst = decoded.inst;
+
+        if self.trace {
+            self.print_trace(current_pc, &decoded);
+        }
+
+        if !self.hooks.is_empty() {
+            return self.execute_hooked(current_pc, decoded);
+        }
+
+        self.cpu.pc = current_pc.wrapping_add(decoded.length);
+        match self.dispatch(decoded.spec, &inst) {
+            Ok(_) => {}
+            Err(error) if stops(&error) => {
+                self.cpu.pc = current_pc;
+                return Err(error);
+            }
+            Err(error) => {
+                println!("{}", error)
+            }
+        }
+        self.retire(current_pc, &inst, current_pc.wrapping_add(decoded.length));
+        Ok(())
+    }
+
+    // execute_next with every hook called, they are moved out meanwhile so
+    // that they can look at the whole emulator
+    #[cold]
+    #[inline(never)]
+    fn execute_hooked(&mut self, pc: u32, decoded: Decoded) -> Result<()> {
+        let mut hooks = std::mem::take(&mut self.hooks);
+        let inst = decoded.inst;
+        let fallthrough_pc = pc.wrapping_add(decoded.length);
+        for hook in hooks.iter_mut() {
+            hook.fetch(self, pc, &decoded);
+            hook.pre_execute(self, pc, &inst);
+        }
+
+        let register = self.cpu.register;
+        self.cpu.written = 0;
+        self.memory.accesses = Some(Vec::new());
+        self.cpu.pc = fallthrough_pc;
+        let result = self.dispatch(decoded.spec, &inst);
+        let accesses = self.memory.accesses.take().unwrap_or_default();
+        if let Some(error) = result.as_ref().err().filter(|error| stops(error)) {
+            self.cpu.pc = pc;
+            for hook in hooks.iter_mut() {
+                hook.trap(self, pc, error);
             }
-            match self.execute(&inst) {
-                Ok(_) => {}
-                Err(error) => {
-                    println!("{}", error)
+            self.hooks = hooks;
+            return result;
+        }
+        self.retire(pc, &inst, fallthrough_pc);
+
+        let category = decoded.spec.map(|spec| spec.category);
+        let written = self.cpu.written;
+        for hook in hooks.iter_mut() {
+            if let Err(error) = &result {
+                hook.trap(self, pc, error);
+            }
+            for access in &accesses {
+                if access.write {
+                    hook.mem_write(self, access.address, access.size, access.data);
+                } else {
+                    hook.mem_read(self, access.address, access.size, access.data);
                 }
             }
+            for num in (1..32).filter(|num| written & 1 << num != 0) {
+                hook.reg_write(self, num as u8, register[num], self.cpu.register[num]);
+            }
+            if category == Some(Category::Branch) && self.cpu.pc != fallthrough_pc {
+                hook.branch_taken(self, pc, self.cpu.pc);
+            }
+            hook.post_execute(self, pc, &inst);
+        }
+        self.hooks = hooks;
+
+        if let Err(error) = result {
+            println!("{}", error)
         }
         Ok(())
     }
 
-    pub fn decode_32(word: u32) -> Type {
-        let opcode = (word & 0x0000003F) as u8;
-        let rd = ((word & 0x000007C0) >> 6) as u8;
-        let rs = ((word & 0x0000F800) >> 11) as u8;
-        let imm_i = ((word & 0xFFFF0000) as i32) >> 16;
-        let imm_j = ((word & 0xFFFFF800) as i32) >> 11;
-
-        match opcode {
-            0b100000 => Type::I32Format {
-                mnemonic: String::from("ADDI"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b100001 => Type::I32Format {
-                mnemonic: String::from("ANDI"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b100010 => Type::I32Format {
-                mnemonic: String::from("ORI"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b100011 => Type::I32Format {
-                mnemonic: String::from("XORI"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b100100 => Type::I32Format {
-                mnemonic: String::from("SLTI"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b100101 => Type::I32Format {
-                mnemonic: String::from("SLTIU"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b100110 => Type::I32Format {
-                mnemonic: String::from("BEQ"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b100111 => Type::I32Format {
-                mnemonic: String::from("BNQ"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b101000 => Type::I32Format {
-                mnemonic: String::from("BLT"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b101001 => Type::I32Format {
-                mnemonic: String::from("BGE"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b101010 => Type::I32Format {
-                mnemonic: String::from("BLTU"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b101011 => Type::I32Format {
-                mnemonic: String::from("BGEU"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b101100 => Type::I32Format {
-                mnemonic: String::from("JALR"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b101101 => Type::I32Format {
-                mnemonic: String::from("LB"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b101110 => Type::I32Format {
-                mnemonic: String::from("LH"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b101111 => Type::I32Format {
-                mnemonic: String::from("LBU"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b110000 => Type::I32Format {
-                mnemonic: String::from("LHU"),
-                rd,
-                rs: 0,
-                imm: imm_i,
-            },
-            0b110001 => Type::I32Format {
-                mnemonic: String::from("LW"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b110010 => Type::I32Format {
-                mnemonic: String::from("LUI"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b110011 => Type::I32Format {
-                mnemonic: String::from("SB"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b110100 => Type::I32Format {
-                mnemonic: String::from("SH"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b110101 => Type::I32Format {
-                mnemonic: String::from("SW"),
-                rd,
-                rs,
-                imm: imm_i,
-            },
-            0b111111 => Type::JFormat {
-                mnemonic: String::from("JAL"),
-                rd,
-                imm: imm_j,
-            },
-            _ => Type::I32Format {
-                mnemonic: String::from("UNKNOWN"),
-                rd: 0,
-                rs: 0,
-                imm: 0,
-            },
+    fn fetch(&mut self, pc: u32) -> Result<Decoded> {
+        let (word, length) = fetch_word(&mut self.memory, pc)?;
+        let decoded = Decoded::new(self.decode(word, length), word, length);
+        self.memory.mark_code(pc, decoded.length);
+        self.icache.insert(pc, decoded);
+        Ok(decoded)
+    }
+
+    // decode without touching the icache, None past the end of memory
+    pub fn disassemble(&self, address: u32) -> Option<Decoded> {
+        let word_16 = self.memory.peek_data(address, 2).ok()? as u16;
+        if (CHECK_32BIT_INST & word_16 as u32) == 32 {
+            let high = self.memory.peek_data(address.checked_add(2)?, 2).ok()?;
+            let word = word_16 as u32 | high << 16;
+            Some(Decoded::new(self.decode(word, 4), word, 4))
+        } else {
+            let word = word_16 as u32;
+            Some(Decoded::new(self.decode(word, 2), word, 2))
         }
     }
 
-    pub fn decode_16(word: u16) -> Type {
-        let opcode = (word & 0x003F) as u8;
-        let rd = ((word & 0x07C0) >> 6) as u8;
-        let rs = ((word & 0xF800) >> 11) as u8;
-        let imm = ((word & 0xF800) >> 11) as u8;
-
-        match opcode {
-            0b000000 => Type::RFormat {
-                mnemonic: String::from("MOV"),
-                rd,
-                rs,
-            },
-            0b000001 => Type::RFormat {
-                mnemonic: String::from("ADD"),
-                rd,
-                rs,
-            },
-            0b000010 => Type::RFormat {
-                mnemonic: String::from("SUB"),
-                rd,
-                rs,
-            },
-            0b000011 => Type::RFormat {
-                mnemonic: String::from("AND"),
-                rd,
-                rs,
-            },
-            0b000100 => Type::RFormat {
-                mnemonic: String::from("OR"),
-                rd,
-                rs,
-            },
-            0b000101 => Type::RFormat {
-                mnemonic: String::from("XOR"),
-                rd,
-                rs,
-            },
-            0b000110 => Type::RFormat {
-                mnemonic: String::from("SLL"),
-                rd,
-                rs,
-            },
-            0b000111 => Type::RFormat {
-                mnemonic: String::from("SRL"),
-                rd,
-                rs,
-            },
-            0b001000 => Type::RFormat {
-                mnemonic: String::from("SRA"),
-                rd,
-                rs,
-            },
-            0b001001 => Type::RFormat {
-                mnemonic: String::from("SLT"),
-                rd,
-                rs,
-            },
-            0b001010 => Type::RFormat {
-                mnemonic: String::from("SLTU"),
-                rd,
-                rs,
-            },
-            0b010000 => Type::I16Format {
-                mnemonic: String::from("SLLI"),
-                rd,
-                imm,
-            },
-            0b010001 => Type::I16Format {
-                mnemonic: String::from("SRLI"),
-                rd,
-                imm,
-            },
-            0b010010 => Type::I16Format {
-                mnemonic: String::from("SRAI"),
-                rd,
-                imm,
-            },
-            _ => Type::RFormat {
-                mnemonic: String::from("UNKNOWN"),
-                rd: 0,
-                rs: 0,
-            },
+    // the ISA table first, then the plugin instructions
+    fn decode(&self, word: u32, length: u32) -> Type {
+        let inst = if length == 4 {
+            Self::decode_32(word)
+        } else {
+            Self::decode_16(word as u16)
+        };
+        if inst.mnemonic() != "UNKNOWN" || self.instructions.is_empty() {
+            return inst;
         }
+        custom::decode(&self.instructions, word, length).unwrap_or(inst)
+    }
+
+    // assembly syntax, including plugin instructions
+    pub fn format_inst(&self, inst: &Type) -> String {
+        let mnemonic = inst.mnemonic();
+        match self
+            .instructions
+            .iter()
+            .find(|custom| custom.mnemonic() == mnemonic)
+        {
+            Some(custom) => custom.disassemble(inst),
+            None => inst.to_string(),
+        }
+    }
+
+    fn print_trace(&self, pc: u32, decoded: &Decoded) {
+        let width = decoded.length as usize * 8;
+        if self.harts.len() > 1 {
+            print!(" hart : {}", self.hart);
+        }
+        println!(
+            " pc : 0x{:08x} inst : 0b{:0width$b} {}",
+            pc,
+            decoded.word,
+            self.format_inst(&decoded.inst),
+            width = width
+        );
+    }
+
+    pub fn decode_32(word: u32) -> Type {
+        isa::decode_32(word)
+    }
+
+    pub fn decode_16(word: u16) -> Type {
+        isa::decode_16(word)
     }
 
-    #[allow(clippy::single_match)]
     pub fn execute(&mut self, format: &Type) -> Result<()> {
+        self.dispatch(isa::by_mnemonic(format.mnemonic()), format)
+    }
+
+    // execute with the instruction's ISA row looked up already, see Decoded;
+    // dispatches on its opcode, plugin instructions have none
+    fn dispatch(&mut self, spec: Option<&'static Spec>, format: &Type) -> Result<()> {
+        let opcode = match spec {
+            // instructions of extensions the profile does not implement are illegal
+            Some(spec) if !self.isa.implements(spec) => return Err(illegal(format, self.isa)),
+            Some(spec) => spec.opcode,
+            None => return self.execute_custom(format),
+        };
         match format {
-            Type::RFormat { mnemonic, rd, rs } => match mnemonic.as_str() {
-                "MOV" => self.cpu.mov(*rd, *rs),
-                "ADD" => self.cpu.add(*rd, *rs),
-                "SUB" => self.cpu.sub(*rd, *rs),
-                "AND" => self.cpu.and(*rd, *rs),
-                "OR" => self.cpu.or(*rd, *rs),
-                "XOR" => self.cpu.xor(*rd, *rs),
-                "SLL" => self.cpu.sll(*rd, *rs),
-                "SRL" => self.cpu.srl(*rd, *rs),
-                "SRA" => self.cpu.sra(*rd, *rs),
-                "SLT" => self.cpu.slt(*rd, *rs),
-                "SLTU" => self.cpu.sltu(*rd, *rs),
-                _ => {}
+            Type::RFormat { rd, rs, .. } => match opcode {
+                op::MOV => self.cpu.mov(*rd, *rs),
+                op::ADD => self.cpu.add(*rd, *rs),
+                op::SUB => self.cpu.sub(*rd, *rs),
+                op::AND => self.cpu.and(*rd, *rs),
+                op::OR => self.cpu.or(*rd, *rs),
+                op::XOR => self.cpu.xor(*rd, *rs),
+                op::SLL => self.cpu.sll(*rd, *rs),
+                op::SRL => self.cpu.srl(*rd, *rs),
+                op::SRA => self.cpu.sra(*rd, *rs),
+                op::SLT => self.cpu.slt(*rd, *rs),
+                op::SLTU => self.cpu.sltu(*rd, *rs),
+                op::MUL => self.cpu.mul(*rd, *rs),
+                op::MULH => self.cpu.mulh(*rd, *rs),
+                op::MULHU => self.cpu.mulhu(*rd, *rs),
+                op::DIV => self.cpu.div(*rd, *rs),
+                op::DIVU => self.cpu.divu(*rd, *rs),
+                op::REM => self.cpu.rem(*rd, *rs),
+                op::REMU => self.cpu.remu(*rd, *rs),
+                _ => return self.execute_custom(format),
             },
 
-            Type::I16Format { mnemonic, rd, imm } => match mnemonic.as_str() {
-                "SLLI" => self.cpu.slli(*rd, *imm),
-                "SRLI" => self.cpu.srli(*rd, *imm),
-                "SRAI" => self.cpu.srai(*rd, *imm),
-                _ => {}
+            Type::I16Format { rd, imm, .. } => match opcode {
+                op::SLLI => self.cpu.slli(*rd, *imm),
+                op::SRLI => self.cpu.srli(*rd, *imm),
+                op::SRAI => self.cpu.srai(*rd, *imm),
+                op::TRAP => self.trap(*imm)?,
+                _ => return self.execute_custom(format),
             },
 
-            Type::I32Format {
-                mnemonic,
-                rd,
-                rs,
-                imm,
-            } => match mnemonic.as_str() {
-                "ADDI" => self.cpu.addi(*rd, *rs, *imm),
-                "ANDI" => self.cpu.andi(*rd, *rs, *imm),
-                "ORI" => self.cpu.ori(*rd, *rs, *imm),
-                "XORI" => self.cpu.xori(*rd, *rs, *imm),
-                "SLTI" => self.cpu.slti(*rd, *rs, *imm),
-                "SLTIU" => self.cpu.sltiu(*rd, *rs, *imm),
-                "BEQ" => {
-                    if self.cpu.get_reg(*rd) == self.cpu.get_reg(*rs) {
-                        self.cpu.pc = self.cpu.pc.wrapping_add(*imm as u32);
-                    }
-                }
-                "BNQ" => {
-                    if self.cpu.get_reg(*rd) != self.cpu.get_reg(*rs) {
-                        self.cpu.pc = self.cpu.pc.wrapping_add(*imm as u32);
-                    }
-                }
-                "BLT" => {
-                    if (self.cpu.get_reg(*rd) as i32) < (self.cpu.get_reg(*rs) as i32) {
-                        self.cpu.pc = self.cpu.pc.wrapping_add(*imm as u32);
-                    }
-                }
-                "BGE" => {
-                    if (self.cpu.get_reg(*rd) as i32) >= (self.cpu.get_reg(*rs) as i32) {
-                        self.cpu.pc = self.cpu.pc.wrapping_add(*imm as u32);
-                    }
-                }
-                "BLTU" => {
-                    if self.cpu.get_reg(*rd) < self.cpu.get_reg(*rs) {
-                        self.cpu.pc = self.cpu.pc.wrapping_add(*imm as u32);
-                    }
-                }
-                "BGEU" => {
-                    if self.cpu.get_reg(*rd) >= self.cpu.get_reg(*rs) {
-                        self.cpu.pc = self.cpu.pc.wrapping_add(*imm as u32);
-                    }
-                }
-                "JALR" => {
+            Type::I32Format { rd, rs, imm, .. } => match opcode {
+                op::ADDI => self.cpu.addi(*rd, *rs, *imm),
+                op::ANDI => self.cpu.andi(*rd, *rs, *imm),
+                op::ORI => self.cpu.ori(*rd, *rs, *imm),
+                op::XORI => self.cpu.xori(*rd, *rs, *imm),
+                op::SLTI => self.cpu.slti(*rd, *rs, *imm),
+                op::SLTIU => self.cpu.sltiu(*rd, *rs, *imm),
+                op::BEQ => self.branch(self.cpu.get_reg(*rd) == self.cpu.get_reg(*rs), *imm),
+                op::BNQ => self.branch(self.cpu.get_reg(*rd) != self.cpu.get_reg(*rs), *imm),
+                op::BLT => self.branch(
+                    (self.cpu.get_reg(*rd) as i32) < (self.cpu.get_reg(*rs) as i32),
+                    *imm,
+                ),
+                op::BGE => self.branch(
+                    (self.cpu.get_reg(*rd) as i32) >= (self.cpu.get_reg(*rs) as i32),
+                    *imm,
+                ),
+                op::BLTU => self.branch(self.cpu.get_reg(*rd) < self.cpu.get_reg(*rs), *imm),
+                op::BGEU => self.branch(self.cpu.get_reg(*rd) >= self.cpu.get_reg(*rs), *imm),
+                op::JALR => {
+                    let target = self.cpu.get_reg(*rs).wrapping_add(*imm as u32);
                     self.cpu.set_reg(*rd, self.cpu.pc);
-                    self.cpu.pc = self.cpu.get_reg(*rs).wrapping_add(*imm as u32);
+                    self.cpu.pc = target;
                 }
-                "LB" => self.cpu.set_reg(
+                op::LB => self.cpu.set_reg(
                     *rd,
                     ((self
                         .memory
                         .read_data_8bit(self.cpu.register[*rs as usize].wrapping_add(*imm as u32))?
                         as i8) as i32) as u32,
                 ),
-                "LH" => self.cpu.set_reg(
-                    *rd,
-                    ((self.memory.read_data_16bit(
-                        self.cpu.register[*rs as usize].wrapping_add(*imm as u32),
-                    )? as i16) as i32) as u32,
-                ),
-                "LBU" => self.cpu.set_reg(
+                op::LH => {
+                    let data = self.load(self.cpu.get_reg(*rs).wrapping_add(*imm as u32), 2)?;
+                    self.cpu.set_reg(*rd, ((data as u16 as i16) as i32) as u32)
+                }
+                op::LBU => self.cpu.set_reg(
                     *rd,
                     self.memory
                         .read_data_8bit(self.cpu.register[*rs as usize].wrapping_add(*imm as u32))?
                         as u32,
                 ),
-                "LHU" => self.cpu.set_reg(
-                    *rd,
-                    self.memory.read_data_16bit(
-                        self.cpu.register[*rs as usize].wrapping_add(*imm as u32),
-                    )? as u32,
-                ),
-                "LW" => self.cpu.set_reg(
-                    *rd,
-                    self.memory
-                        .read_data(self.cpu.register[*rs as usize].wrapping_add(*imm as u32))?,
-                ),
-                "LUI" => self.cpu.set_reg(*rd, (*imm << 16) as u32),
-                "SB" => self.memory.write_data_8bit(
+                op::LHU => {
+                    let data = self.load(self.cpu.get_reg(*rs).wrapping_add(*imm as u32), 2)?;
+                    self.cpu.set_reg(*rd, data)
+                }
+                op::LW => {
+                    let data = self.load(self.cpu.get_reg(*rs).wrapping_add(*imm as u32), 4)?;
+                    self.cpu.set_reg(*rd, data)
+                }
+                op::LUI => self.cpu.set_reg(*rd, (*imm << 16) as u32),
+                op::SB => self.memory.write_data_8bit(
                     self.cpu.get_reg(*rs).wrapping_add(*imm as u32),
                     self.cpu.get_reg(*rd) as u8,
                 )?,
-                "SH" => self.memory.write_data_16bit(
+                op::SH => self.store(
                     self.cpu.get_reg(*rs).wrapping_add(*imm as u32),
-                    self.cpu.get_reg(*rd) as u16,
+                    2,
+                    self.cpu.get_reg(*rd) & 0xFFFF,
                 )?,
-                "SW" => self.memory.write_data(
+                op::SW => self.store(
                     self.cpu.get_reg(*rs).wrapping_add(*imm as u32),
+                    4,
                     self.cpu.get_reg(*rd),
                 )?,
-                _ => {}
+                _ => return self.execute_custom(format),
             },
 
-            Type::JFormat { mnemonic, rd, imm } => match mnemonic.as_str() {
-                "JAL" => {
+            Type::JFormat { rd, imm, .. } => match opcode {
+                op::JAL => {
                     self.cpu.set_reg(*rd, self.cpu.pc);
                     self.cpu.pc = self.cpu.pc.wrapping_add(*imm as u32);
                 }
-                _ => {}
+                _ => return self.execute_custom(format),
             },
         }
         Ok(())
     }
+
+    // plugin instructions, anything else is illegal
+    fn execute_custom(&mut self, inst: &Type) -> Result<()> {
+        let mnemonic = inst.mnemonic();
+        match self
+            .instructions
+            .iter_mut()
+            .find(|custom| custom.mnemonic() == mnemonic)
+        {
+            Some(custom) => custom.execute(inst, &mut self.cpu, &mut self.memory),
+            None => Err(illegal(inst, self.isa)),
+        }
+    }
+
+    fn retire(&mut self, pc: u32, inst: &Type, fallthrough_pc: u32) {
+        self.instret += 1;
+        if let Some(timing) = self.timing.as_mut() {
+            self.cycles += timing.cycles(inst, fallthrough_pc - pc, self.cpu.pc != fallthrough_pc);
+        }
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(pc, inst, self.cpu.pc, fallthrough_pc);
+        }
+    }
+
+    pub fn cpi(&self) -> f64 {
+        if self.instret == 0 {
+            0.0
+        } else {
+            self.cycles as f64 / self.instret as f64
+        }
+    }
+
+    // halfword or word load under the alignment policy
+    fn load(&mut self, address: u32, size: u32) -> Result<u32, MemoryError> {
+        if !address.is_multiple_of(size) {
+            match self.alignment {
+                Alignment::Allow => {}
+                Alignment::Trap => return Err(misaligned(address, size)),
+                Alignment::Split => {
+                    let mut data = 0;
+                    for i in 0..size {
+                        let byte = self.memory.read_data_8bit(address.wrapping_add(i))?;
+                        data |= (byte as u32) << self.memory.byte_order.shift(i, size);
+                    }
+                    return Ok(data);
+                }
+            }
+        }
+        match size {
+            2 => self.memory.read_data_16bit(address).map(u32::from),
+            _ => self.memory.read_data(address),
+        }
+    }
+
+    fn store(&mut self, address: u32, size: u32, data: u32) -> Result<(), MemoryError> {
+        if !address.is_multiple_of(size) {
+            match self.alignment {
+                Alignment::Allow => {}
+                Alignment::Trap => return Err(misaligned(address, size)),
+                Alignment::Split => {
+                    let bytes = self.memory.byte_order.bytes(data, size);
+                    for (i, byte) in (0..).zip(bytes) {
+                        self.memory.write_data_8bit(address.wrapping_add(i), byte)?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        match size {
+            2 => self.memory.write_data_16bit(address, data as u16),
+            _ => self.memory.write_data(address, data),
+        }
+    }
+
+    fn trap(&mut self, number: u8) -> Result<()> {
+        let exit_code = match (number, self.semihost.as_mut(), self.syscalls.as_mut()) {
+            (semihost::TRAP, Some(semihost), _) => {
+                semihost.call(&mut self.cpu, &mut self.memory)?
+            }
+            (syscall::TRAP, _, Some(syscalls)) => syscalls.call(&mut self.cpu, &mut self.memory)?,
+            _ => return Err(anyhow!("unhandled trap {}", number)),
+        };
+        if exit_code.is_some() {
+            self.exit_code = exit_code;
+        }
+        Ok(())
+    }
+
+    fn branch(&mut self, taken: bool, imm: i32) {
+        if taken {
+            self.cpu.pc = self.cpu.pc.wrapping_add(imm as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn emulator() -> Emulator {
+        let mut emu = Emulator::new(vec![]);
+        emu.memory.init();
+        emu.trace = false;
+        emu
+    }
+
+    fn i32_format(mnemonic: &'static str, rd: u8, rs: u8, imm: i32) -> Type {
+        Type::I32Format {
+            mnemonic,
+            rd,
+            rs,
+            imm,
+        }
+    }
+
+    #[test]
+    fn decode_32_sign_extends_immediates() {
+        // ADDI r1 r2 -1
+        assert_eq!(
+            Emulator::decode_32(0xFFFF_1060),
+            i32_format("ADDI", 1, 2, -1)
+        );
+        // LHU r3 r4 0x7FFF
+        assert_eq!(
+            Emulator::decode_32(0x7FFF_20F0),
+            i32_format("LHU", 3, 4, 0x7FFF)
+        );
+        // JAL r1 -8
+        assert_eq!(
+            Emulator::decode_32(0xFFFF_C07F),
+            Type::JFormat {
+                mnemonic: "JAL",
+                rd: 1,
+                imm: -8
+            }
+        );
+        assert_eq!(Emulator::decode_32(0x0000_003E).mnemonic(), "UNKNOWN");
+    }
+
+    #[test]
+    fn decode_16_fields() {
+        // ADD r3 r4
+        assert_eq!(
+            Emulator::decode_16(0x20C1),
+            Type::RFormat {
+                mnemonic: "ADD",
+                rd: 3,
+                rs: 4
+            }
+        );
+        // SRAI r31 31
+        assert_eq!(
+            Emulator::decode_16(0xFFD2),
+            Type::I16Format {
+                mnemonic: "SRAI",
+                rd: 31,
+                imm: 31
+            }
+        );
+        assert_eq!(Emulator::decode_16(0x001F).mnemonic(), "UNKNOWN");
+    }
+
+    #[test]
+    fn branches_are_relative_to_the_next_instruction() {
+        let mut emu = emulator();
+        emu.cpu.set_reg(1, 0xFFFF_FFFF);
+        emu.cpu.set_reg(2, 1);
+        let cases = [
+            ("BEQ", false),
+            ("BNQ", true),
+            ("BLT", true),
+            ("BGE", false),
+            ("BLTU", false),
+            ("BGEU", true),
+        ];
+        for (mnemonic, taken) in cases {
+            emu.cpu.pc = 0x100;
+            emu.execute(&i32_format(mnemonic, 1, 2, -0x10)).unwrap();
+            let expected = if taken { 0xF0 } else { 0x100 };
+            assert_eq!(emu.cpu.pc, expected, "{}", mnemonic);
+        }
+    }
+
+    #[test]
+    fn jumps_link_the_next_pc() {
+        let mut emu = emulator();
+        emu.cpu.pc = 0x20;
+        emu.execute(&Type::JFormat {
+            mnemonic: "JAL",
+            rd: 1,
+            imm: -0x10,
+        })
+        .unwrap();
+        assert_eq!((emu.cpu.pc, emu.cpu.get_reg(1)), (0x10, 0x20));
+
+        // JALR reads rs before writing rd
+        emu.cpu.set_reg(2, 0x40);
+        emu.execute(&i32_format("JALR", 2, 2, 4)).unwrap();
+        assert_eq!((emu.cpu.pc, emu.cpu.get_reg(2)), (0x44, 0x10));
+
+        emu.execute(&i32_format("JALR", 0, 2, 0)).unwrap();
+        assert_eq!((emu.cpu.pc, emu.cpu.get_reg(0)), (0x10, 0));
+    }
+
+    #[test]
+    fn loads_extend() {
+        let mut emu = emulator();
+        emu.memory.write_data(0x80, 0x8081_F0FF).unwrap();
+        emu.cpu.set_reg(1, 0x90);
+        let cases = [
+            ("LB", 0xFFFF_FFFF),
+            ("LBU", 0x0000_00FF),
+            ("LH", 0xFFFF_F0FF),
+            ("LHU", 0x0000_F0FF),
+            ("LW", 0x8081_F0FF),
+        ];
+        for (mnemonic, expected) in cases {
+            emu.execute(&i32_format(mnemonic, 2, 1, -0x10)).unwrap();
+            assert_eq!(emu.cpu.get_reg(2), expected, "{}", mnemonic);
+            emu.execute(&i32_format(mnemonic, 0, 1, -0x10)).unwrap();
+            assert_eq!(emu.cpu.get_reg(0), 0);
+        }
+        emu.execute(&i32_format("LUI", 2, 0, -2)).unwrap();
+        assert_eq!(emu.cpu.get_reg(2), 0xFFFE_0000);
+    }
+
+    #[test]
+    fn stores_write_low_bytes() {
+        let mut emu = emulator();
+        emu.cpu.set_reg(1, 0x1122_3344);
+        emu.cpu.set_reg(2, 0x40);
+        emu.execute(&i32_format("SW", 1, 2, 0)).unwrap();
+        emu.execute(&i32_format("SH", 1, 2, 4)).unwrap();
+        emu.execute(&i32_format("SB", 1, 2, 6)).unwrap();
+        assert_eq!(
+            emu.memory.memory_array[0x40..0x48],
+            [0x44, 0x33, 0x22, 0x11, 0x44, 0x33, 0x44, 0x00]
+        );
+    }
+
+    #[test]
+    fn step_advances_by_instruction_length() {
+        // ADD r1 r1; ADDI r1 r0 5
+        let mut emu = Emulator::new(vec![0x41, 0x08, 0x60, 0x00, 0x05, 0x00]);
+        emu.memory.init();
+        emu.trace = false;
+        emu.step().unwrap();
+        assert_eq!(emu.cpu.pc, 2);
+        emu.step().unwrap();
+        assert_eq!((emu.cpu.pc, emu.cpu.get_reg(1)), (6, 5));
+        assert_eq!(emu.instret, 2);
+    }
+
+    #[test]
+    fn reverse_step_restores_registers_and_memory() {
+        // ADDI r1 r0 5; SW r1 r0 0x40
+        let mut emu = Emulator::new(vec![0x60, 0x00, 0x05, 0x00, 0x75, 0x00, 0x40, 0x00]);
+        emu.memory.init();
+        emu.trace = false;
+        emu.history = History::new(16);
+        emu.run().unwrap();
+        assert_eq!(emu.memory.read_data(0x40).unwrap(), 5);
+        assert!(emu.reverse_step());
+        assert_eq!((emu.cpu.pc, emu.memory.read_data(0x40).unwrap()), (4, 0));
+        assert!(emu.reverse_step());
+        assert_eq!((emu.cpu.pc, emu.cpu.get_reg(1)), (0, 0));
+        assert!(!emu.reverse_step());
+    }
+
+    #[test]
+    fn reverse_step_skips_steps_that_did_not_retire() {
+        // JALR r0 r0 1, then a trapped fetch from pc 1
+        let mut emu = Emulator::new(vec![0x2c, 0x00, 0x01, 0x00]);
+        emu.memory.init();
+        emu.trace = false;
+        emu.alignment = Alignment::Trap;
+        emu.history = History::new(16);
+        emu.step().unwrap();
+        assert!(emu.step().is_err());
+        assert_eq!((emu.cpu.pc, emu.instret), (1, 1));
+        assert!(emu.reverse_step());
+        assert_eq!((emu.cpu.pc, emu.instret), (0, 0));
+        assert!(!emu.reverse_step());
+    }
+
+    #[test]
+    fn reverse_step_restores_the_timing_model() {
+        use crate::asm::reg::*;
+        use crate::asm::Asm;
+
+        let mut emu = Asm::new().lw(r1, r0, 0x100).add(r2, r1).emulator().unwrap();
+        emu.trace = false;
+        emu.history = History::new(16);
+        emu.timing = Some(Box::new(timing::Pipeline::new()));
+        emu.step().unwrap();
+        emu.step().unwrap();
+        let cycles = emu.cycles;
+        assert!(emu.reverse_step());
+        emu.step().unwrap();
+        assert_eq!(emu.cycles, cycles);
+    }
+
+    #[test]
+    fn threaded_engine_reports_what_blocks_it() {
+        let mut emu = emulator();
+        assert_eq!(emu.threaded_blocker(), None);
+        emu.history = History::new(16);
+        assert_eq!(
+            emu.threaded_blocker(),
+            Some("history for reverse execution is kept")
+        );
+        emu.history = History::new(0);
+        emu.add_hart();
+        assert_eq!(emu.threaded_blocker(), Some("there are several harts"));
+    }
+
+    #[test]
+    fn alignment_policy_applies_to_misaligned_accesses() {
+        for alignment in [Alignment::Allow, Alignment::Trap, Alignment::Split] {
+            let mut emu = emulator();
+            emu.alignment = alignment;
+            for (i, byte) in [1, 2, 3, 4].into_iter().enumerate() {
+                emu.memory.store(0x41 + i as u32, byte);
+            }
+            emu.cpu.set_reg(2, 0x41);
+            emu.memory.accesses = Some(Vec::new());
+            let load = emu.execute(&i32_format("LW", 1, 2, 0));
+            let store = emu.execute(&i32_format("SH", 1, 2, 8));
+            let accesses = emu.memory.accesses.take().unwrap();
+
+            if alignment == Alignment::Trap {
+                assert!(load.is_err() && store.is_err());
+                assert_eq!(emu.cpu.get_reg(1), 0);
+                assert!(accesses.is_empty());
+                continue;
+            }
+            assert_eq!(emu.cpu.get_reg(1), 0x0403_0201);
+            assert_eq!(emu.memory.memory_array[0x49..0x4B], [0x01, 0x02]);
+            let sizes = accesses
+                .iter()
+                .map(|access| access.size)
+                .collect::<Vec<_>>();
+            if alignment == Alignment::Split {
+                assert_eq!(sizes, [1, 1, 1, 1, 1, 1]);
+            } else {
+                assert_eq!(sizes, [4, 2]);
+            }
+        }
+    }
+
+    #[test]
+    fn trapped_misaligned_accesses_stop_the_step() {
+        use crate::asm::reg::*;
+        use crate::asm::Asm;
+
+        let mut asm = Asm::new();
+        asm.li(r1, 0x1234).lw(r2, r0, 0x41).sh(r1, r0, 0x43);
+        let mut emu = asm.emulator().unwrap();
+        emu.trace = false;
+        emu.alignment = Alignment::Trap;
+        emu.step().unwrap();
+        emu.step().unwrap();
+        let error = emu.step().unwrap_err();
+        assert_eq!(error.to_string(), "misaligned 4-byte access at 0x00000041");
+        assert_eq!((emu.cpu.pc, emu.instret, emu.cpu.get_reg(r2)), (8, 2, 0));
+        assert!(emu.run().is_err());
+
+        emu.cpu.pc = 12;
+        assert!(emu.step().is_err());
+        assert_eq!((emu.cpu.pc, emu.instret), (12, 2));
+        assert_eq!(emu.memory.memory_array[0x43..0x45], [0, 0]);
+    }
+
+    #[test]
+    fn trapping_alignment_rejects_odd_fetches() {
+        let mut emu = emulator();
+        emu.cpu.pc = 1;
+        assert!(emu.step().is_ok());
+        emu.cpu.pc = 1;
+        emu.alignment = Alignment::Trap;
+        assert!(emu.step().is_err());
+    }
+
+    #[test]
+    fn unused_opcodes_are_illegal() {
+        let mut emu = emulator();
+        for inst in [
+            Emulator::decode_16(0x003F),
+            Emulator::decode_32(0x0000_003E),
+        ] {
+            let error = emu.execute(&inst).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "illegal instruction UNKNOWN for ISA base"
+            );
+        }
+    }
+
+    #[test]
+    fn illegal_instructions_stop_without_retiring() {
+        let mut emu = emulator();
+        emu.history = History::new(16);
+        // ADDI r1 r0 5, then the unused 32-bit opcode 0b111110
+        for (i, byte) in [0x60, 0x00, 0x05, 0x00, 0x3e, 0x00, 0x00, 0x00]
+            .into_iter()
+            .enumerate()
+        {
+            emu.memory.store(i as u32, byte);
+        }
+        emu.step().unwrap();
+        let error = emu.step().unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "illegal instruction UNKNOWN for ISA base"
+        );
+        assert_eq!((emu.cpu.pc, emu.instret), (4, 1));
+        assert!(emu.run().is_err());
+        assert!(emu.run_until_halt(10).is_err());
+        assert_eq!(emu.cpu.pc, 4);
+        assert!(emu.reverse_step());
+        assert_eq!((emu.cpu.pc, emu.cpu.get_reg(1)), (0, 0));
+    }
+
+    #[test]
+    fn multiply_and_divide_need_the_m_extension() {
+        use crate::asm::reg::*;
+        use crate::asm::Asm;
+
+        let mut asm = Asm::new();
+        asm.li(r1, 6).li(r2, 7).li(r3, 45).li(r4, 0);
+        let mul = asm.pc();
+        asm.mul(r1, r2).div(r3, r2).remu(r2, r4);
+        let end = asm.pc();
+        asm.halt();
+        for engine in [Engine::Interpreter, Engine::Threaded] {
+            let mut emu = asm.emulator().unwrap();
+            emu.engine = engine;
+            emu.break_point = end;
+            let error = emu.run().unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "illegal instruction MUL r1 r2 for ISA base"
+            );
+            assert_eq!(emu.cpu.pc, mul);
+            assert_eq!([1, 2, 3].map(|num| emu.cpu.get_reg(num)), [6, 7, 45]);
+
+            let mut emu = asm.emulator().unwrap();
+            emu.engine = engine;
+            emu.isa = Profile::BASE.with(isa::Extension::M);
+            emu.break_point = end;
+            emu.run().unwrap();
+            assert_eq!([1, 2, 3].map(|num| emu.cpu.get_reg(num)), [42, 7, 6]);
+        }
+    }
+
+    #[test]
+    fn programs_run_in_either_byte_order() {
+        use crate::asm::reg::*;
+        use crate::asm::Asm;
+        use memory::ByteOrder;
+
+        let expected = [
+            (ByteOrder::Little, [0x78, 0x56, 0x34, 0x12], 0x5678, 0x12),
+            (ByteOrder::Big, [0x12, 0x34, 0x56, 0x78], 0x1234, 0x78),
+        ];
+        for (order, bytes, half, byte) in expected {
+            let mut asm = Asm::new();
+            asm.byte_order(order)
+                .li(r1, 0x1234_5678)
+                .sw(r1, r0, 0x100)
+                .lhu(r2, r0, 0x100)
+                .lb(r3, r0, 0x103)
+                .addi(r5, r0, 3)
+                .label("loop")
+                .addi(r4, r4, 1)
+                .bne(r4, r5, "loop");
+            let end = asm.pc();
+            asm.halt();
+            for engine in [Engine::Interpreter, Engine::Threaded] {
+                let mut emu = asm.emulator().unwrap();
+                emu.engine = engine;
+                emu.break_point = end;
+                emu.run().unwrap();
+                assert_eq!(emu.memory.memory_array[0x100..0x104], bytes);
+                assert_eq!(emu.cpu.get_reg(r2), half);
+                assert_eq!(emu.cpu.get_reg(r3), byte);
+                assert_eq!(emu.cpu.get_reg(r4), 3);
+            }
+        }
+    }
+
+    #[test]
+    fn fetches_stop_at_the_end_of_memory() {
+        let mut emu = emulator();
+        // ADD r1 r1 in the last halfword
+        emu.memory.store(memory::MEMORY_SIZE - 2, 0x41);
+        emu.memory.store(memory::MEMORY_SIZE - 1, 0x08);
+        emu.cpu.pc = memory::MEMORY_SIZE - 2;
+        emu.step().unwrap();
+        assert_eq!(emu.cpu.pc, memory::MEMORY_SIZE);
+        for pc in [memory::MEMORY_SIZE - 1, memory::MEMORY_SIZE, u32::MAX] {
+            emu.cpu.pc = pc;
+            assert!(emu.step().is_err());
+        }
+    }
 }
@@ -0,0 +1,385 @@
+use super::hooks::Hook;
+use super::icache::Decoded;
+use super::isa::{self, Category};
+use super::{Emulator, Type};
+use anyhow::{anyhow, Context, Result};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::rc::Rc;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Coverage {
+    // execution count per instruction address
+    pub hits: BTreeMap<u32, u64>,
+    // (taken, not taken) per conditional branch
+    pub branches: BTreeMap<u32, (u64, u64)>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    pub instructions: usize,
+    pub covered: usize,
+    // two outcomes per conditional branch
+    pub outcomes: usize,
+    pub outcomes_covered: usize,
+}
+
+impl Summary {
+    pub fn percent(covered: usize, total: usize) -> f64 {
+        if total == 0 {
+            100.0
+        } else {
+            covered as f64 * 100.0 / total as f64
+        }
+    }
+}
+
+// Hook filling a shared Coverage, see Emulator::add_hook
+#[derive(Debug, Default)]
+pub struct Collector {
+    pub coverage: Rc<RefCell<Coverage>>,
+}
+
+impl Hook for Collector {
+    fn post_execute(&mut self, emu: &Emulator, pc: u32, inst: &Type) {
+        let mut coverage = self.coverage.borrow_mut();
+        *coverage.hits.entry(pc).or_insert(0) += 1;
+        if is_branch(inst) {
+            // branches are 32-bit, a branch to the next instruction counts as not taken
+            let counts = coverage.branches.entry(pc).or_insert((0, 0));
+            if emu.cpu.pc != pc.wrapping_add(4) {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+        }
+    }
+}
+
+fn is_branch(inst: &Type) -> bool {
+    isa::by_mnemonic(inst.mnemonic()).is_some_and(|spec| spec.category == Category::Branch)
+}
+
+// instructions found by a linear sweep of the program image
+pub fn listing(emu: &Emulator, end: u32) -> Vec<(u32, Decoded)> {
+    let mut rows = Vec::new();
+    let mut address = 0;
+    while address < end {
+        let Some(decoded) = emu.disassemble(address) else {
+            break;
+        };
+        rows.push((address, decoded));
+        address += decoded.length;
+    }
+    rows
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // reads the "pc <hex address> <count>" and
+    // "branch <hex address> <taken> <not taken>" lines written by save
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut coverage = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let address = |text: &str| {
+                u32::from_str_radix(text.trim_start_matches("0x"), 16)
+                    .with_context(|| format!("invalid coverage address at line {}", i + 1))
+            };
+            let count = |text: &str| {
+                text.parse::<u64>()
+                    .with_context(|| format!("invalid coverage count at line {}", i + 1))
+            };
+            match fields.as_slice() {
+                [] => continue,
+                ["pc", pc, hits] => {
+                    *coverage.hits.entry(address(pc)?).or_insert(0) += count(hits)?;
+                }
+                ["branch", pc, taken, not_taken] => {
+                    let counts = coverage.branches.entry(address(pc)?).or_insert((0, 0));
+                    counts.0 += count(taken)?;
+                    counts.1 += count(not_taken)?;
+                }
+                _ => return Err(anyhow!("invalid coverage record at line {}", i + 1)),
+            }
+        }
+        Ok(coverage)
+    }
+
+    pub fn save(&self, w: &mut impl Write) -> std::io::Result<()> {
+        for (pc, hits) in &self.hits {
+            writeln!(w, "pc 0x{:08x} {}", pc, hits)?;
+        }
+        for (pc, (taken, not_taken)) in &self.branches {
+            writeln!(w, "branch 0x{:08x} {} {}", pc, taken, not_taken)?;
+        }
+        Ok(())
+    }
+
+    pub fn merge(&mut self, other: &Coverage) {
+        for (pc, hits) in &other.hits {
+            *self.hits.entry(*pc).or_insert(0) += hits;
+        }
+        for (pc, (taken, not_taken)) in &other.branches {
+            let counts = self.branches.entry(*pc).or_insert((0, 0));
+            counts.0 += taken;
+            counts.1 += not_taken;
+        }
+    }
+
+    fn outcomes(&self, pc: u32) -> (u64, u64) {
+        self.branches.get(&pc).copied().unwrap_or((0, 0))
+    }
+
+    pub fn summary(&self, emu: &Emulator, end: u32) -> Summary {
+        let mut summary = Summary::default();
+        for (pc, decoded) in listing(emu, end) {
+            summary.instructions += 1;
+            if self.hits.contains_key(&pc) {
+                summary.covered += 1;
+            }
+            if is_branch(&decoded.inst) {
+                let (taken, not_taken) = self.outcomes(pc);
+                summary.outcomes += 2;
+                summary.outcomes_covered += usize::from(taken > 0) + usize::from(not_taken > 0);
+            }
+        }
+        summary
+    }
+
+    pub fn report(&self, emu: &Emulator, end: u32) {
+        let summary = self.summary(emu, end);
+        println!(
+            "coverage : {}/{} instructions ({:.1}%)  {}/{} branch outcomes ({:.1}%)",
+            summary.covered,
+            summary.instructions,
+            Summary::percent(summary.covered, summary.instructions),
+            summary.outcomes_covered,
+            summary.outcomes,
+            Summary::percent(summary.outcomes_covered, summary.outcomes),
+        );
+    }
+
+    // gcov style listing, "#####" marks instructions that never ran
+    pub fn write_annotated(&self, w: &mut impl Write, emu: &Emulator, end: u32) -> Result<()> {
+        for (pc, decoded) in listing(emu, end) {
+            if let Some(name) = emu.symbols.entries.get(&pc) {
+                writeln!(w, "{:>10}  {}:", "", name)?;
+            }
+            let hits = match self.hits.get(&pc) {
+                Some(hits) => hits.to_string(),
+                None => "#####".to_string(),
+            };
+            let inst = emu.format_inst(&decoded.inst);
+            write!(w, "{:>10}  0x{:08x}  {}", hits, pc, inst)?;
+            if is_branch(&decoded.inst) {
+                let (taken, not_taken) = self.outcomes(pc);
+                write!(w, "  [taken {}, not taken {}]", taken, not_taken)?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+
+    // one record per source file; a line counts as executed as often as its
+    // most executed instruction
+    pub fn write_lcov(
+        &self,
+        w: &mut impl Write,
+        emu: &Emulator,
+        end: u32,
+        lines: &LineMap,
+    ) -> Result<()> {
+        #[derive(Default)]
+        struct Line {
+            hits: u64,
+            // (executed, taken, not taken) per conditional branch
+            branches: Vec<(bool, u64, u64)>,
+        }
+        let mut files: BTreeMap<&str, BTreeMap<u32, Line>> = BTreeMap::new();
+        for (pc, decoded) in listing(emu, end) {
+            let Some((file, number)) = lines.lookup(pc) else {
+                continue;
+            };
+            let line = files.entry(file).or_default().entry(number).or_default();
+            let hits = self.hits.get(&pc).copied().unwrap_or(0);
+            line.hits = line.hits.max(hits);
+            if is_branch(&decoded.inst) {
+                let (taken, not_taken) = self.outcomes(pc);
+                line.branches.push((hits > 0, taken, not_taken));
+            }
+        }
+
+        writeln!(w, "TN:")?;
+        for (file, lines) in files {
+            writeln!(w, "SF:{}", file)?;
+            let (mut branches, mut branches_hit) = (0, 0);
+            for (number, line) in &lines {
+                for (block, (executed, taken, not_taken)) in line.branches.iter().enumerate() {
+                    for (branch, count) in [taken, not_taken].into_iter().enumerate() {
+                        let count = if *executed {
+                            count.to_string()
+                        } else {
+                            "-".to_string()
+                        };
+                        writeln!(w, "BRDA:{},{},{},{}", number, block, branch, count)?;
+                    }
+                    branches += 2;
+                    branches_hit += usize::from(*taken > 0) + usize::from(*not_taken > 0);
+                }
+            }
+            if branches > 0 {
+                writeln!(w, "BRF:{}", branches)?;
+                writeln!(w, "BRH:{}", branches_hit)?;
+            }
+            for (number, line) in &lines {
+                writeln!(w, "DA:{},{}", number, line.hits)?;
+            }
+            writeln!(w, "LF:{}", lines.len())?;
+            writeln!(
+                w,
+                "LH:{}",
+                lines.values().filter(|line| line.hits > 0).count()
+            )?;
+            writeln!(w, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+// source line of every instruction address
+#[derive(Debug, Default)]
+pub struct LineMap {
+    pub entries: BTreeMap<u32, (String, u32)>,
+}
+
+impl LineMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // accepts "<hex address> <file>:<line>" lines, an entry covers the
+    // addresses up to the next one
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut lines = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (address, location) = match fields.as_slice() {
+                [] => continue,
+                [address, location] => (address, location),
+                _ => return Err(anyhow!("invalid line map entry at line {}", i + 1)),
+            };
+            let address = u32::from_str_radix(address.trim_start_matches("0x"), 16)
+                .with_context(|| format!("invalid line map address at line {}", i + 1))?;
+            let (file, number) = location
+                .rsplit_once(':')
+                .and_then(|(file, number)| Some((file, number.parse::<u32>().ok()?)))
+                .ok_or_else(|| anyhow!("invalid line map location at line {}", i + 1))?;
+            lines.entries.insert(address, (file.to_string(), number));
+        }
+        Ok(lines)
+    }
+
+    pub fn lookup(&self, address: u32) -> Option<(&str, u32)> {
+        self.entries
+            .range(..=address)
+            .next_back()
+            .map(|(_, (file, number))| (file.as_str(), *number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+
+    fn collect(asm: &Asm, steps: usize) -> (Emulator, Coverage, u32) {
+        let end = asm.pc();
+        let mut emu = asm.emulator().unwrap();
+        let collector = Collector::default();
+        let coverage = collector.coverage.clone();
+        emu.add_hook(Box::new(collector));
+        for _ in 0..steps {
+            emu.step().unwrap();
+        }
+        let coverage = coverage.borrow().clone();
+        (emu, coverage, end)
+    }
+
+    fn program() -> Asm {
+        let mut asm = Asm::new();
+        asm.addi(r1, r0, 2)
+            .label("loop")
+            .addi(r1, r1, -1)
+            .bnq(r1, r0, "loop")
+            .beq(r1, r0, "done")
+            .addi(r2, r0, 1)
+            .label("done")
+            .halt();
+        asm
+    }
+
+    #[test]
+    fn records_instructions_and_branch_outcomes() {
+        let (emu, coverage, end) = collect(&program(), 7);
+
+        assert_eq!(
+            coverage.hits,
+            BTreeMap::from([(0, 1), (4, 2), (8, 2), (12, 1), (20, 1)])
+        );
+        assert_eq!(
+            coverage.branches,
+            BTreeMap::from([(8, (1, 1)), (12, (1, 0))])
+        );
+        assert_eq!(
+            coverage.summary(&emu, end),
+            Summary {
+                instructions: 6,
+                covered: 5,
+                outcomes: 4,
+                outcomes_covered: 3,
+            }
+        );
+
+        let mut listing = Vec::new();
+        coverage.write_annotated(&mut listing, &emu, end).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        assert!(listing.contains("#####  0x00000010  ADDI r2 r0 1\n"));
+        assert!(listing.contains("1  0x0000000c  BEQ r1 r0 4  [taken 1, not taken 0]\n"));
+    }
+
+    #[test]
+    fn merges_saved_runs() {
+        let (_, coverage, _) = collect(&program(), 7);
+        let mut saved = Vec::new();
+        coverage.save(&mut saved).unwrap();
+        let mut merged = Coverage::parse(&String::from_utf8(saved).unwrap()).unwrap();
+        merged.merge(&coverage);
+
+        assert_eq!(merged.hits[&4], 4);
+        assert_eq!(merged.branches[&8], (2, 2));
+        assert!(Coverage::parse("pc 0x10").is_err());
+    }
+
+    #[test]
+    fn writes_lcov_records() {
+        let (emu, coverage, end) = collect(&program(), 7);
+        let lines =
+            LineMap::parse("0 main.s:1\n4 main.s:3\n0xc main.s:5\n0x10 main.s:6\n0x14 main.s:8")
+                .unwrap();
+        let mut lcov = Vec::new();
+        coverage.write_lcov(&mut lcov, &emu, end, &lines).unwrap();
+
+        assert_eq!(
+            String::from_utf8(lcov).unwrap(),
+            "TN:\nSF:main.s\n\
+             BRDA:3,0,0,1\nBRDA:3,0,1,1\nBRDA:5,0,0,1\nBRDA:5,0,1,0\nBRF:4\nBRH:3\n\
+             DA:1,1\nDA:3,2\nDA:5,1\nDA:6,0\nDA:8,1\nLF:5\nLH:4\nend_of_record\n"
+        );
+    }
+}
@@ -1,7 +1,10 @@
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Cpu {
     pub pc: u32,
     pub register: [u32; 32],
+    // bit n is set by every set_reg(n), how hooks learn which registers an
+    // instruction, a trap handler or a plugin wrote
+    pub written: u32,
 }
 
 impl Cpu {
@@ -9,9 +12,10 @@ impl Cpu {
         Cpu {
             pc: 0,
             register: [0; 32],
+            written: 0,
         }
     }
-    pub fn get_reg(&mut self, num: u8) -> u32 {
+    pub fn get_reg(&self, num: u8) -> u32 {
         if num == 0 {
             0
         } else {
@@ -21,6 +25,7 @@ impl Cpu {
     pub fn set_reg(&mut self, num: u8, data: u32) {
         if num != 0 {
             self.register[num as usize] = data;
+            self.written |= 1 << num;
         }
     }
 
@@ -53,11 +58,11 @@ impl Cpu {
     }
 
     pub fn sll(&mut self, rd: u8, rs: u8) {
-        let data = self.get_reg(rd) << self.get_reg(rs) & 0x0000_001F;
+        let data = self.get_reg(rd) << (self.get_reg(rs) & 0x0000_001F);
         self.set_reg(rd, data)
     }
     pub fn srl(&mut self, rd: u8, rs: u8) {
-        let data = self.get_reg(rd) >> self.get_reg(rs) & 0x0000_001F;
+        let data = self.get_reg(rd) >> (self.get_reg(rs) & 0x0000_001F);
         self.set_reg(rd, data);
     }
     pub fn sra(&mut self, rd: u8, rs: u8) {
@@ -75,6 +80,44 @@ impl Cpu {
         self.set_reg(31, data)
     }
 
+    // M extension, division by zero and overflow give the RISC-V results
+    pub fn mul(&mut self, rd: u8, rs: u8) {
+        let data = self.get_reg(rd).wrapping_mul(self.get_reg(rs));
+        self.set_reg(rd, data);
+    }
+    pub fn mulh(&mut self, rd: u8, rs: u8) {
+        let data = (self.get_reg(rd) as i32 as i64) * (self.get_reg(rs) as i32 as i64);
+        self.set_reg(rd, (data >> 32) as u32);
+    }
+    pub fn mulhu(&mut self, rd: u8, rs: u8) {
+        let data = (self.get_reg(rd) as u64) * (self.get_reg(rs) as u64);
+        self.set_reg(rd, (data >> 32) as u32);
+    }
+    // x / 0 = -1, i32::MIN / -1 = i32::MIN
+    pub fn div(&mut self, rd: u8, rs: u8) {
+        let (a, b) = (self.get_reg(rd) as i32, self.get_reg(rs) as i32);
+        let data = if b == 0 { -1 } else { a.wrapping_div(b) };
+        self.set_reg(rd, data as u32);
+    }
+    pub fn divu(&mut self, rd: u8, rs: u8) {
+        let data = self
+            .get_reg(rd)
+            .checked_div(self.get_reg(rs))
+            .unwrap_or(u32::MAX);
+        self.set_reg(rd, data);
+    }
+    // x % 0 = x, i32::MIN % -1 = 0
+    pub fn rem(&mut self, rd: u8, rs: u8) {
+        let (a, b) = (self.get_reg(rd) as i32, self.get_reg(rs) as i32);
+        let data = if b == 0 { a } else { a.wrapping_rem(b) };
+        self.set_reg(rd, data as u32);
+    }
+    pub fn remu(&mut self, rd: u8, rs: u8) {
+        let a = self.get_reg(rd);
+        let data = a.checked_rem(self.get_reg(rs)).unwrap_or(a);
+        self.set_reg(rd, data);
+    }
+
     pub fn slli(&mut self, rd: u8, imm: u8) {
         let data = self.get_reg(rd) << imm;
         self.set_reg(rd, data);
@@ -113,3 +156,172 @@ impl Cpu {
         self.set_reg(rd, data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_regs(rd: u32, rs: u32) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_reg(1, rd);
+        cpu.set_reg(2, rs);
+        cpu
+    }
+
+    #[test]
+    fn r0_is_hardwired_to_zero() {
+        let mut cpu = Cpu::new();
+        cpu.set_reg(0, 0xFFFF_FFFF);
+        assert_eq!(cpu.get_reg(0), 0);
+        cpu.set_reg(1, 5);
+        cpu.mov(0, 1);
+        cpu.addi(0, 1, 1);
+        assert_eq!(cpu.get_reg(0), 0);
+        assert_eq!(cpu.register[0], 0);
+    }
+
+    #[test]
+    fn mov() {
+        let mut cpu = with_regs(1, 0xDEAD_BEEF);
+        cpu.mov(1, 2);
+        assert_eq!(cpu.get_reg(1), 0xDEAD_BEEF);
+        cpu.mov(1, 0);
+        assert_eq!(cpu.get_reg(1), 0);
+    }
+
+    #[test]
+    fn add_and_sub_wrap() {
+        let mut cpu = with_regs(0xFFFF_FFFF, 2);
+        cpu.add(1, 2);
+        assert_eq!(cpu.get_reg(1), 1);
+        cpu.sub(1, 2);
+        assert_eq!(cpu.get_reg(1), 0xFFFF_FFFF);
+        let mut cpu = with_regs(0x8000_0000, 1);
+        cpu.sub(1, 2);
+        assert_eq!(cpu.get_reg(1), 0x7FFF_FFFF);
+    }
+
+    #[test]
+    fn logic() {
+        let mut cpu = with_regs(0b1100, 0b1010);
+        cpu.and(1, 2);
+        assert_eq!(cpu.get_reg(1), 0b1000);
+        let mut cpu = with_regs(0b1100, 0b1010);
+        cpu.or(1, 2);
+        assert_eq!(cpu.get_reg(1), 0b1110);
+        let mut cpu = with_regs(0b1100, 0b1010);
+        cpu.xor(1, 2);
+        assert_eq!(cpu.get_reg(1), 0b0110);
+    }
+
+    #[test]
+    fn shifts_mask_the_shift_amount() {
+        let mut cpu = with_regs(0x8000_0001, 33);
+        cpu.sll(1, 2);
+        assert_eq!(cpu.get_reg(1), 0x0000_0002);
+        let mut cpu = with_regs(0x8000_0001, 33);
+        cpu.srl(1, 2);
+        assert_eq!(cpu.get_reg(1), 0x4000_0000);
+        let mut cpu = with_regs(0x8000_0000, 35);
+        cpu.sra(1, 2);
+        assert_eq!(cpu.get_reg(1), 0xF000_0000);
+        let mut cpu = with_regs(0x8000_0000, 31);
+        cpu.srl(1, 2);
+        assert_eq!(cpu.get_reg(1), 1);
+    }
+
+    #[test]
+    fn multiply_keeps_low_or_high_word() {
+        let mut cpu = with_regs(0xFFFF_FFFF, 0xFFFF_FFFF);
+        cpu.mul(1, 2);
+        assert_eq!(cpu.get_reg(1), 1);
+        let mut cpu = with_regs(0xFFFF_FFFF, 0xFFFF_FFFF);
+        cpu.mulh(1, 2);
+        assert_eq!(cpu.get_reg(1), 0);
+        let mut cpu = with_regs(0xFFFF_FFFF, 0xFFFF_FFFF);
+        cpu.mulhu(1, 2);
+        assert_eq!(cpu.get_reg(1), 0xFFFF_FFFE);
+        let mut cpu = with_regs(0x8000_0000, 2);
+        cpu.mulh(1, 2);
+        assert_eq!(cpu.get_reg(1), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn divide_by_zero_and_overflow() {
+        type Op = fn(&mut Cpu, u8, u8);
+        let cases: [(Op, u32, u32, u32); 10] = [
+            (Cpu::div, (-7i32) as u32, 2, (-3i32) as u32),
+            (Cpu::rem, (-7i32) as u32, 2, (-1i32) as u32),
+            (Cpu::divu, 7, 2, 3),
+            (Cpu::remu, 7, 2, 1),
+            (Cpu::div, 7, 0, u32::MAX),
+            (Cpu::divu, 7, 0, u32::MAX),
+            (Cpu::rem, 7, 0, 7),
+            (Cpu::remu, 7, 0, 7),
+            (Cpu::div, 0x8000_0000, u32::MAX, 0x8000_0000),
+            (Cpu::rem, 0x8000_0000, u32::MAX, 0),
+        ];
+        for (i, (op, rd, rs, expected)) in cases.into_iter().enumerate() {
+            let mut cpu = with_regs(rd, rs);
+            op(&mut cpu, 1, 2);
+            assert_eq!(cpu.get_reg(1), expected, "case {}", i);
+        }
+    }
+
+    #[test]
+    fn shift_immediates() {
+        let mut cpu = with_regs(0x8000_0001, 0);
+        cpu.slli(1, 31);
+        assert_eq!(cpu.get_reg(1), 0x8000_0000);
+        let mut cpu = with_regs(0x8000_0000, 0);
+        cpu.srli(1, 4);
+        assert_eq!(cpu.get_reg(1), 0x0800_0000);
+        let mut cpu = with_regs(0x8000_0000, 0);
+        cpu.srai(1, 4);
+        assert_eq!(cpu.get_reg(1), 0xF800_0000);
+    }
+
+    #[test]
+    fn set_less_than_writes_r31() {
+        let mut cpu = with_regs(0xFFFF_FFFF, 1);
+        cpu.slt(1, 2);
+        assert_eq!(cpu.get_reg(31), 1);
+        assert_eq!(cpu.get_reg(1), 0xFFFF_FFFF);
+        cpu.sltu(1, 2);
+        assert_eq!(cpu.get_reg(31), 0);
+        cpu.slt(2, 1);
+        assert_eq!(cpu.get_reg(31), 0);
+        cpu.sltu(2, 1);
+        assert_eq!(cpu.get_reg(31), 1);
+        cpu.slt(1, 1);
+        assert_eq!(cpu.get_reg(31), 0);
+    }
+
+    #[test]
+    fn immediates_are_sign_extended() {
+        let mut cpu = with_regs(0, 10);
+        cpu.addi(1, 2, -11);
+        assert_eq!(cpu.get_reg(1), 0xFFFF_FFFF);
+        cpu.andi(1, 2, -2);
+        assert_eq!(cpu.get_reg(1), 10);
+        cpu.ori(1, 2, -16);
+        assert_eq!(cpu.get_reg(1), 0xFFFF_FFFA);
+        cpu.xori(1, 2, -1);
+        assert_eq!(cpu.get_reg(1), !10);
+    }
+
+    #[test]
+    fn set_less_than_immediate() {
+        let mut cpu = with_regs(0, 0xFFFF_FFFF);
+        cpu.slti(1, 2, 0);
+        assert_eq!(cpu.get_reg(1), 1);
+        cpu.sltiu(1, 2, 0);
+        assert_eq!(cpu.get_reg(1), 0);
+        // the sign-extended immediate compares as a large unsigned number
+        cpu.set_reg(2, 5);
+        cpu.sltiu(1, 2, -1);
+        assert_eq!(cpu.get_reg(1), 1);
+        cpu.slti(1, 2, -1);
+        assert_eq!(cpu.get_reg(1), 0);
+    }
+}
@@ -0,0 +1,186 @@
+use super::cpu::Cpu;
+use super::isa::{self, Format};
+use super::memory::Memory;
+use super::Type;
+use anyhow::{anyhow, Result};
+use std::fmt::Debug;
+
+// Instruction added by a plugin, see Emulator::add_instruction. Plugins are
+// consulted for words the ISA table does not decode, so they can only use
+// unused opcodes, but may share one by matching more bits of the word.
+pub trait Instruction: Debug {
+    // must not be used by the ISA or another plugin
+    fn mnemonic(&self) -> &'static str;
+    // length and fields of the instruction
+    fn format(&self) -> Format;
+    // (mask, value), the word is this instruction when word & mask == value;
+    // the mask covers the opcode, 16-bit words are in the low half
+    fn pattern(&self) -> (u32, u32);
+    // registers written with Cpu::set_reg are reported to hooks
+    fn execute(&mut self, inst: &Type, cpu: &mut Cpu, memory: &mut Memory) -> Result<()>;
+
+    // assembly syntax, the mnemonic followed by the fields of the format
+    fn disassemble(&self, inst: &Type) -> String {
+        match *inst {
+            Type::RFormat { rd, rs, .. } => format!("{} r{} r{}", self.mnemonic(), rd, rs),
+            Type::I16Format { rd, imm, .. } => format!("{} r{} {}", self.mnemonic(), rd, imm),
+            Type::I32Format { rd, rs, imm, .. } => {
+                format!("{} r{} r{} {}", self.mnemonic(), rd, rs, imm)
+            }
+            Type::JFormat { rd, imm, .. } => format!("{} r{} {}", self.mnemonic(), rd, imm),
+        }
+    }
+}
+
+// rejects instructions that could never be decoded or would be ambiguous
+pub fn check(instructions: &[Box<dyn Instruction>], new: &dyn Instruction) -> Result<()> {
+    let mnemonic = new.mnemonic();
+    let (mask, value) = new.pattern();
+    let opcode = (value & 0x3F) as u8;
+    let taken = instructions.iter().any(|inst| inst.mnemonic() == mnemonic);
+    if isa::by_mnemonic(mnemonic).is_some() || taken {
+        return Err(anyhow!("{} is already an instruction", mnemonic));
+    }
+    if mask & 0x3F != 0x3F {
+        return Err(anyhow!("{} pattern does not fix the opcode", mnemonic));
+    }
+    if value & !mask != 0 {
+        return Err(anyhow!("{} pattern sets bits outside its mask", mnemonic));
+    }
+    if (opcode & 0x20 != 0) != (new.format().length() == 4) {
+        return Err(anyhow!(
+            "{} opcode 0b{:06b} does not match the {} format",
+            mnemonic,
+            opcode,
+            new.format()
+        ));
+    }
+    if let Some(spec) = isa::by_opcode(opcode) {
+        return Err(anyhow!("{} opcode is used by {}", mnemonic, spec.mnemonic));
+    }
+    // two patterns overlap when they agree on the bits both of them fix
+    let overlap = instructions.iter().find(|other| {
+        let (other_mask, other_value) = other.pattern();
+        (value ^ other_value) & mask & other_mask == 0
+    });
+    if let Some(other) = overlap {
+        return Err(anyhow!(
+            "{} pattern overlaps {}",
+            mnemonic,
+            other.mnemonic()
+        ));
+    }
+    Ok(())
+}
+
+pub fn decode(instructions: &[Box<dyn Instruction>], word: u32, length: u32) -> Option<Type> {
+    instructions
+        .iter()
+        .find(|inst| {
+            let (mask, value) = inst.pattern();
+            inst.format().length() == length && word & mask == value
+        })
+        .map(|inst| isa::decode_as(word, inst.format(), inst.mnemonic()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+    use crate::assert_reg;
+    use crate::emulator::Emulator;
+
+    // rd = number of set bits in rs, counting the executions
+    #[derive(Debug, Default)]
+    struct Popcount {
+        executed: u32,
+    }
+
+    impl Instruction for Popcount {
+        fn mnemonic(&self) -> &'static str {
+            "POPCNT"
+        }
+        fn format(&self) -> Format {
+            Format::R
+        }
+        fn pattern(&self) -> (u32, u32) {
+            (0x3F, 0b001011)
+        }
+        fn execute(&mut self, inst: &Type, cpu: &mut Cpu, _: &mut Memory) -> Result<()> {
+            if let Type::RFormat { rd, rs, .. } = *inst {
+                cpu.set_reg(rd, cpu.get_reg(rs).count_ones());
+            }
+            self.executed += 1;
+            Ok(())
+        }
+    }
+
+    // a pattern with the given mnemonic, format and mask over opcode 0b111000
+    #[derive(Debug)]
+    struct Wide(&'static str, Format, u32, u32);
+
+    impl Instruction for Wide {
+        fn mnemonic(&self) -> &'static str {
+            self.0
+        }
+        fn format(&self) -> Format {
+            self.1
+        }
+        fn pattern(&self) -> (u32, u32) {
+            (self.2, self.3)
+        }
+        fn execute(&mut self, _: &Type, _: &mut Cpu, _: &mut Memory) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn plugins_decode_execute_and_disassemble() {
+        let mut asm = Asm::new();
+        asm.li(r2, 0xF0F0);
+        let at = asm.pc();
+        // replaced by POPCNT r1 r2 and the unused opcode 0b001100
+        asm.mov(r0, r0).mov(r0, r0).halt();
+        let mut emu = asm.emulator().unwrap();
+        let popcount = 0b001011 | 1 << 6 | 2 << 11;
+        emu.memory.write_data_16bit(at, popcount).unwrap();
+        emu.memory.write_data_16bit(at + 2, 0b001100).unwrap();
+        assert_eq!(emu.disassemble(at).unwrap().inst.mnemonic(), "UNKNOWN");
+
+        emu.add_instruction(Box::new(Popcount::default())).unwrap();
+        let decoded = emu.disassemble(at).unwrap();
+        assert_eq!(emu.format_inst(&decoded.inst), "POPCNT r1 r2");
+        emu.break_point = at + 2;
+        emu.run().unwrap();
+        assert_reg!(emu, r1, 8);
+        let unused = emu.disassemble(at + 2).unwrap().inst;
+        assert_eq!(unused.mnemonic(), "UNKNOWN");
+        assert!(emu.execute(&unused).is_err());
+        assert!(format!("{:?}", emu.instructions).contains("executed: 1"));
+    }
+
+    #[test]
+    fn clashing_patterns_are_rejected() {
+        let mut emu = Emulator::new(vec![]);
+        let valid = Wide("CUSTOM0", Format::I32, 0xF800_003F, 0x0800_0038);
+        emu.add_instruction(Box::new(valid)).unwrap();
+        let other_rs = Wide("CUSTOM1", Format::I32, 0xF800_003F, 0x1000_0038);
+        emu.add_instruction(Box::new(other_rs)).unwrap();
+
+        let invalid = [
+            Wide("ADD", Format::R, 0x3F, 0b001011),
+            Wide("CUSTOM0", Format::R, 0x3F, 0b001011),
+            Wide("LOOSE", Format::R, 0x1F, 0b001011),
+            Wide("STRAY", Format::R, 0x3F, 0x4000_000B),
+            Wide("SHORT", Format::R, 0x3F, 0b111001),
+            Wide("TAKEN", Format::R, 0x3F, 0b000001),
+            Wide("OVERLAP", Format::I32, 0x003F, 0x0038),
+        ];
+        for inst in invalid {
+            let name = inst.0;
+            assert!(emu.add_instruction(Box::new(inst)).is_err(), "{}", name);
+        }
+        assert_eq!(emu.instructions.len(), 2);
+    }
+}
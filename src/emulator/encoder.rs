@@ -0,0 +1,172 @@
+use super::isa::{self, Format};
+use super::memory::ByteOrder;
+use super::Type;
+use anyhow::{anyhow, Result};
+
+fn opcode(inst: &Type) -> Result<u32> {
+    let mnemonic = inst.mnemonic();
+    let spec =
+        isa::by_mnemonic(mnemonic).ok_or_else(|| anyhow!("{} is not an instruction", mnemonic))?;
+    let format = Format::of(inst);
+    if spec.format != format {
+        return Err(anyhow!(
+            "{} is a {} format instruction, not {}",
+            mnemonic,
+            spec.format,
+            format
+        ));
+    }
+    Ok(spec.opcode as u32)
+}
+
+fn reg(num: u8) -> Result<u32> {
+    if num >= 32 {
+        return Err(anyhow!("r{} is not a register", num));
+    }
+    Ok(num as u32)
+}
+
+fn imm(imm: i32, bits: u32) -> Result<u32> {
+    let min = -(1 << (bits - 1));
+    let max = (1 << (bits - 1)) - 1;
+    if imm < min || imm > max {
+        return Err(anyhow!(
+            "immediate {} is out of range {}..={}",
+            imm,
+            min,
+            max
+        ));
+    }
+    Ok((imm as u32) & ((1 << bits) - 1))
+}
+
+// little-endian machine code, 2 bytes for R and I16, 4 bytes for I32 and J
+pub fn encode(inst: &Type) -> Result<Vec<u8>> {
+    encode_in(inst, ByteOrder::Little)
+}
+
+// machine code in the given byte order, as 16-bit parcels with the opcode in
+// the first one
+pub fn encode_in(inst: &Type, order: ByteOrder) -> Result<Vec<u8>> {
+    let opcode = opcode(inst)?;
+    let (word, length) = match *inst {
+        Type::RFormat { rd, rs, .. } => (opcode | reg(rd)? << 6 | reg(rs)? << 11, 2),
+        Type::I16Format { rd, imm, .. } => {
+            if imm >= 32 {
+                return Err(anyhow!("immediate {} is out of range 0..=31", imm));
+            }
+            (opcode | reg(rd)? << 6 | (imm as u32) << 11, 2)
+        }
+        Type::I32Format { rd, rs, imm: i, .. } => (
+            opcode | reg(rd)? << 6 | reg(rs)? << 11 | imm(i, 16)? << 16,
+            4,
+        ),
+        Type::JFormat { rd, imm: i, .. } => (opcode | reg(rd)? << 6 | imm(i, 21)? << 11, 4),
+    };
+    Ok((0..length / 2)
+        .flat_map(|parcel| order.bytes(word >> (16 * parcel), 2))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Emulator;
+
+    fn decode(bytes: &[u8]) -> Type {
+        match bytes {
+            [a, b] => Emulator::decode_16(u16::from_le_bytes([*a, *b])),
+            [a, b, c, d] => Emulator::decode_32(u32::from_le_bytes([*a, *b, *c, *d])),
+            _ => panic!("unexpected length {}", bytes.len()),
+        }
+    }
+
+    #[test]
+    fn round_trips_every_format() {
+        let insts = [
+            Type::RFormat {
+                mnemonic: "SLTU",
+                rd: 31,
+                rs: 1,
+            },
+            Type::I16Format {
+                mnemonic: "SRAI",
+                rd: 7,
+                imm: 31,
+            },
+            Type::I32Format {
+                mnemonic: "LHU",
+                rd: 3,
+                rs: 4,
+                imm: -0x8000,
+            },
+            Type::JFormat {
+                mnemonic: "JAL",
+                rd: 1,
+                imm: -(1 << 20),
+            },
+            Type::JFormat {
+                mnemonic: "JAL",
+                rd: 0,
+                imm: (1 << 20) - 1,
+            },
+        ];
+        for inst in insts {
+            assert_eq!(decode(&encode(&inst).unwrap()), inst);
+        }
+    }
+
+    #[test]
+    fn big_endian_swaps_bytes_within_parcels() {
+        let inst = Type::I32Format {
+            mnemonic: "ADDI",
+            rd: 1,
+            rs: 2,
+            imm: 0x1234,
+        };
+        let little = encode(&inst).unwrap();
+        let big = encode_in(&inst, ByteOrder::Big).unwrap();
+        assert_eq!(big, [little[1], little[0], little[3], little[2]]);
+    }
+
+    #[test]
+    fn rejects_invalid_operands() {
+        let invalid = [
+            Type::RFormat {
+                mnemonic: "ADD",
+                rd: 32,
+                rs: 0,
+            },
+            Type::RFormat {
+                mnemonic: "ADDI",
+                rd: 1,
+                rs: 0,
+            },
+            Type::I16Format {
+                mnemonic: "SLLI",
+                rd: 1,
+                imm: 32,
+            },
+            Type::I32Format {
+                mnemonic: "ADDI",
+                rd: 1,
+                rs: 0,
+                imm: 0x8000,
+            },
+            Type::JFormat {
+                mnemonic: "JAL",
+                rd: 1,
+                imm: 1 << 20,
+            },
+            Type::I32Format {
+                mnemonic: "UNKNOWN",
+                rd: 0,
+                rs: 0,
+                imm: 0,
+            },
+        ];
+        for inst in invalid {
+            assert!(encode(&inst).is_err(), "{:?}", inst);
+        }
+    }
+}
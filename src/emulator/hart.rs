@@ -0,0 +1,238 @@
+use super::cpu::Cpu;
+use super::Emulator;
+use anyhow::{anyhow, Result};
+
+// A hardware thread. The running hart lives in Emulator::cpu and
+// Emulator::break_point, its entry in Emulator::harts is stale meanwhile.
+#[derive(Debug)]
+pub struct Hart {
+    pub cpu: Cpu,
+    pub break_point: u32,
+    // its last instruction jumped to itself, the scheduler skips it
+    pub halted: bool,
+}
+
+impl Hart {
+    pub fn new(break_point: u32) -> Self {
+        Self {
+            cpu: Cpu::new(),
+            break_point,
+            halted: false,
+        }
+    }
+}
+
+impl Emulator {
+    // a hart starting at address 0 with the running hart's break point,
+    // returns its id
+    pub fn add_hart(&mut self) -> usize {
+        self.harts.push(Hart::new(self.break_point));
+        self.harts.len() - 1
+    }
+
+    // run hart next, the scheduler continues from it
+    pub fn select(&mut self, hart: usize) -> Result<()> {
+        if hart >= self.harts.len() {
+            return Err(anyhow!("there is no hart {}", hart));
+        }
+        // either pc may have been changed in the debugger
+        self.harts[self.hart].halted = false;
+        self.harts[hart].halted = false;
+        self.switch(hart);
+        Ok(())
+    }
+
+    pub fn hart_cpu(&self, hart: usize) -> &Cpu {
+        if hart == self.hart {
+            &self.cpu
+        } else {
+            &self.harts[hart].cpu
+        }
+    }
+
+    pub fn hart_break_point(&self, hart: usize) -> u32 {
+        if hart == self.hart {
+            self.break_point
+        } else {
+            self.harts[hart].break_point
+        }
+    }
+
+    // every hart jumped to itself, pc is where the running hart was
+    pub(super) fn halted(&self, pc: u32) -> bool {
+        if self.harts.len() == 1 {
+            self.cpu.pc == pc
+        } else {
+            self.harts.iter().all(|hart| hart.halted)
+        }
+    }
+
+    pub(super) fn switch(&mut self, hart: usize) {
+        if hart != self.hart {
+            for index in [self.hart, hart] {
+                let entry = &mut self.harts[index];
+                std::mem::swap(&mut entry.cpu, &mut self.cpu);
+                std::mem::swap(&mut entry.break_point, &mut self.break_point);
+            }
+            self.hart = hart;
+            self.memory.hart = hart as u32;
+        }
+        self.slice = 0;
+    }
+
+    // round robin after each step, pc is where the running hart was; a hart
+    // keeps running while it waits at its break point
+    pub(super) fn schedule(&mut self, pc: u32) {
+        let halted = self.cpu.pc == pc;
+        self.harts[self.hart].halted = halted;
+        self.slice += 1;
+        let quantum = match self.quantum.get(self.hart).or(self.quantum.last()) {
+            Some(&quantum) => quantum.max(1),
+            None => 1,
+        };
+        if (self.slice < quantum && !halted)
+            || self.cpu.pc == self.break_point
+            || self.exit_code.is_some()
+        {
+            return;
+        }
+        let count = self.harts.len();
+        let next = (1..count)
+            .map(|i| (self.hart + i) % count)
+            .find(|&hart| !self.harts[hart].halted);
+        match next {
+            Some(hart) => self.switch(hart),
+            None => self.slice = 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+    use crate::emulator::history::History;
+    use crate::emulator::memory::{HART_ID, LOCK};
+
+    // device registers are reached through r0 with a negative offset
+    fn device(address: u32) -> i32 {
+        address as i32
+    }
+
+    #[test]
+    fn harts_share_memory_under_the_lock() {
+        let mut asm = Asm::new();
+        asm.lbu(r1, r0, device(HART_ID)).li(r2, 5);
+        asm.label("loop")
+            .lbu(r3, r0, device(LOCK))
+            .bnq(r3, r0, "loop")
+            .lw(r4, r0, 0x200)
+            .addi(r4, r4, 1)
+            .sw(r4, r0, 0x200)
+            .sb(r0, r0, device(LOCK))
+            .addi(r2, r2, -1)
+            .bnq(r2, r0, "loop")
+            .addi(r5, r1, 1)
+            .sb(r5, r1, 0x210)
+            .halt();
+        let mut emu = asm.emulator().unwrap();
+        emu.trace = false;
+        emu.add_hart();
+        emu.add_hart();
+        emu.quantum = vec![2, 3];
+        emu.run_until_halt(10_000).unwrap();
+
+        assert_eq!(emu.memory.peek_data(0x200, 4).unwrap(), 15);
+        assert_eq!(emu.memory.memory_array[0x210..0x213], [1, 2, 3]);
+        for hart in 0..3 {
+            assert_eq!(emu.hart_cpu(hart).get_reg(r1), hart as u32);
+            assert!(emu.harts[hart].halted);
+        }
+        assert_eq!(emu.memory.lock, 0);
+    }
+
+    #[test]
+    fn harts_take_turns_by_quantum() {
+        let mut asm = Asm::new();
+        for _ in 0..8 {
+            asm.addi(r1, r1, 1);
+        }
+        let mut emu = asm.halt().emulator().unwrap();
+        emu.trace = false;
+        emu.add_hart();
+        emu.add_hart();
+        emu.quantum = vec![3, 1];
+        let mut order = Vec::new();
+        for _ in 0..10 {
+            order.push(emu.hart);
+            emu.step().unwrap();
+        }
+        assert_eq!(order, [0, 0, 0, 1, 2, 0, 0, 0, 1, 2]);
+        assert_eq!(emu.hart_cpu(0).get_reg(r1), 6);
+        assert_eq!(emu.hart_cpu(1).get_reg(r1), 2);
+    }
+
+    #[test]
+    fn each_hart_has_its_break_point() {
+        let mut asm = Asm::new();
+        for _ in 0..8 {
+            asm.addi(r1, r1, 1);
+        }
+        let mut emu = asm.halt().emulator().unwrap();
+        emu.trace = false;
+        emu.add_hart();
+        emu.select(1).unwrap();
+        emu.break_point = 12;
+        emu.select(0).unwrap();
+        emu.run().unwrap();
+
+        assert_eq!(emu.hart, 1);
+        assert_eq!(emu.cpu.pc, 12);
+        assert_eq!(emu.hart_cpu(0).pc, 12);
+        assert_eq!(emu.hart_break_point(0), 36);
+        assert!(emu.select(2).is_err());
+    }
+
+    #[test]
+    fn reverse_step_returns_to_the_hart() {
+        let mut asm = Asm::new();
+        asm.lbu(r1, r0, device(HART_ID)).addi(r2, r1, 10).halt();
+        let mut emu = asm.emulator().unwrap();
+        emu.trace = false;
+        emu.history = History::new(16);
+        emu.add_hart();
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.hart, 1);
+        assert_eq!(emu.hart_cpu(0).get_reg(r2), 10);
+        assert!(emu.reverse_step());
+        assert_eq!(emu.hart, 0);
+        assert_eq!(emu.cpu.get_reg(r2), 0);
+        assert_eq!(emu.hart_cpu(1).get_reg(r1), 1);
+    }
+
+    #[test]
+    fn reverse_step_releases_the_lock_again() {
+        let mut asm = Asm::new();
+        asm.label("acquire")
+            .lbu(r1, r0, device(LOCK))
+            .bnq(r1, r0, "acquire")
+            .sb(r0, r0, device(LOCK))
+            .halt();
+        let mut emu = asm.emulator().unwrap();
+        emu.trace = false;
+        emu.history = History::new(16);
+        emu.step().unwrap();
+        assert_eq!(emu.memory.lock, 1);
+        assert!(emu.reverse_step());
+        assert_eq!(emu.memory.lock, 0);
+        emu.run_until_halt(10).unwrap();
+        assert_eq!((emu.cpu.get_reg(r1), emu.memory.lock), (0, 0));
+
+        // undoing the release takes the lock again
+        assert!(emu.reverse_step());
+        assert!(emu.reverse_step());
+        assert_eq!(emu.memory.lock, 1);
+    }
+}
@@ -0,0 +1,51 @@
+use std::any::Any;
+use std::collections::VecDeque;
+
+// undo information for one retired instruction
+#[derive(Debug, Default)]
+pub struct Record {
+    // hart that ran it and the scheduler state before it, see hart.rs
+    pub hart: usize,
+    pub slice: u64,
+    pub halted: bool,
+    pub pc: u32,
+    pub cycles: u64,
+    // TimingModel::state before it
+    pub timing: Option<Box<dyn Any>>,
+    // Syscalls::brk before it
+    pub brk: u32,
+    // copied whole, cheaper than allocating a list of the changed ones
+    pub register: [u32; 32],
+    pub memory: Vec<(u32, u8)>,
+    // Emulator::exit_code before it, a semihosting or syscall EXIT sets it
+    pub exit_code: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct History {
+    records: VecDeque<Record>,
+    pub budget: usize,
+}
+
+impl History {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            records: VecDeque::new(),
+            budget,
+        }
+    }
+
+    pub fn push(&mut self, record: Record) {
+        if self.budget == 0 {
+            return;
+        }
+        while self.records.len() >= self.budget {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    pub fn pop(&mut self) -> Option<Record> {
+        self.records.pop_back()
+    }
+}
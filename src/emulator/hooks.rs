@@ -0,0 +1,185 @@
+use super::icache::Decoded;
+use super::{Emulator, Type};
+use anyhow::Error;
+use std::fmt::Debug;
+
+// Observer of executed instructions, see Emulator::add_hook. Every callback
+// gets the emulator read-only; register and memory callbacks run after the
+// instruction, in the order the accesses happened.
+#[allow(unused_variables)]
+pub trait Hook: Debug {
+    fn fetch(&mut self, emu: &Emulator, pc: u32, decoded: &Decoded) {}
+    fn pre_execute(&mut self, emu: &Emulator, pc: u32, inst: &Type) {}
+    fn post_execute(&mut self, emu: &Emulator, pc: u32, inst: &Type) {}
+    // also called when the value does not change, never for r0
+    fn reg_write(&mut self, emu: &Emulator, num: u8, old: u32, new: u32) {}
+    fn mem_read(&mut self, emu: &Emulator, address: u32, size: u32, data: u32) {}
+    fn mem_write(&mut self, emu: &Emulator, address: u32, size: u32, data: u32) {}
+    // conditional branches only, jumps are seen by post_execute
+    fn branch_taken(&mut self, emu: &Emulator, pc: u32, target: u32) {}
+    // the instruction failed; an illegal instruction or a misaligned access
+    // under Alignment::Trap stops execution there without retiring it and
+    // without post_execute, after other errors execution goes on
+    fn trap(&mut self, emu: &Emulator, pc: u32, error: &Error) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+    use crate::emulator::cpu::Cpu;
+    use crate::emulator::custom::Instruction;
+    use crate::emulator::isa::Format;
+    use crate::emulator::memory::Memory;
+    use crate::emulator::semihost::{self, Semihost};
+    use anyhow::Result;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        events: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Hook for Recorder {
+        fn fetch(&mut self, _: &Emulator, pc: u32, decoded: &Decoded) {
+            self.events
+                .borrow_mut()
+                .push(format!("fetch {} {}", pc, decoded.inst));
+        }
+        fn post_execute(&mut self, emu: &Emulator, pc: u32, _: &Type) {
+            self.events
+                .borrow_mut()
+                .push(format!("post {} {}", pc, emu.cpu.pc));
+        }
+        fn reg_write(&mut self, _: &Emulator, num: u8, old: u32, new: u32) {
+            self.events
+                .borrow_mut()
+                .push(format!("reg r{} {} {}", num, old, new));
+        }
+        fn mem_read(&mut self, _: &Emulator, address: u32, size: u32, data: u32) {
+            self.events
+                .borrow_mut()
+                .push(format!("read {} {} {}", address, size, data));
+        }
+        fn mem_write(&mut self, _: &Emulator, address: u32, size: u32, data: u32) {
+            self.events
+                .borrow_mut()
+                .push(format!("write {} {} {}", address, size, data));
+        }
+        fn branch_taken(&mut self, _: &Emulator, pc: u32, target: u32) {
+            self.events
+                .borrow_mut()
+                .push(format!("branch {} {}", pc, target));
+        }
+        fn trap(&mut self, _: &Emulator, pc: u32, _: &Error) {
+            self.events.borrow_mut().push(format!("trap {}", pc));
+        }
+    }
+
+    #[test]
+    fn reports_every_event() {
+        let mut emu = Asm::new()
+            .addi(r1, r0, 5)
+            .sh(r1, r0, 0x100)
+            .lw(r2, r0, 0x100)
+            .beq(r1, r2, "skip")
+            .addi(r3, r0, 1)
+            .label("skip")
+            .lw(r3, r0, 0x7FFF)
+            .emulator()
+            .unwrap();
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        emu.add_hook(Box::new(recorder));
+        for _ in 0..5 {
+            emu.step().unwrap();
+        }
+
+        assert_eq!(
+            *events.borrow(),
+            [
+                "fetch 0 ADDI r1 r0 5",
+                "reg r1 0 5",
+                "post 0 4",
+                "fetch 4 SH r1 r0 256",
+                "write 256 2 5",
+                "post 4 8",
+                "fetch 8 LW r2 r0 256",
+                "read 256 4 5",
+                "reg r2 0 5",
+                "post 8 12",
+                "fetch 12 BEQ r1 r2 4",
+                "branch 12 20",
+                "post 12 20",
+                "fetch 20 LW r3 r0 32767",
+                "trap 20",
+                "post 20 24",
+            ]
+        );
+    }
+
+    // COPY rd rs on the unused opcode 0b001011
+    #[derive(Debug)]
+    struct Copy;
+
+    impl Instruction for Copy {
+        fn mnemonic(&self) -> &'static str {
+            "COPY"
+        }
+        fn format(&self) -> Format {
+            Format::R
+        }
+        fn pattern(&self) -> (u32, u32) {
+            (0x3F, 0b001011)
+        }
+        fn execute(&mut self, inst: &Type, cpu: &mut Cpu, _: &mut Memory) -> Result<()> {
+            if let Type::RFormat { rd, rs, .. } = *inst {
+                cpu.set_reg(rd, cpu.get_reg(rs));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reports_what_traps_and_plugins_write() {
+        let mut asm = Asm::new();
+        asm.addi(r2, r0, 7);
+        let at = asm.pc();
+        // replaced by COPY r3 r2
+        asm.mov(r0, r0)
+            .addi(r1, r0, semihost::WRITE_CONSOLE as i32)
+            .trap(semihost::TRAP)
+            .addi(r1, r0, semihost::EXIT as i32)
+            .trap(semihost::TRAP);
+        let mut emu = asm.emulator().unwrap();
+        emu.memory
+            .write_data_16bit(at, 0b001011 | 3 << 6 | 2 << 11)
+            .unwrap();
+        emu.add_instruction(Box::new(Copy)).unwrap();
+        emu.semihost = Some(Semihost::new(std::env::temp_dir()));
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        emu.add_hook(Box::new(recorder));
+        emu.run().unwrap();
+
+        assert_eq!(emu.exit_code, Some(7));
+        let writes = events
+            .borrow()
+            .iter()
+            .filter(|event| event.starts_with("reg"))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            writes,
+            [
+                "reg r2 0 7",
+                "reg r3 0 7",
+                "reg r1 0 1",
+                "reg r1 1 7",
+                "reg r1 7 9",
+            ]
+        );
+    }
+}
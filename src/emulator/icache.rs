@@ -0,0 +1,103 @@
+use super::isa::{self, Spec};
+use super::Type;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Decoded {
+    pub inst: Type,
+    pub word: u32,
+    pub length: u32,
+    // row of the ISA table, None for unknown and plugin instructions
+    pub spec: Option<&'static Spec>,
+}
+
+impl Decoded {
+    pub fn new(inst: Type, word: u32, length: u32) -> Self {
+        Self {
+            inst,
+            word,
+            length,
+            spec: isa::by_mnemonic(inst.mnemonic()),
+        }
+    }
+}
+
+// predecoded instructions indexed by pc
+#[derive(Debug, Default)]
+pub struct ICache {
+    entries: Vec<Option<Decoded>>,
+}
+
+impl ICache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, pc: u32) -> Option<&Decoded> {
+        self.entries
+            .get(pc as usize)
+            .and_then(|entry| entry.as_ref())
+    }
+
+    pub fn insert(&mut self, pc: u32, decoded: Decoded) {
+        let index = pc as usize;
+        if index >= self.entries.len() {
+            self.entries.resize(index + 1, None);
+        }
+        self.entries[index] = Some(decoded);
+    }
+
+    // drop every instruction that overlaps a written byte
+    pub fn invalidate(&mut self, address: u32) {
+        for pc in address.saturating_sub(3)..=address {
+            if let Some(entry) = self.entries.get_mut(pc as usize) {
+                if entry.is_some_and(|decoded| pc + decoded.length > address) {
+                    *entry = None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(length: u32) -> Decoded {
+        let inst = Type::RFormat {
+            mnemonic: "ADD",
+            rd: 1,
+            rs: 2,
+        };
+        Decoded::new(inst, 0x0841, length)
+    }
+
+    #[test]
+    fn get_returns_inserted_instructions() {
+        let mut icache = ICache::new();
+        assert!(icache.get(8).is_none());
+        icache.insert(8, decoded(2));
+        assert_eq!(icache.get(8).map(|decoded| decoded.length), Some(2));
+        assert!(icache.get(6).is_none());
+        assert!(icache.get(u32::MAX).is_none());
+    }
+
+    #[test]
+    fn writes_invalidate_overlapping_instructions() {
+        let mut icache = ICache::new();
+        icache.insert(0, decoded(4));
+        icache.insert(4, decoded(2));
+        icache.insert(6, decoded(4));
+        // the upper half of the 32-bit instruction at 0
+        icache.invalidate(3);
+        assert!(icache.get(0).is_none());
+        assert!(icache.get(4).is_some());
+        // a byte after the 16-bit instruction at 4 leaves it alone
+        icache.invalidate(6);
+        assert!(icache.get(4).is_some());
+        assert!(icache.get(6).is_none());
+        icache.insert(6, decoded(4));
+        icache.invalidate(9);
+        assert!(icache.get(6).is_none());
+        icache.invalidate(1000);
+    }
+}
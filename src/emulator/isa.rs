@@ -0,0 +1,522 @@
+// KTC32 instruction set, one line per instruction. The decoder, encoder,
+// disassembler and docs/isa.md are all derived from ISA below.
+use super::Type;
+use anyhow::{anyhow, Error};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    R,
+    I16,
+    I32,
+    J,
+}
+
+impl Format {
+    // bit 5 of the opcode selects a 32-bit instruction
+    pub const fn length(self) -> u32 {
+        match self {
+            Format::R | Format::I16 => 2,
+            Format::I32 | Format::J => 4,
+        }
+    }
+
+    pub fn of(inst: &Type) -> Format {
+        match inst {
+            Type::RFormat { .. } => Format::R,
+            Type::I16Format { .. } => Format::I16,
+            Type::I32Format { .. } => Format::I32,
+            Type::JFormat { .. } => Format::J,
+        }
+    }
+
+    // bit fields from msb to lsb
+    pub fn layout(self) -> &'static str {
+        match self {
+            Format::R => "rs[15:11] rd[10:6] opcode[5:0]",
+            Format::I16 => "imm[15:11] rd[10:6] opcode[5:0]",
+            Format::I32 => "imm[31:16] rs[15:11] rd[10:6] opcode[5:0]",
+            Format::J => "imm[31:11] rd[10:6] opcode[5:0]",
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Format::R => "R",
+            Format::I16 => "I16",
+            Format::I32 => "I32",
+            Format::J => "J",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Rd,
+    Rs,
+    Imm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Move,
+    Alu,
+    Shift,
+    Compare,
+    Branch,
+    Jump,
+    Load,
+    Store,
+    Upper,
+    System,
+}
+
+// instruction groups a profile can enable, Base is always implemented
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Extension {
+    Base,
+    // multiply and divide
+    M,
+}
+
+impl Extension {
+    pub const ALL: &'static [Extension] = &[Extension::Base, Extension::M];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Extension::Base => "base",
+            Extension::M => "m",
+        }
+    }
+
+    const fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+// the extensions an emulator implements, written "base+ext+..."
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Profile {
+    extensions: u32,
+}
+
+impl Profile {
+    pub const BASE: Profile = Profile {
+        extensions: Extension::Base.bit(),
+    };
+
+    pub const fn with(self, extension: Extension) -> Profile {
+        Profile {
+            extensions: self.extensions | extension.bit(),
+        }
+    }
+
+    pub const fn has(self, extension: Extension) -> bool {
+        self.extensions & extension.bit() != 0
+    }
+
+    pub fn implements(self, spec: &Spec) -> bool {
+        self.has(spec.extension)
+    }
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile::BASE
+    }
+}
+
+impl FromStr for Profile {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Self, Error> {
+        text.split('+').try_fold(Profile::BASE, |profile, name| {
+            Extension::ALL
+                .iter()
+                .find(|extension| extension.name() == name.trim().to_ascii_lowercase())
+                .map(|&extension| profile.with(extension))
+                .ok_or_else(|| anyhow!("unknown ISA extension '{}'", name))
+        })
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = Extension::ALL
+            .iter()
+            .filter(|&&extension| self.has(extension))
+            .map(|extension| extension.name())
+            .collect::<Vec<_>>();
+        write!(f, "{}", names.join("+"))
+    }
+}
+
+#[derive(Debug)]
+pub struct Spec {
+    pub mnemonic: &'static str,
+    pub format: Format,
+    pub opcode: u8,
+    pub operands: &'static [Operand],
+    pub category: Category,
+    pub semantics: &'static str,
+    pub extension: Extension,
+}
+
+const fn spec(
+    mnemonic: &'static str,
+    format: Format,
+    opcode: u8,
+    operands: &'static [Operand],
+    category: Category,
+    semantics: &'static str,
+) -> Spec {
+    Spec {
+        mnemonic,
+        format,
+        opcode,
+        operands,
+        category,
+        semantics,
+        extension: Extension::Base,
+    }
+}
+
+impl Spec {
+    // moves the row from the base ISA into an extension
+    const fn extension(self, extension: Extension) -> Spec {
+        Spec { extension, ..self }
+    }
+}
+
+use Category::*;
+use Extension::M;
+use Format::{I16, I32, J, R};
+use Operand::{Imm, Rd, Rs};
+
+const RD_RS: &[Operand] = &[Rd, Rs];
+const RD_IMM: &[Operand] = &[Rd, Imm];
+const RD_RS_IMM: &[Operand] = &[Rd, Rs, Imm];
+const IMM: &[Operand] = &[Imm];
+
+// immediates are sign-extended except for I16, branches and JAL are relative
+// to the address of the next instruction
+#[rustfmt::skip]
+pub const ISA: &[Spec] = &[
+    spec("MOV",   R,   0b000000, RD_RS,     Move,    "rd = rs"),
+    spec("ADD",   R,   0b000001, RD_RS,     Alu,     "rd = rd + rs"),
+    spec("SUB",   R,   0b000010, RD_RS,     Alu,     "rd = rd - rs"),
+    spec("AND",   R,   0b000011, RD_RS,     Alu,     "rd = rd & rs"),
+    spec("OR",    R,   0b000100, RD_RS,     Alu,     "rd = rd | rs"),
+    spec("XOR",   R,   0b000101, RD_RS,     Alu,     "rd = rd ^ rs"),
+    spec("SLL",   R,   0b000110, RD_RS,     Shift,   "rd = rd << rs[4:0]"),
+    spec("SRL",   R,   0b000111, RD_RS,     Shift,   "rd = rd >> rs[4:0] (logical)"),
+    spec("SRA",   R,   0b001000, RD_RS,     Shift,   "rd = rd >> rs[4:0] (arithmetic)"),
+    spec("SLT",   R,   0b001001, RD_RS,     Compare, "r31 = rd < rs (signed)"),
+    spec("SLTU",  R,   0b001010, RD_RS,     Compare, "r31 = rd < rs (unsigned)"),
+    spec("MUL",   R,   0b011000, RD_RS,     Alu,     "rd = (rd * rs)[31:0]").extension(M),
+    spec("MULH",  R,   0b011001, RD_RS,     Alu,     "rd = (rd * rs)[63:32] (signed)").extension(M),
+    spec("MULHU", R,   0b011010, RD_RS,     Alu,     "rd = (rd * rs)[63:32] (unsigned)").extension(M),
+    spec("DIV",   R,   0b011011, RD_RS,     Alu,     "rd = rd / rs (signed), -1 if rs == 0").extension(M),
+    spec("DIVU",  R,   0b011100, RD_RS,     Alu,     "rd = rd / rs (unsigned), 0xffffffff if rs == 0").extension(M),
+    spec("REM",   R,   0b011101, RD_RS,     Alu,     "rd = rd % rs (signed), rd if rs == 0").extension(M),
+    spec("REMU",  R,   0b011110, RD_RS,     Alu,     "rd = rd % rs (unsigned), rd if rs == 0").extension(M),
+    spec("SLLI",  I16, 0b010000, RD_IMM,    Shift,   "rd = rd << imm"),
+    spec("SRLI",  I16, 0b010001, RD_IMM,    Shift,   "rd = rd >> imm (logical)"),
+    spec("SRAI",  I16, 0b010010, RD_IMM,    Shift,   "rd = rd >> imm (arithmetic)"),
+    spec("TRAP",  I16, 0b010011, IMM,       System,  "call the host service imm"),
+    spec("ADDI",  I32, 0b100000, RD_RS_IMM, Alu,     "rd = rs + imm"),
+    spec("ANDI",  I32, 0b100001, RD_RS_IMM, Alu,     "rd = rs & imm"),
+    spec("ORI",   I32, 0b100010, RD_RS_IMM, Alu,     "rd = rs | imm"),
+    spec("XORI",  I32, 0b100011, RD_RS_IMM, Alu,     "rd = rs ^ imm"),
+    spec("SLTI",  I32, 0b100100, RD_RS_IMM, Compare, "rd = rs < imm (signed)"),
+    spec("SLTIU", I32, 0b100101, RD_RS_IMM, Compare, "rd = rs < imm (unsigned)"),
+    spec("BEQ",   I32, 0b100110, RD_RS_IMM, Branch,  "if rd == rs: pc += imm"),
+    spec("BNQ",   I32, 0b100111, RD_RS_IMM, Branch,  "if rd != rs: pc += imm"),
+    spec("BLT",   I32, 0b101000, RD_RS_IMM, Branch,  "if rd < rs (signed): pc += imm"),
+    spec("BGE",   I32, 0b101001, RD_RS_IMM, Branch,  "if rd >= rs (signed): pc += imm"),
+    spec("BLTU",  I32, 0b101010, RD_RS_IMM, Branch,  "if rd < rs (unsigned): pc += imm"),
+    spec("BGEU",  I32, 0b101011, RD_RS_IMM, Branch,  "if rd >= rs (unsigned): pc += imm"),
+    spec("JALR",  I32, 0b101100, RD_RS_IMM, Jump,    "rd = pc, pc = rs + imm"),
+    spec("LB",    I32, 0b101101, RD_RS_IMM, Load,    "rd = sext(mem8[rs + imm])"),
+    spec("LH",    I32, 0b101110, RD_RS_IMM, Load,    "rd = sext(mem16[rs + imm])"),
+    spec("LBU",   I32, 0b101111, RD_RS_IMM, Load,    "rd = mem8[rs + imm]"),
+    spec("LHU",   I32, 0b110000, RD_RS_IMM, Load,    "rd = mem16[rs + imm]"),
+    spec("LW",    I32, 0b110001, RD_RS_IMM, Load,    "rd = mem32[rs + imm]"),
+    spec("LUI",   I32, 0b110010, RD_IMM,    Upper,   "rd = imm << 16"),
+    spec("SB",    I32, 0b110011, RD_RS_IMM, Store,   "mem8[rs + imm] = rd"),
+    spec("SH",    I32, 0b110100, RD_RS_IMM, Store,   "mem16[rs + imm] = rd"),
+    spec("SW",    I32, 0b110101, RD_RS_IMM, Store,   "mem32[rs + imm] = rd"),
+    spec("JAL",   J,   0b111111, RD_IMM,    Jump,    "rd = pc, pc += imm"),
+];
+
+// index into ISA by opcode, NONE for unused opcodes
+const NONE: u8 = u8::MAX;
+const OPCODES: [u8; 64] = {
+    let mut table = [NONE; 64];
+    let mut i = 0;
+    while i < ISA.len() {
+        table[ISA[i].opcode as usize] = i as u8;
+        i += 1;
+    }
+    table
+};
+
+pub fn by_opcode(opcode: u8) -> Option<&'static Spec> {
+    match OPCODES.get(opcode as usize) {
+        Some(&index) if index != NONE => Some(&ISA[index as usize]),
+        _ => None,
+    }
+}
+
+pub fn by_mnemonic(mnemonic: &str) -> Option<&'static Spec> {
+    ISA.iter().find(|spec| spec.mnemonic == mnemonic)
+}
+
+// opcode of a mnemonic in ISA, evaluated at compile time for op
+const fn opcode(mnemonic: &str) -> u8 {
+    let mut i = 0;
+    while i < ISA.len() {
+        let (a, b) = (ISA[i].mnemonic.as_bytes(), mnemonic.as_bytes());
+        let mut same = a.len() == b.len();
+        let mut j = 0;
+        while same && j < a.len() {
+            same = a[j] == b[j];
+            j += 1;
+        }
+        if same {
+            return ISA[i].opcode;
+        }
+        i += 1;
+    }
+    panic!("mnemonic is not in ISA")
+}
+
+// opcodes by mnemonic, so that execute can dispatch on Spec::opcode
+// instead of comparing mnemonics
+pub mod op {
+    use super::opcode;
+
+    pub const MOV: u8 = opcode("MOV");
+    pub const ADD: u8 = opcode("ADD");
+    pub const SUB: u8 = opcode("SUB");
+    pub const AND: u8 = opcode("AND");
+    pub const OR: u8 = opcode("OR");
+    pub const XOR: u8 = opcode("XOR");
+    pub const SLL: u8 = opcode("SLL");
+    pub const SRL: u8 = opcode("SRL");
+    pub const SRA: u8 = opcode("SRA");
+    pub const SLT: u8 = opcode("SLT");
+    pub const SLTU: u8 = opcode("SLTU");
+    pub const MUL: u8 = opcode("MUL");
+    pub const MULH: u8 = opcode("MULH");
+    pub const MULHU: u8 = opcode("MULHU");
+    pub const DIV: u8 = opcode("DIV");
+    pub const DIVU: u8 = opcode("DIVU");
+    pub const REM: u8 = opcode("REM");
+    pub const REMU: u8 = opcode("REMU");
+    pub const SLLI: u8 = opcode("SLLI");
+    pub const SRLI: u8 = opcode("SRLI");
+    pub const SRAI: u8 = opcode("SRAI");
+    pub const TRAP: u8 = opcode("TRAP");
+    pub const ADDI: u8 = opcode("ADDI");
+    pub const ANDI: u8 = opcode("ANDI");
+    pub const ORI: u8 = opcode("ORI");
+    pub const XORI: u8 = opcode("XORI");
+    pub const SLTI: u8 = opcode("SLTI");
+    pub const SLTIU: u8 = opcode("SLTIU");
+    pub const BEQ: u8 = opcode("BEQ");
+    pub const BNQ: u8 = opcode("BNQ");
+    pub const BLT: u8 = opcode("BLT");
+    pub const BGE: u8 = opcode("BGE");
+    pub const BLTU: u8 = opcode("BLTU");
+    pub const BGEU: u8 = opcode("BGEU");
+    pub const JALR: u8 = opcode("JALR");
+    pub const LB: u8 = opcode("LB");
+    pub const LH: u8 = opcode("LH");
+    pub const LBU: u8 = opcode("LBU");
+    pub const LHU: u8 = opcode("LHU");
+    pub const LW: u8 = opcode("LW");
+    pub const LUI: u8 = opcode("LUI");
+    pub const SB: u8 = opcode("SB");
+    pub const SH: u8 = opcode("SH");
+    pub const SW: u8 = opcode("SW");
+    pub const JAL: u8 = opcode("JAL");
+}
+
+// fields of word read as a format instruction, 16-bit words in the low half
+pub fn decode_as(word: u32, format: Format, mnemonic: &'static str) -> Type {
+    let rd = ((word & 0x0000_07C0) >> 6) as u8;
+    let rs = ((word & 0x0000_F800) >> 11) as u8;
+    match format {
+        R => Type::RFormat { mnemonic, rd, rs },
+        I16 => Type::I16Format {
+            mnemonic,
+            rd,
+            imm: rs,
+        },
+        I32 => Type::I32Format {
+            mnemonic,
+            rd,
+            rs,
+            imm: (word as i32) >> 16,
+        },
+        J => Type::JFormat {
+            mnemonic,
+            rd,
+            imm: (word as i32) >> 11,
+        },
+    }
+}
+
+pub fn decode_32(word: u32) -> Type {
+    match by_opcode((word & 0x3F) as u8) {
+        Some(spec) if spec.format.length() == 4 => decode_as(word, spec.format, spec.mnemonic),
+        _ => Type::I32Format {
+            mnemonic: "UNKNOWN",
+            rd: 0,
+            rs: 0,
+            imm: 0,
+        },
+    }
+}
+
+pub fn decode_16(word: u16) -> Type {
+    match by_opcode((word & 0x3F) as u8) {
+        Some(spec) if spec.format.length() == 2 => {
+            decode_as(word as u32, spec.format, spec.mnemonic)
+        }
+        _ => Type::RFormat {
+            mnemonic: "UNKNOWN",
+            rd: 0,
+            rs: 0,
+        },
+    }
+}
+
+// assembly syntax, e.g. "ADDI r1 r2 -4"
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let spec = match by_mnemonic(self.mnemonic()) {
+            Some(spec) => spec,
+            None => return write!(f, "{}", self.mnemonic()),
+        };
+        let (rd, rs, imm) = match *self {
+            Type::RFormat { rd, rs, .. } => (rd, rs, 0),
+            Type::I16Format { rd, imm, .. } => (rd, 0, imm as i32),
+            Type::I32Format { rd, rs, imm, .. } => (rd, rs, imm),
+            Type::JFormat { rd, imm, .. } => (rd, 0, imm),
+        };
+        write!(f, "{}", spec.mnemonic)?;
+        for operand in spec.operands {
+            match operand {
+                Rd => write!(f, " r{}", rd)?,
+                Rs => write!(f, " r{}", rs)?,
+                Imm => write!(f, " {}", imm)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// markdown ISA reference, written to docs/isa.md by examples/isa_reference.rs
+pub fn reference() -> String {
+    let mut doc = String::from("# KTC32 instruction set\n\n");
+    doc.push_str("Generated from `src/emulator/isa.rs`, do not edit by hand.\n\n");
+    doc.push_str("## Formats\n\n| Format | Length | Fields |\n| --- | --- | --- |\n");
+    for format in [R, I16, I32, J] {
+        doc.push_str(&format!(
+            "| {} | {} bytes | `{}` |\n",
+            format,
+            format.length(),
+            format.layout()
+        ));
+    }
+    doc.push_str(
+        "\nImmediates of I32 and J instructions are sign-extended. \
+         Branches and JAL are relative to the address of the next instruction.\n",
+    );
+    doc.push_str(
+        "\nInstructions outside the base set execute only when their extension is \
+         part of the emulator's ISA profile, otherwise they and unused opcodes \
+         raise an illegal instruction error.\n",
+    );
+    doc.push_str("\n## Instructions\n\n");
+    doc.push_str("| Mnemonic | Format | Opcode | Operands | Category | Extension | Semantics |\n");
+    doc.push_str("| --- | --- | --- | --- | --- | --- | --- |\n");
+    for spec in ISA {
+        let operands = spec
+            .operands
+            .iter()
+            .map(|operand| match operand {
+                Rd => "rd",
+                Rs => "rs",
+                Imm => "imm",
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        doc.push_str(&format!(
+            "| {} | {} | `0b{:06b}` | {} | {:?} | {} | `{}` |\n",
+            spec.mnemonic,
+            spec.format,
+            spec.opcode,
+            operands,
+            spec.category,
+            spec.extension.name(),
+            spec.semantics.replace('|', "\\|")
+        ));
+    }
+    doc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_is_consistent() {
+        for (i, spec) in ISA.iter().enumerate() {
+            assert_eq!(by_opcode(spec.opcode).unwrap().mnemonic, spec.mnemonic);
+            assert_eq!(by_mnemonic(spec.mnemonic).unwrap().opcode, spec.opcode);
+            assert_eq!(
+                spec.opcode & 0b100000 != 0,
+                spec.format.length() == 4,
+                "{} opcode does not match its length",
+                spec.mnemonic
+            );
+            assert!(ISA[..i].iter().all(|other| other.opcode != spec.opcode));
+        }
+    }
+
+    #[test]
+    fn disassembles_operands() {
+        assert_eq!(decode_32(0xFFFC_1060).to_string(), "ADDI r1 r2 -4");
+        assert_eq!(decode_32(0x1234_0072).to_string(), "LUI r1 4660");
+        assert_eq!(decode_16(0xF850).to_string(), "SLLI r1 31");
+        assert_eq!(decode_16(0x003F).to_string(), "UNKNOWN");
+    }
+
+    #[test]
+    fn profiles_parse_and_print() {
+        let profile = "base".parse::<Profile>().unwrap();
+        assert_eq!(profile, Profile::BASE);
+        assert_eq!(profile.to_string(), "base");
+        assert!(!profile.implements(by_mnemonic("MUL").unwrap()));
+        let profile = "base+M".parse::<Profile>().unwrap();
+        assert_eq!(profile, Profile::BASE.with(M));
+        assert_eq!(profile.to_string(), "base+m");
+        assert!(ISA.iter().all(|spec| profile.implements(spec)));
+        assert!("base+x".parse::<Profile>().is_err());
+    }
+
+    #[test]
+    fn reference_is_up_to_date() {
+        assert!(
+            include_str!("../../docs/isa.md") == reference(),
+            "docs/isa.md is stale, run `cargo run --example isa_reference > docs/isa.md`"
+        );
+    }
+}
@@ -1,72 +1,489 @@
-use anyhow::{anyhow, Result};
+use super::replay::{Inputs, Source};
+use std::collections::VecDeque;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryErrorKind {
+    // some byte of the access lies past the end of memory
+    OutOfRange,
+    // refused by Alignment::Trap
+    Misaligned,
+}
+
+// how the bytes of halfwords and words are laid out in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ByteOrder {
+    #[default]
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    // bit position of byte index within a value of size bytes
+    pub fn shift(self, index: u32, size: u32) -> u32 {
+        match self {
+            ByteOrder::Little => 8 * index,
+            ByteOrder::Big => 8 * (size - 1 - index),
+        }
+    }
+
+    // the size low bytes of data in memory order
+    pub fn bytes(self, data: u32, size: u32) -> Vec<u8> {
+        (0..size)
+            .map(|index| (data >> self.shift(index, size)) as u8)
+            .collect()
+    }
+}
+
+// failed access of size bytes at addr
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryError {
+    pub addr: u32,
+    pub size: u32,
+    pub kind: MemoryErrorKind,
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            MemoryErrorKind::OutOfRange => write!(
+                f,
+                "{}-byte access at 0x{:08x} is out of range of memory",
+                self.size, self.addr
+            ),
+            MemoryErrorKind::Misaligned => write!(
+                f,
+                "misaligned {}-byte access at 0x{:08x}",
+                self.size, self.addr
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MemoryError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub address: u32,
+    pub size: u32,
+    pub data: u32,
+    pub write: bool,
+}
+
 #[derive(Debug)]
 pub struct Memory {
     pub memory_array: Vec<u8>,
+    // previous contents of every byte written while recording, and of LOCK
+    pub journal: Option<Vec<(u32, u8)>>,
+    // bytes holding predecoded instructions and the ones written since
+    pub code: Vec<bool>,
+    pub code_writes: Vec<u32>,
+    // bytes written to UART_TX
+    pub uart: Vec<u8>,
+    // bytes waiting to be read from UART_RX
+    pub uart_input: VecDeque<u8>,
+    // loads and stores while recording, see Emulator::add_hook
+    pub accesses: Option<Vec<Access>>,
+    pub layout: Layout,
+    pub byte_order: ByteOrder,
+    // hart running the current instruction, read from HART_ID
+    pub hart: u32,
+    // LOCK register, 1 while held
+    pub lock: u8,
+    // inputs from outside the emulator, recorded or replayed
+    pub inputs: Inputs,
+}
+
+// where programs using the syscall layer keep their heap and stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    // initial program break
+    pub heap_start: u32,
+    // the break can not move past this, the stack lives above it
+    pub heap_end: u32,
+    // initial stack pointer, the stack grows down
+    pub stack_top: u32,
+}
+
+impl Layout {
+    // heap from the end of the program to the stack at the top of memory
+    pub fn new(program_size: u32, stack_size: u32) -> Self {
+        Self {
+            heap_start: (program_size + 3) & !3,
+            heap_end: MEMORY_SIZE.saturating_sub(stack_size),
+            stack_top: MEMORY_SIZE,
+        }
+    }
 }
 
+pub const DEFAULT_STACK_SIZE: u32 = 256;
+
 pub const MEMORY_SIZE: u32 = 768; // 768Byte
 
+// UART registers, reachable with SB rd r0 -256 and LBU rd r0 -252
+pub const UART_TX: u32 = 0xFFFF_FF00;
+// next input byte, 0 when there is none
+pub const UART_RX: u32 = 0xFFFF_FF04;
+// 1 while input is waiting
+pub const UART_STATUS: u32 = 0xFFFF_FF08;
+// id of the hart that reads it
+pub const HART_ID: u32 = 0xFFFF_FF0C;
+// test-and-set: a read returns the lock and sets it to 1, a write stores the byte
+pub const LOCK: u32 = 0xFFFF_FF10;
+
 impl Memory {
     pub fn new(memory_array: Vec<u8>) -> Self {
-        Self { memory_array }
+        Self {
+            layout: Layout::new(memory_array.len() as u32, DEFAULT_STACK_SIZE),
+            memory_array,
+            journal: None,
+            code: Vec::new(),
+            code_writes: Vec::new(),
+            uart: Vec::new(),
+            uart_input: VecDeque::new(),
+            accesses: None,
+            byte_order: ByteOrder::Little,
+            hart: 0,
+            lock: 0,
+            inputs: Inputs::new(),
+        }
+    }
+
+    fn log(&mut self, address: u32, size: u32, data: u32, write: bool) {
+        if let Some(accesses) = self.accesses.as_mut() {
+            accesses.push(Access {
+                address,
+                size,
+                data,
+                write,
+            });
+        }
     }
 
     pub fn init(&mut self) {
-        for _i in 0..((MEMORY_SIZE as usize) - self.memory_array.len()) {
-            self.memory_array.push(0);
+        if self.memory_array.len() < MEMORY_SIZE as usize {
+            self.memory_array.resize(MEMORY_SIZE as usize, 0);
         }
     }
 
-    pub fn read_data_8bit(&self, address: u32) -> Result<u8> {
-        if address > MEMORY_SIZE {
-            return Err(anyhow!("0x{:08x} is out of range of memory", address));
+    // index of the first byte when all size bytes lie inside memory
+    fn range(&self, address: u32, size: u32) -> Result<usize, MemoryError> {
+        match address.checked_add(size) {
+            Some(end) if end as usize <= self.memory_array.len() => Ok(address as usize),
+            _ => Err(MemoryError {
+                addr: address,
+                size,
+                kind: MemoryErrorKind::OutOfRange,
+            }),
         }
-        Ok(self.memory_array[address as usize])
     }
 
-    pub fn read_data_16bit(&self, address: u32) -> Result<u16> {
-        if address + 1 > MEMORY_SIZE {
-            return Err(anyhow!("0x{:08x} is out of range of memory", address + 1));
+    fn load(&mut self, address: u32, size: u32) -> Result<u32, MemoryError> {
+        let data = self.peek_data(address, size)?;
+        self.log(address, size, data, false);
+        Ok(data)
+    }
+
+    fn store_sized(&mut self, address: u32, size: u32, data: u32) -> Result<(), MemoryError> {
+        self.range(address, size)?;
+        for i in 0..size {
+            self.store(address + i, (data >> self.byte_order.shift(i, size)) as u8);
         }
-        Ok((self.memory_array[(address + 1) as usize] as u16) << 8
-            | self.memory_array[address as usize] as u16)
+        self.log(address, size, data, true);
+        Ok(())
     }
 
-    pub fn read_data(&self, address: u32) -> Result<u32> {
-        if address + 3 > MEMORY_SIZE {
-            return Err(anyhow!("0x{:08x} is out of range of memory", address + 3));
+    pub fn read_data_8bit(&mut self, address: u32) -> Result<u8, MemoryError> {
+        let data = match address {
+            UART_RX => {
+                let input = &mut self.uart_input;
+                let live = || input.pop_front().unwrap_or(0);
+                self.inputs.byte(Source::Device(UART_RX), live)
+            }
+            UART_STATUS => {
+                let waiting = !self.uart_input.is_empty();
+                self.inputs
+                    .byte(Source::Device(UART_STATUS), || u8::from(waiting))
+            }
+            HART_ID => self.hart as u8,
+            LOCK => self.set_lock(1),
+            _ => return self.load(address, 1).map(|data| data as u8),
+        };
+        self.log(address, 1, data as u32, false);
+        Ok(data)
+    }
+
+    pub fn read_data_16bit(&mut self, address: u32) -> Result<u16, MemoryError> {
+        self.load(address, 2).map(|data| data as u16)
+    }
+
+    pub fn read_data(&mut self, address: u32) -> Result<u32, MemoryError> {
+        self.load(address, 4)
+    }
+
+    // a byte without logging or device side effects, for debuggers
+    pub fn peek(&self, address: u32) -> Result<u8, MemoryError> {
+        self.range(address, 1).map(|index| self.memory_array[index])
+    }
+
+    // like peek, for 1, 2 or 4 bytes in the configured byte order
+    pub fn peek_data(&self, address: u32, size: u32) -> Result<u32, MemoryError> {
+        let start = self.range(address, size)?;
+        Ok(self.memory_array[start..start + size as usize]
+            .iter()
+            .zip(0..)
+            .fold(0, |data, (&byte, i)| {
+                data | (byte as u32) << self.byte_order.shift(i, size)
+            }))
+    }
+
+    pub fn mark_code(&mut self, address: u32, length: u32) {
+        let end = address.saturating_add(length) as usize;
+        if end > self.code.len() {
+            self.code.resize(end, false);
+        }
+        for flag in &mut self.code[address as usize..end] {
+            *flag = true;
         }
-        Ok((self.memory_array[(address + 3) as usize] as u32) << 24
-            | (self.memory_array[(address + 2) as usize] as u32) << 16
-            | (self.memory_array[(address + 1) as usize] as u32) << 8
-            | self.memory_array[address as usize] as u32)
     }
 
-    pub fn write_data_8bit(&mut self, address: u32, data: u8) -> Result<()> {
-        if address > MEMORY_SIZE {
-            return Err(anyhow!("0x{:08x} is out of range of memory", address));
+    // unchecked byte write, every store goes through here; the address has
+    // to be inside memory
+    pub(crate) fn store(&mut self, address: u32, data: u8) {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push((address, self.memory_array[address as usize]));
+        }
+        if self.code.get(address as usize) == Some(&true) {
+            self.code_writes.push(address);
+        }
+        self.memory_array[address as usize] = data;
+    }
+
+    // LOCK is journaled like a byte of memory, returns its previous value
+    fn set_lock(&mut self, data: u8) -> u8 {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.push((LOCK, self.lock));
+        }
+        std::mem::replace(&mut self.lock, data)
+    }
+
+    // put back a byte from the journal
+    pub(crate) fn restore(&mut self, address: u32, data: u8) {
+        match address {
+            LOCK => self.lock = data,
+            _ => self.store(address, data),
         }
-        self.memory_array[(address) as usize] = data;
-        Ok(())
     }
 
-    pub fn write_data_16bit(&mut self, address: u32, data: u16) -> Result<()> {
-        if address + 1 > MEMORY_SIZE {
-            return Err(anyhow!("0x{:08x} is out of range of memory", address + 1));
+    pub fn write_data_8bit(&mut self, address: u32, data: u8) -> Result<(), MemoryError> {
+        match address {
+            UART_TX => self.uart.push(data),
+            LOCK => {
+                self.set_lock(data);
+            }
+            _ => return self.store_sized(address, 1, data as u32),
         }
-        self.memory_array[(address) as usize] = (data & 0x00FF) as u8;
-        self.memory_array[(address + 1) as usize] = (data & 0xFF00) as u8;
+        self.log(address, 1, data as u32, true);
         Ok(())
     }
 
-    pub fn write_data(&mut self, address: u32, data: u32) -> Result<()> {
-        if address + 3 > MEMORY_SIZE {
-            return Err(anyhow!("0x{:08x} is out of range of memory", address + 3));
+    pub fn write_data_16bit(&mut self, address: u32, data: u16) -> Result<(), MemoryError> {
+        self.store_sized(address, 2, data as u32)
+    }
+
+    pub fn write_data(&mut self, address: u32, data: u32) -> Result<(), MemoryError> {
+        self.store_sized(address, 4, data)
+    }
+
+    // buffer of a host call, it has to lie inside memory
+    pub fn read_bytes(&mut self, address: u32, length: u32) -> Result<Vec<u8>, MemoryError> {
+        self.check_buffer(address, length)?;
+        (address..address + length)
+            .map(|address| self.read_data_8bit(address))
+            .collect()
+    }
+
+    pub fn write_bytes(&mut self, address: u32, data: &[u8]) -> Result<(), MemoryError> {
+        self.check_buffer(address, data.len() as u32)?;
+        for (i, byte) in data.iter().enumerate() {
+            self.write_data_8bit(address + i as u32, *byte)?;
         }
-        self.memory_array[(address) as usize] = (data & 0x000000FF) as u8;
-        self.memory_array[(address + 1) as usize] = (data & 0x0000FF00) as u8;
-        self.memory_array[(address + 2) as usize] = (data & 0x00FF0000) as u8;
-        self.memory_array[(address + 3) as usize] = (data & 0xFF000000) as u8;
         Ok(())
     }
+
+    pub fn check_buffer(&self, address: u32, length: u32) -> Result<(), MemoryError> {
+        self.range(address, length).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> Memory {
+        let mut memory = Memory::new(vec![]);
+        memory.init();
+        memory
+    }
+
+    #[test]
+    fn little_endian_round_trip() {
+        let mut memory = memory();
+        memory.write_data(0, 0x1234_5678).unwrap();
+        assert_eq!(memory.memory_array[0..4], [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(memory.read_data(0).unwrap(), 0x1234_5678);
+        assert_eq!(memory.read_data_16bit(2).unwrap(), 0x1234);
+        assert_eq!(memory.read_data_8bit(1).unwrap(), 0x56);
+
+        memory.write_data_16bit(8, 0xBEEF).unwrap();
+        assert_eq!(memory.memory_array[8..10], [0xEF, 0xBE]);
+        assert_eq!(memory.read_data_16bit(8).unwrap(), 0xBEEF);
+
+        memory.write_data_8bit(12, 0xA5).unwrap();
+        assert_eq!(memory.read_data_8bit(12).unwrap(), 0xA5);
+    }
+
+    #[test]
+    fn big_endian_round_trip() {
+        let mut memory = memory();
+        memory.byte_order = ByteOrder::Big;
+        memory.write_data(0, 0x1234_5678).unwrap();
+        assert_eq!(memory.memory_array[0..4], [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(memory.read_data(0).unwrap(), 0x1234_5678);
+        assert_eq!(memory.read_data_16bit(2).unwrap(), 0x5678);
+        assert_eq!(memory.peek_data(0, 2).unwrap(), 0x1234);
+
+        memory.write_data_16bit(8, 0xBEEF).unwrap();
+        assert_eq!(memory.memory_array[8..10], [0xBE, 0xEF]);
+        assert_eq!(memory.read_data_16bit(8).unwrap(), 0xBEEF);
+    }
+
+    #[test]
+    fn journal_records_previous_bytes() {
+        let mut memory = memory();
+        memory.write_data_16bit(4, 0x1122).unwrap();
+        memory.journal = Some(Vec::new());
+        memory.write_data_16bit(4, 0x3344).unwrap();
+        assert_eq!(memory.journal.unwrap(), vec![(4, 0x22), (5, 0x11)]);
+    }
+
+    #[test]
+    fn writes_to_code_are_reported() {
+        let mut memory = memory();
+        memory.mark_code(0, 4);
+        memory.write_data_8bit(8, 1).unwrap();
+        assert!(memory.code_writes.is_empty());
+        memory.write_data_16bit(3, 1).unwrap();
+        assert_eq!(memory.code_writes, vec![3]);
+    }
+
+    #[test]
+    fn uart_collects_byte_stores() {
+        let mut memory = memory();
+        memory.write_data_8bit(UART_TX, b'o').unwrap();
+        memory.write_data_8bit(UART_TX, b'k').unwrap();
+        assert_eq!(memory.uart, b"ok");
+        assert!(memory.memory_array.iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn lock_is_test_and_set() {
+        let mut memory = memory();
+        memory.hart = 1;
+        assert_eq!(memory.read_data_8bit(HART_ID).unwrap(), 1);
+        assert_eq!(memory.read_data_8bit(LOCK).unwrap(), 0);
+        assert_eq!(memory.read_data_8bit(LOCK).unwrap(), 1);
+        memory.write_data_8bit(LOCK, 0).unwrap();
+        assert_eq!(memory.read_data_8bit(LOCK).unwrap(), 0);
+    }
+
+    #[test]
+    fn uart_input_is_consumed_by_byte_loads() {
+        let mut memory = memory();
+        assert_eq!(memory.read_data_8bit(UART_STATUS).unwrap(), 0);
+        memory.uart_input.extend(b"a");
+        assert_eq!(memory.read_data_8bit(UART_STATUS).unwrap(), 1);
+        assert_eq!(memory.read_data_8bit(UART_RX).unwrap(), b'a');
+        assert_eq!(memory.read_data_8bit(UART_STATUS).unwrap(), 0);
+        assert_eq!(memory.read_data_8bit(UART_RX).unwrap(), 0);
+    }
+
+    #[test]
+    fn out_of_range_accesses_fail() {
+        let mut memory = memory();
+        assert_eq!(
+            memory.read_data(MEMORY_SIZE - 3),
+            Err(MemoryError {
+                addr: MEMORY_SIZE - 3,
+                size: 4,
+                kind: MemoryErrorKind::OutOfRange,
+            })
+        );
+        assert_eq!(
+            memory
+                .write_data_16bit(u32::MAX, 0)
+                .unwrap_err()
+                .to_string(),
+            "2-byte access at 0xffffffff is out of range of memory"
+        );
+        assert!(memory.peek(MEMORY_SIZE).is_err());
+        assert!(memory.read_bytes(MEMORY_SIZE - 1, 2).is_err());
+        assert!(memory.write_bytes(u32::MAX, &[0]).is_err());
+    }
+
+    #[test]
+    fn every_access_near_the_boundaries_is_checked() {
+        let addresses = (0..8)
+            .chain(MEMORY_SIZE - 8..MEMORY_SIZE + 8)
+            .chain(UART_TX - 4..LOCK + 4)
+            .chain(u32::MAX - 8..=u32::MAX);
+        for address in addresses {
+            for size in [1, 2, 4] {
+                let mut memory = memory();
+                memory.uart_input.extend(b"x");
+                let fits = address as u64 + size as u64 <= MEMORY_SIZE as u64;
+                let expected = |device: bool| {
+                    if fits || size == 1 && device {
+                        Ok(())
+                    } else {
+                        Err(MemoryError {
+                            addr: address,
+                            size,
+                            kind: MemoryErrorKind::OutOfRange,
+                        })
+                    }
+                };
+
+                let read = match size {
+                    1 => memory.read_data_8bit(address).map(drop),
+                    2 => memory.read_data_16bit(address).map(drop),
+                    _ => memory.read_data(address).map(drop),
+                };
+                let devices = [UART_RX, UART_STATUS, HART_ID, LOCK];
+                assert_eq!(read, expected(devices.contains(&address)));
+                let write = match size {
+                    1 => memory.write_data_8bit(address, 0xFF),
+                    2 => memory.write_data_16bit(address, 0xFFFF),
+                    _ => memory.write_data(address, u32::MAX),
+                };
+                assert_eq!(write, expected(address == UART_TX || address == LOCK));
+                let written = memory
+                    .memory_array
+                    .iter()
+                    .filter(|&&byte| byte != 0)
+                    .count();
+                assert_eq!(written, if fits { size as usize } else { 0 });
+                assert_eq!(memory.memory_array.len(), MEMORY_SIZE as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn accesses_stop_at_a_short_memory() {
+        let mut memory = Memory::new(vec![1, 2, 3]);
+        assert_eq!(memory.read_data_16bit(1), Ok(0x0302));
+        assert!(memory.read_data_8bit(3).is_err());
+        assert!(memory.read_data(0).is_err());
+        assert!(memory.write_data_8bit(MEMORY_SIZE - 1, 0).is_err());
+    }
 }
@@ -0,0 +1,202 @@
+use super::symbols::Symbols;
+use super::Type;
+use std::collections::HashMap;
+use std::io::Write;
+
+const MAX_CALL_DEPTH: usize = 1024;
+const HOTSPOT_ROWS: usize = 20;
+
+// Counts retired instructions. Reverse execution does not subtract them, a
+// profile covers every instruction executed, including ones later undone.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    pub total: u64,
+    pub pc_counts: HashMap<u32, u64>,
+    pub opcode_counts: HashMap<&'static str, u64>,
+    // (taken, not taken) per branch instruction
+    pub branches: HashMap<u32, (u64, u64)>,
+    pub loads: u64,
+    pub stores: u64,
+    // entry addresses of the functions currently on the call stack
+    stack: Vec<u32>,
+    stacks: HashMap<Vec<u32>, u64>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, pc: u32, inst: &Type, next_pc: u32, fallthrough_pc: u32) {
+        self.total += 1;
+        *self.pc_counts.entry(pc).or_insert(0) += 1;
+        *self.opcode_counts.entry(inst.mnemonic()).or_insert(0) += 1;
+        match self.stacks.get_mut(&self.stack) {
+            Some(count) => *count += 1,
+            None => {
+                self.stacks.insert(self.stack.clone(), 1);
+            }
+        }
+
+        match inst {
+            Type::I32Format { mnemonic, rd, .. } => match *mnemonic {
+                "BEQ" | "BNQ" | "BLT" | "BGE" | "BLTU" | "BGEU" => {
+                    let counts = self.branches.entry(pc).or_insert((0, 0));
+                    if next_pc != fallthrough_pc {
+                        counts.0 += 1;
+                    } else {
+                        counts.1 += 1;
+                    }
+                }
+                "LB" | "LH" | "LBU" | "LHU" | "LW" => self.loads += 1,
+                "SB" | "SH" | "SW" => self.stores += 1,
+                "JALR" if *rd == 0 => {
+                    self.stack.pop();
+                }
+                "JALR" => self.call(next_pc),
+                _ => {}
+            },
+            Type::JFormat { rd, .. } if *rd != 0 => self.call(next_pc),
+            _ => {}
+        }
+    }
+
+    fn call(&mut self, target: u32) {
+        if self.stack.len() < MAX_CALL_DEPTH {
+            self.stack.push(target);
+        }
+    }
+
+    pub fn report(&self, symbols: &Symbols) {
+        println!("profile : {} instructions", self.total);
+        if self.total == 0 {
+            return;
+        }
+
+        println!();
+        println!(" {:>10} {:>7}  {:<10}  symbol", "count", "%", "pc");
+        for (pc, count) in self.hotspots().into_iter().take(HOTSPOT_ROWS) {
+            println!(
+                " {:>10} {:>6.2}%  0x{:08x}  {}",
+                count,
+                self.percent(count),
+                pc,
+                symbols.format(pc)
+            );
+        }
+
+        println!();
+        println!(" {:>10} {:>7}  mnemonic", "count", "%");
+        let mut opcodes = self.opcode_counts.iter().collect::<Vec<_>>();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (mnemonic, count) in opcodes {
+            println!(
+                " {:>10} {:>6.2}%  {}",
+                count,
+                self.percent(*count),
+                mnemonic
+            );
+        }
+
+        if !self.branches.is_empty() {
+            println!();
+            println!(" {:>10} {:>10} {:>7}  pc", "taken", "not taken", "taken%");
+            let mut branches = self.branches.iter().collect::<Vec<_>>();
+            branches.sort_by_key(|(pc, _)| **pc);
+            for (pc, (taken, not_taken)) in branches {
+                println!(
+                    " {:>10} {:>10} {:>6.2}%  0x{:08x} {}",
+                    taken,
+                    not_taken,
+                    *taken as f64 * 100.0 / (taken + not_taken) as f64,
+                    pc,
+                    symbols.format(*pc)
+                );
+            }
+        }
+
+        println!();
+        println!(" loads : {}  stores : {}", self.loads, self.stores);
+    }
+
+    // (pc, count) by count, most executed first, ties by address
+    pub fn hotspots(&self) -> Vec<(u32, u64)> {
+        let mut pcs = self
+            .pc_counts
+            .iter()
+            .map(|(pc, count)| (*pc, *count))
+            .collect::<Vec<_>>();
+        pcs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pcs
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.total as f64
+    }
+
+    // one "root;caller;callee count" line per distinct call stack
+    pub fn write_folded<W: Write>(&self, out: &mut W, symbols: &Symbols) -> std::io::Result<()> {
+        let mut lines = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let mut frames = vec![String::from("root")];
+                frames.extend(stack.iter().map(|address| symbols.format(*address)));
+                (frames.join(";"), *count)
+            })
+            .collect::<Vec<_>>();
+        lines.sort();
+        for (frames, count) in lines {
+            writeln!(out, "{} {}", frames, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+
+    // main calls f twice, f loops twice per call
+    fn profile() -> Profiler {
+        let mut asm = Asm::new();
+        asm.jal(r31, "f").jal(r31, "f");
+        let end = asm.pc();
+        asm.halt()
+            .label("f")
+            .addi(r1, r0, 2)
+            .label("loop")
+            .addi(r1, r1, -1)
+            .bnq(r1, r0, "loop")
+            .jalr(r0, r31, 0);
+        let mut emu = asm.emulator().unwrap();
+        emu.trace = false;
+        emu.profiler = Some(Profiler::new());
+        emu.break_point = end;
+        emu.run().unwrap();
+        emu.profiler.unwrap()
+    }
+
+    #[test]
+    fn counts_hotspots_and_branches() {
+        let profiler = profile();
+        // JAL, JAL, then per call ADDI, 2 x (ADDI, BNQ), JALR
+        assert_eq!(profiler.total, 14);
+        let expected = [(16, 4), (20, 4), (12, 2), (24, 2), (0, 1), (4, 1)];
+        assert_eq!(profiler.hotspots(), expected);
+        assert_eq!(profiler.branches[&20], (2, 2));
+        assert_eq!(profiler.opcode_counts["ADDI"], 6);
+        assert_eq!((profiler.loads, profiler.stores), (0, 0));
+    }
+
+    #[test]
+    fn folds_call_stacks() {
+        let profiler = profile();
+        let symbols = Symbols::parse("0 main\n0c f").unwrap();
+        let mut out = Vec::new();
+        profiler.write_folded(&mut out, &symbols).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "root 2\nroot;f 12\n");
+    }
+}
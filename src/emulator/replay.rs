@@ -0,0 +1,340 @@
+use anyhow::{anyhow, Context, Result};
+use std::fmt;
+use std::io::Write;
+
+// where an external input came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    // a load from a device register such as UART_RX
+    Device(u32),
+    // bytes read from the host's stdin
+    Console,
+    // semihost::TIME
+    Time,
+    // semihost::CLOCK
+    Clock,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Device(address) => write!(f, "device 0x{:08x}", address),
+            Source::Console => write!(f, "console"),
+            Source::Time => write!(f, "time"),
+            Source::Clock => write!(f, "clock"),
+        }
+    }
+}
+
+// one input, read by the instruction retired as number instret + 1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub instret: u64,
+    pub source: Source,
+    // None when the host call failed
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mode {
+    // inputs come from the host and are not kept
+    #[default]
+    Off,
+    Record,
+    // inputs come from events, and from the host again once they run out
+    Replay,
+}
+
+// Log of the inputs a program reads from outside the emulator. Replaying it
+// makes a session repeatable, the log is checked against the reads the
+// program makes, so a run that takes another path fails instead of
+// silently reading the wrong input.
+#[derive(Debug, Default)]
+pub struct Inputs {
+    pub mode: Mode,
+    pub events: Vec<Event>,
+    // next event to replay
+    next: usize,
+    // instructions retired before the current one, set by Emulator::step
+    pub instret: u64,
+    // why replay stopped, returned by check
+    diverged: Option<String>,
+}
+
+impl Inputs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record() -> Self {
+        Self {
+            mode: Mode::Record,
+            ..Self::default()
+        }
+    }
+
+    pub fn replay(events: Vec<Event>) -> Self {
+        Self {
+            mode: Mode::Replay,
+            events,
+            ..Self::default()
+        }
+    }
+
+    // the input read from the host by live, or the logged one when replaying
+    pub fn input(
+        &mut self,
+        source: Source,
+        live: impl FnOnce() -> Option<Vec<u8>>,
+    ) -> Option<Vec<u8>> {
+        match self.mode {
+            Mode::Off => live(),
+            Mode::Record => {
+                let data = live();
+                self.events.push(Event {
+                    instret: self.instret,
+                    source,
+                    data: data.clone(),
+                });
+                data
+            }
+            Mode::Replay => match self.events.get(self.next) {
+                Some(event) if event.instret == self.instret && event.source == source => {
+                    self.next += 1;
+                    event.data.clone()
+                }
+                Some(event) => {
+                    let expected = format!("{} at instruction {}", event.source, event.instret);
+                    self.stop(format!(
+                        "the program read {}, the log has {}",
+                        source, expected
+                    ));
+                    live()
+                }
+                None => {
+                    self.mode = Mode::Off;
+                    live()
+                }
+            },
+        }
+    }
+
+    pub fn byte(&mut self, source: Source, live: impl FnOnce() -> u8) -> u8 {
+        let data = self.input(source, || Some(vec![live()]));
+        data.and_then(|data| data.first().copied()).unwrap_or(0)
+    }
+
+    pub fn word(&mut self, source: Source, live: impl FnOnce() -> Option<u32>) -> Option<u32> {
+        let data = self.input(source, || live().map(|word| word.to_le_bytes().to_vec()));
+        data.and_then(|data| data.try_into().ok())
+            .map(u32::from_le_bytes)
+    }
+
+    // called after each instruction; fails once the program and the log
+    // disagree, replay is off from then on
+    pub fn check(&mut self, instret: u64) -> Result<()> {
+        if self.mode == Mode::Replay {
+            if let Some(event) = self.events.get(self.next).filter(|e| e.instret < instret) {
+                let missed = format!("{} at instruction {}", event.source, event.instret);
+                self.stop(format!("the program did not read {}", missed));
+            }
+        }
+        match self.diverged.take() {
+            Some(reason) => Err(anyhow!("replay diverged: {}", reason)),
+            None => Ok(()),
+        }
+    }
+
+    fn stop(&mut self, reason: String) {
+        self.mode = Mode::Off;
+        self.diverged = Some(reason);
+    }
+
+    // forget the inputs of instructions after the first instret ones, which
+    // read them again when they are executed again
+    pub fn rewind(&mut self, instret: u64) {
+        let kept = self.events.partition_point(|event| event.instret < instret);
+        match self.mode {
+            Mode::Record => self.events.truncate(kept),
+            Mode::Replay => self.next = self.next.min(kept),
+            Mode::Off => {}
+        }
+    }
+
+    // reads the "<source> <instret> <data>" lines written by save, data is
+    // hex bytes, "-" when empty or "error"
+    pub fn parse(text: &str) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let instret = |text: &str| {
+                text.parse::<u64>()
+                    .with_context(|| format!("invalid input count at line {}", i + 1))
+            };
+            let data = |text: &str| match text {
+                "error" => Ok(None),
+                "-" => Ok(Some(Vec::new())),
+                _ => hex::decode(text)
+                    .map(Some)
+                    .with_context(|| format!("invalid input data at line {}", i + 1)),
+            };
+            let (source, count, bytes) = match fields.as_slice() {
+                [] => continue,
+                ["device", count, address, bytes] => {
+                    let address = u32::from_str_radix(address.trim_start_matches("0x"), 16)
+                        .with_context(|| format!("invalid device address at line {}", i + 1))?;
+                    (Source::Device(address), count, bytes)
+                }
+                ["console", count, bytes] => (Source::Console, count, bytes),
+                ["time", count, bytes] => (Source::Time, count, bytes),
+                ["clock", count, bytes] => (Source::Clock, count, bytes),
+                _ => return Err(anyhow!("invalid input record at line {}", i + 1)),
+            };
+            let event = Event {
+                instret: instret(count)?,
+                source,
+                data: data(bytes)?,
+            };
+            if events
+                .last()
+                .is_some_and(|last: &Event| last.instret > event.instret)
+            {
+                return Err(anyhow!("input out of order at line {}", i + 1));
+            }
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    pub fn save(&self, w: &mut impl Write) -> std::io::Result<()> {
+        for event in &self.events {
+            let data = match &event.data {
+                None => "error".to_string(),
+                Some(data) if data.is_empty() => "-".to_string(),
+                Some(data) => hex::encode(data),
+            };
+            match event.source {
+                Source::Device(address) => {
+                    writeln!(w, "device {} 0x{:08x} {}", event.instret, address, data)?
+                }
+                source => writeln!(w, "{} {} {}", source, event.instret, data)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+    use crate::emulator::history::History;
+    use crate::emulator::memory::{UART_RX, UART_STATUS};
+    use crate::emulator::Emulator;
+
+    // sums UART input bytes into r2 until a 0 byte, waiting while none is there
+    fn emulator() -> Emulator {
+        let mut asm = Asm::new();
+        asm.label("wait")
+            .lbu(r1, r0, UART_STATUS as i32)
+            .beq(r1, r0, "wait")
+            .lbu(r1, r0, UART_RX as i32)
+            .add(r2, r1)
+            .bnq(r1, r0, "wait")
+            .halt();
+        let mut emu = asm.emulator().unwrap();
+        emu.trace = false;
+        emu
+    }
+
+    // runs with input arriving a byte at a time every 7 steps
+    fn session(emu: &mut Emulator) {
+        for step in 0..200 {
+            if step % 7 == 0 {
+                emu.memory.uart_input.push_back([3, 40, 0][step / 7 % 3]);
+            }
+            emu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn replay_repeats_the_recorded_session() {
+        let mut recorded = emulator();
+        recorded.memory.inputs = Inputs::record();
+        session(&mut recorded);
+        assert_ne!(recorded.cpu.get_reg(r2), 0);
+        let mut log = Vec::new();
+        recorded.memory.inputs.save(&mut log).unwrap();
+        let events = Inputs::parse(std::str::from_utf8(&log).unwrap()).unwrap();
+        assert_eq!(events, recorded.memory.inputs.events);
+
+        let mut emu = emulator();
+        emu.memory.inputs = Inputs::replay(events);
+        for _ in 0..200 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.cpu.register, recorded.cpu.register);
+        assert_eq!(emu.cpu.pc, recorded.cpu.pc);
+        assert!(emu.memory.uart_input.is_empty());
+    }
+
+    #[test]
+    fn reverse_execution_reads_the_inputs_again() {
+        let mut emu = emulator();
+        emu.history = History::new(100);
+        emu.memory.uart_input.extend([5, 0]);
+        emu.memory.inputs = Inputs::record();
+        for _ in 0..3 {
+            emu.step().unwrap();
+        }
+        assert_eq!(emu.memory.inputs.events.len(), 2);
+        emu.reverse_step();
+        assert_eq!(emu.memory.inputs.events.len(), 1);
+
+        let events = emu.memory.inputs.events.clone();
+        let mut emu = emulator();
+        emu.history = History::new(100);
+        emu.memory.inputs = Inputs::replay(events);
+        emu.step().unwrap();
+        emu.reverse_step();
+        emu.step().unwrap();
+        emu.step().unwrap();
+        assert_eq!(emu.memory.inputs.mode, Mode::Replay);
+        emu.step().unwrap();
+        // the log ran out, UART_RX reads the empty queue
+        assert_eq!(emu.memory.inputs.mode, Mode::Off);
+        assert_eq!(emu.cpu.get_reg(r1), 0);
+    }
+
+    #[test]
+    fn replay_stops_where_the_program_diverges() {
+        let log = "device 0 0xffffff08 01\ndevice 3 0xffffff04 07\n";
+        let mut emu = emulator();
+        emu.memory.inputs = Inputs::replay(Inputs::parse(log).unwrap());
+        emu.step().unwrap();
+        emu.step().unwrap();
+        let error = emu.step().unwrap_err().to_string();
+        assert!(
+            error.contains("the program read device 0xffffff04"),
+            "{}",
+            error
+        );
+        assert_eq!(emu.memory.inputs.mode, Mode::Off);
+
+        let mut emu = emulator();
+        let log = "device 0 0xffffff08 00\ndevice 1 0xffffff08 00";
+        emu.memory.inputs = Inputs::replay(Inputs::parse(log).unwrap());
+        emu.step().unwrap();
+        let error = emu.step().unwrap_err().to_string();
+        assert!(
+            error.contains("did not read device 0xffffff08 at instruction 1"),
+            "{}",
+            error
+        );
+
+        for log in ["time x 00", "device 0 0xffffff04", "time 5 00\ntime 4 00"] {
+            assert!(Inputs::parse(log).is_err(), "{}", log);
+        }
+    }
+}
@@ -0,0 +1,354 @@
+use super::cpu::Cpu;
+use super::memory::{Memory, MEMORY_SIZE};
+use super::replay::Source;
+use anyhow::{anyhow, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// TRAP number serviced by Semihost, the operation is in r1 and its
+// arguments in r2, r3 and r4, the result is returned in r1
+pub const TRAP: u8 = 0x1F;
+
+// r2 = address, r3 = length, appended to the UART output; returns length
+pub const WRITE_CONSOLE: u32 = 0x01;
+// r2 = address, r3 = length, read from host stdin; returns bytes read
+pub const READ_CONSOLE: u32 = 0x02;
+// r2 = NUL-terminated path, r3 = mode; returns a handle
+pub const OPEN: u32 = 0x03;
+// r2 = handle; returns 0
+pub const CLOSE: u32 = 0x04;
+// r2 = handle, r3 = address, r4 = length; returns bytes read, 0 at the end
+pub const READ: u32 = 0x05;
+// r2 = handle, r3 = address, r4 = length; returns bytes written
+pub const WRITE: u32 = 0x06;
+// returns seconds since the unix epoch
+pub const TIME: u32 = 0x07;
+// returns milliseconds since semihosting started
+pub const CLOCK: u32 = 0x08;
+// r2 = exit code, stops the emulator
+pub const EXIT: u32 = 0x09;
+
+// OPEN modes
+pub const MODE_READ: u32 = 0;
+// created or truncated
+pub const MODE_WRITE: u32 = 1;
+// created when missing
+pub const MODE_APPEND: u32 = 2;
+pub const MODE_READ_WRITE: u32 = 3;
+
+// returned in r1 when an operation fails on the host
+pub const ERROR: u32 = u32::MAX;
+
+// handles below are reserved for the console
+const FIRST_HANDLE: u32 = 3;
+
+#[derive(Debug)]
+pub struct Semihost {
+    // files are opened relative to this directory and can not leave it
+    pub root: PathBuf,
+    files: Vec<Option<File>>,
+    start: Instant,
+}
+
+impl Semihost {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            files: Vec::new(),
+            start: Instant::now(),
+        }
+    }
+
+    // services the operation in r1, returns the exit code when the program
+    // asked to stop; bad guest addresses are errors, host failures return ERROR
+    pub fn call(&mut self, cpu: &mut Cpu, memory: &mut Memory) -> Result<Option<u32>> {
+        let [a, b, c] = [cpu.get_reg(2), cpu.get_reg(3), cpu.get_reg(4)];
+        let result = match cpu.get_reg(1) {
+            WRITE_CONSOLE => {
+                let data = memory.read_bytes(a, b)?;
+                memory.uart.extend_from_slice(&data);
+                Some(b)
+            }
+            READ_CONSOLE => {
+                memory.check_buffer(a, b)?;
+                match memory.inputs.input(Source::Console, || read_stdin(b)) {
+                    Some(mut data) => {
+                        data.truncate(b as usize);
+                        memory.write_bytes(a, &data)?;
+                        Some(data.len() as u32)
+                    }
+                    None => None,
+                }
+            }
+            OPEN => {
+                let name = read_string(memory, a)?;
+                self.open(&name, b)
+            }
+            CLOSE => self.slot(a).and_then(Option::take).map(|_| 0),
+            READ => {
+                memory.check_buffer(b, c)?;
+                let mut data = vec![0; c as usize];
+                match self.file(a).map(|file| file.read(&mut data)) {
+                    Some(Ok(n)) => {
+                        memory.write_bytes(b, &data[..n])?;
+                        Some(n as u32)
+                    }
+                    _ => None,
+                }
+            }
+            WRITE => {
+                let data = memory.read_bytes(b, c)?;
+                match self.file(a).map(|file| file.write_all(&data)) {
+                    Some(Ok(())) => Some(c),
+                    _ => None,
+                }
+            }
+            TIME => memory.inputs.word(Source::Time, || {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|time| time.as_secs() as u32)
+            }),
+            CLOCK => memory.inputs.word(Source::Clock, || {
+                Some(self.start.elapsed().as_millis() as u32)
+            }),
+            EXIT => return Ok(Some(a)),
+            op => return Err(anyhow!("unknown semihosting operation 0x{:02x}", op)),
+        };
+        cpu.set_reg(1, result.unwrap_or(ERROR));
+        Ok(None)
+    }
+
+    fn open(&mut self, name: &str, mode: u32) -> Option<u32> {
+        let path = self.resolve(name)?;
+        let mut options = OpenOptions::new();
+        match mode {
+            MODE_READ => options.read(true),
+            MODE_WRITE => options.write(true).create(true).truncate(true),
+            MODE_APPEND => options.append(true).create(true),
+            MODE_READ_WRITE => options.read(true).write(true),
+            _ => return None,
+        };
+        let file = options.open(path).ok()?;
+        let index = match self.files.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[index] = Some(file);
+        Some(index as u32 + FIRST_HANDLE)
+    }
+
+    // relative paths without ".." only; symbolic links are followed but have
+    // to lead inside root, as does the directory of a file to be created
+    fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let path = Path::new(name);
+        let inside = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if name.is_empty() || !inside {
+            return None;
+        }
+        let root = self.root.canonicalize().ok()?;
+        let joined = self.root.join(path);
+        let resolved = match joined.canonicalize() {
+            Ok(resolved) => resolved,
+            // a dangling link would create its target wherever it points
+            Err(_) if joined.symlink_metadata().is_ok() => return None,
+            Err(_) => joined
+                .parent()?
+                .canonicalize()
+                .ok()?
+                .join(joined.file_name()?),
+        };
+        resolved.starts_with(&root).then_some(resolved)
+    }
+
+    fn slot(&mut self, handle: u32) -> Option<&mut Option<File>> {
+        let index = handle.checked_sub(FIRST_HANDLE)?;
+        self.files.get_mut(index as usize)
+    }
+
+    fn file(&mut self, handle: u32) -> Option<&mut File> {
+        self.slot(handle)?.as_mut()
+    }
+}
+
+// up to length bytes from the host's stdin, None when reading fails
+pub fn read_stdin(length: u32) -> Option<Vec<u8>> {
+    let mut data = vec![0; length as usize];
+    let n = std::io::stdin().read(&mut data).ok()?;
+    data.truncate(n);
+    Some(data)
+}
+
+fn read_string(memory: &mut Memory, address: u32) -> Result<String> {
+    let mut bytes = Vec::new();
+    for address in address..MEMORY_SIZE {
+        match memory.read_data_8bit(address)? {
+            0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            byte => bytes.push(byte),
+        }
+    }
+    Err(anyhow!("string at 0x{:08x} is not terminated", address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+    use crate::assert_reg;
+    use crate::emulator::history::History;
+    use crate::emulator::{Emulator, Engine};
+
+    fn sandbox(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("ktc32-semihost-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn emulator(asm: &Asm, root: &Path, data: &[(u32, &[u8])]) -> Emulator {
+        let mut emu = asm.emulator().unwrap();
+        emu.semihost = Some(Semihost::new(root));
+        for (address, bytes) in data {
+            for (i, byte) in bytes.iter().enumerate() {
+                emu.memory.store(address + i as u32, *byte);
+            }
+        }
+        emu
+    }
+
+    // TRAP with the operation in r1 and the arguments from r2 on
+    fn call<'a>(asm: &'a mut Asm, op: u32, args: &[u32]) -> &'a mut Asm {
+        for (i, arg) in args.iter().enumerate() {
+            asm.li(r2 + i as u8, *arg);
+        }
+        asm.li(r1, op).trap(TRAP)
+    }
+
+    #[test]
+    fn writes_console_and_exits() {
+        let root = sandbox("console");
+        let mut asm = Asm::new();
+        call(&mut asm, WRITE_CONSOLE, &[0x200, 2]);
+        call(&mut asm, EXIT, &[3]);
+        asm.addi(r5, r0, 1).halt();
+
+        for engine in [Engine::Interpreter, Engine::Threaded] {
+            let mut emu = emulator(&asm, &root, &[(0x200, b"ok")]);
+            emu.engine = engine;
+            emu.run().unwrap();
+            assert_eq!(emu.memory.uart, b"ok");
+            assert_eq!(emu.exit_code, Some(3));
+            assert_reg!(emu, r5, 0);
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn reverse_execution_undoes_the_exit() {
+        let root = sandbox("reverse-exit");
+        let mut asm = Asm::new();
+        call(&mut asm, EXIT, &[3]);
+        asm.halt();
+        let mut emu = emulator(&asm, &root, &[]);
+        emu.history = History::new(16);
+        emu.run().unwrap();
+        assert_eq!(emu.exit_code, Some(3));
+
+        assert!(emu.reverse_step());
+        assert_eq!(emu.exit_code, None);
+        emu.cpu.set_reg(r2, 4);
+        emu.run().unwrap();
+        assert_eq!(emu.exit_code, Some(4));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn files_stay_inside_the_sandbox() {
+        let root = sandbox("files");
+        let mut asm = Asm::new();
+        call(&mut asm, OPEN, &[0x200, MODE_WRITE]).mov(r10, r1);
+        call(&mut asm, WRITE, &[FIRST_HANDLE, 0x210, 5]).mov(r11, r1);
+        call(&mut asm, CLOSE, &[FIRST_HANDLE]).mov(r12, r1);
+        call(&mut asm, OPEN, &[0x200, MODE_READ]);
+        call(&mut asm, READ, &[FIRST_HANDLE, 0x220, 16]).mov(r13, r1);
+        call(&mut asm, OPEN, &[0x230, MODE_READ]).mov(r14, r1);
+        call(&mut asm, CLOSE, &[40]).mov(r15, r1);
+        asm.halt();
+        let mut emu = emulator(
+            &asm,
+            &root,
+            &[
+                (0x200, b"out.txt\0"),
+                (0x210, b"hello"),
+                (0x230, b"../out.txt\0"),
+            ],
+        );
+        emu.run_until_halt(1000).unwrap();
+
+        assert_reg!(emu, r10, FIRST_HANDLE);
+        assert_reg!(emu, r11, 5);
+        assert_reg!(emu, r12, 0);
+        assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"hello");
+        assert_reg!(emu, r13, 5);
+        assert_eq!(&emu.memory.memory_array[0x220..0x225], b"hello");
+        assert_reg!(emu, r14, ERROR);
+        assert_reg!(emu, r15, ERROR);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_can_not_leave_the_sandbox() {
+        use std::os::unix::fs::symlink;
+
+        let root = sandbox("links");
+        let outside = sandbox("links-outside");
+        std::fs::write(outside.join("secret.txt"), "secret").unwrap();
+        std::fs::write(root.join("inside.txt"), "inside").unwrap();
+        symlink(&outside, root.join("escape")).unwrap();
+        symlink(outside.join("new.txt"), root.join("dangling")).unwrap();
+        symlink(root.join("inside.txt"), root.join("alias")).unwrap();
+
+        let semihost = Semihost::new(&root);
+        assert!(semihost.resolve("escape/secret.txt").is_none());
+        assert!(semihost.resolve("escape/new.txt").is_none());
+        assert!(semihost.resolve("dangling").is_none());
+        assert!(semihost.resolve("alias").is_some());
+        assert!(semihost.resolve("created.txt").is_some());
+
+        let mut asm = Asm::new();
+        call(&mut asm, OPEN, &[0x200, MODE_READ]).mov(r10, r1);
+        call(&mut asm, OPEN, &[0x220, MODE_WRITE]).mov(r11, r1);
+        call(&mut asm, OPEN, &[0x240, MODE_READ]).mov(r12, r1);
+        asm.halt();
+        let data: [(u32, &[u8]); 3] = [
+            (0x200, b"escape/secret.txt\0"),
+            (0x220, b"dangling\0"),
+            (0x240, b"alias\0"),
+        ];
+        let mut emu = emulator(&asm, &root, &data);
+        emu.run_until_halt(1000).unwrap();
+        assert_reg!(emu, r10, ERROR);
+        assert_reg!(emu, r11, ERROR);
+        assert_reg!(emu, r12, FIRST_HANDLE);
+        assert!(!outside.join("new.txt").exists());
+        std::fs::remove_dir_all(root).unwrap();
+        std::fs::remove_dir_all(outside).unwrap();
+    }
+
+    #[test]
+    fn traps_without_semihosting_fail() {
+        let mut emu = Asm::new().trap(TRAP).emulator().unwrap();
+        let inst = emu.disassemble(0).unwrap().inst;
+        assert_eq!(inst.to_string(), "TRAP 31");
+        assert!(emu.execute(&inst).is_err());
+    }
+}
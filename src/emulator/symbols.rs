@@ -0,0 +1,73 @@
+use anyhow::{anyhow, Context, Result};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Default)]
+pub struct Symbols {
+    pub entries: BTreeMap<u32, String>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // accepts "<hex address> <name>" and nm style "<hex address> <type> <name>" lines
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut symbols = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (address, name) = match fields.as_slice() {
+                [] => continue,
+                [address, name] | [address, _, name] => (address, name),
+                _ => return Err(anyhow!("invalid symbol at line {}", i + 1)),
+            };
+            let address = u32::from_str_radix(address.trim_start_matches("0x"), 16)
+                .with_context(|| format!("invalid symbol address at line {}", i + 1))?;
+            symbols.entries.insert(address, name.to_string());
+        }
+        Ok(symbols)
+    }
+
+    // nearest symbol at or below address
+    pub fn lookup(&self, address: u32) -> Option<(&str, u32)> {
+        self.entries
+            .range(..=address)
+            .next_back()
+            .map(|(base, name)| (name.as_str(), address - base))
+    }
+
+    pub fn format(&self, address: u32) -> String {
+        match self.lookup(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) => format!("{}+0x{:x}", name, offset),
+            None => format!("0x{:08x}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_plain_and_nm_lines() {
+        let symbols = Symbols::parse("0x10 main\n\n00000040 T helper\n").unwrap();
+        assert_eq!(symbols.entries.len(), 2);
+        assert_eq!(symbols.lookup(0x40), Some(("helper", 0)));
+        for text in ["10", "10 T main extra", "zz main"] {
+            assert!(Symbols::parse(text).is_err(), "{}", text);
+        }
+        let error = Symbols::parse("10 a\nzz b").unwrap_err();
+        assert_eq!(error.to_string(), "invalid symbol address at line 2");
+    }
+
+    #[test]
+    fn looks_up_the_nearest_symbol_below() {
+        let symbols = Symbols::parse("10 main\n40 helper").unwrap();
+        assert_eq!(symbols.lookup(0x0f), None);
+        assert_eq!(symbols.lookup(0x3e), Some(("main", 0x2e)));
+        assert_eq!(symbols.format(0x10), "main");
+        assert_eq!(symbols.format(0x44), "helper+0x4");
+        assert_eq!(symbols.format(0x08), "0x00000008");
+    }
+}
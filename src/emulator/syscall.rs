@@ -0,0 +1,208 @@
+use super::cpu::Cpu;
+use super::memory::Memory;
+use super::replay::Source;
+use super::semihost::read_stdin;
+use anyhow::Result;
+
+// TRAP number serviced by Syscalls. The number is in r1 and the arguments
+// in r2 to r5, r1 gets the result or -errno
+pub const TRAP: u8 = 0x00;
+// stack pointer, set to the top of the stack by Syscalls::init
+pub const SP: u8 = 29;
+
+// code
+pub const EXIT: u32 = 1;
+// fd, address, length; stdin is read from the host
+pub const READ: u32 = 2;
+// fd, address, length; stdout and stderr go where UART output goes
+pub const WRITE: u32 = 3;
+// fd
+pub const CLOSE: u32 = 4;
+// fd, offset, whence
+pub const LSEEK: u32 = 5;
+// fd
+pub const ISATTY: u32 = 6;
+// address, 0 only queries the break; returns the new break
+pub const BRK: u32 = 7;
+// increment (signed); returns the previous break
+pub const SBRK: u32 = 8;
+
+// newlib errno values
+pub const EIO: u32 = 5;
+pub const EBADF: u32 = 9;
+pub const ENOMEM: u32 = 12;
+pub const ESPIPE: u32 = 29;
+pub const ENOSYS: u32 = 88;
+
+const STDIN: u32 = 0;
+const STDOUT: u32 = 1;
+const STDERR: u32 = 2;
+
+#[derive(Debug, Default)]
+pub struct Syscalls {
+    // current program break
+    pub brk: u32,
+}
+
+impl Syscalls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // program break and stack pointer from memory.layout
+    pub fn init(&mut self, cpu: &mut Cpu, memory: &Memory) {
+        self.brk = memory.layout.heap_start;
+        cpu.set_reg(SP, memory.layout.stack_top);
+    }
+
+    // same contract as Semihost::call
+    pub fn call(&mut self, cpu: &mut Cpu, memory: &mut Memory) -> Result<Option<u32>> {
+        let [a, b, c] = [cpu.get_reg(2), cpu.get_reg(3), cpu.get_reg(4)];
+        let result = match cpu.get_reg(1) {
+            EXIT => return Ok(Some(a)),
+            READ if a == STDIN => {
+                memory.check_buffer(b, c)?;
+                match memory.inputs.input(Source::Console, || read_stdin(c)) {
+                    Some(mut data) => {
+                        data.truncate(c as usize);
+                        memory.write_bytes(b, &data)?;
+                        Ok(data.len() as u32)
+                    }
+                    None => Err(EIO),
+                }
+            }
+            WRITE if a == STDOUT || a == STDERR => {
+                let data = memory.read_bytes(b, c)?;
+                memory.uart.extend_from_slice(&data);
+                Ok(c)
+            }
+            CLOSE if a <= STDERR => Ok(0),
+            LSEEK if a <= STDERR => Err(ESPIPE),
+            ISATTY if a <= STDERR => Ok(1),
+            READ | WRITE | CLOSE | LSEEK | ISATTY => Err(EBADF),
+            BRK if a == 0 => Ok(self.brk),
+            BRK => self.set_brk(memory, a),
+            SBRK => {
+                let brk = self.brk;
+                match brk.checked_add_signed(a as i32) {
+                    Some(new) => self.set_brk(memory, new).map(|_| brk),
+                    None => Err(ENOMEM),
+                }
+            }
+            _ => Err(ENOSYS),
+        };
+        cpu.set_reg(1, result.unwrap_or_else(|errno| errno.wrapping_neg()));
+        Ok(None)
+    }
+
+    fn set_brk(&mut self, memory: &Memory, brk: u32) -> Result<u32, u32> {
+        let layout = memory.layout;
+        if brk < layout.heap_start || brk > layout.heap_end {
+            return Err(ENOMEM);
+        }
+        self.brk = brk;
+        Ok(brk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+    use crate::assert_reg;
+    use crate::emulator::history::History;
+    use crate::emulator::memory::Layout;
+    use crate::emulator::replay::Inputs;
+    use crate::emulator::Emulator;
+
+    fn emulator(asm: &Asm) -> Emulator {
+        let mut emu = asm.emulator().unwrap();
+        emu.memory.layout = Layout {
+            heap_start: 0x100,
+            heap_end: 0x200,
+            stack_top: 0x300,
+        };
+        let mut syscalls = Syscalls::new();
+        syscalls.init(&mut emu.cpu, &emu.memory);
+        emu.syscalls = Some(syscalls);
+        emu
+    }
+
+    fn syscall<'a>(asm: &'a mut Asm, number: u32, args: &[u32]) -> &'a mut Asm {
+        for (i, arg) in args.iter().enumerate() {
+            asm.li(r2 + i as u8, *arg);
+        }
+        asm.li(r1, number).trap(TRAP)
+    }
+
+    #[test]
+    fn writes_stdout_and_exits() {
+        let mut asm = Asm::new();
+        asm.li(r5, u32::from_le_bytes(*b"hi!\n")).sw(r5, r0, 0x100);
+        syscall(&mut asm, WRITE, &[STDOUT, 0x100, 4]).mov(r10, r1);
+        syscall(&mut asm, WRITE, &[7, 0x100, 4]).mov(r11, r1);
+        syscall(&mut asm, 99, &[]).mov(r12, r1);
+        syscall(&mut asm, EXIT, &[42]);
+        asm.addi(r13, r0, 1).halt();
+        let mut emu = emulator(&asm);
+        emu.run().unwrap();
+
+        assert_eq!(emu.memory.uart, b"hi!\n");
+        assert_reg!(emu, r10, 4);
+        assert_reg!(emu, r11, EBADF.wrapping_neg());
+        assert_reg!(emu, r12, ENOSYS.wrapping_neg());
+        assert_eq!(emu.exit_code, Some(42));
+        assert_reg!(emu, r13, 0);
+        assert_reg!(emu, SP, 0x300);
+    }
+
+    #[test]
+    fn break_stays_inside_the_heap() {
+        let mut asm = Asm::new();
+        syscall(&mut asm, BRK, &[0]).mov(r10, r1);
+        syscall(&mut asm, SBRK, &[0x40]).mov(r11, r1);
+        syscall(&mut asm, SBRK, &[(-0x10i32) as u32]).mov(r12, r1);
+        syscall(&mut asm, BRK, &[0x201]).mov(r13, r1);
+        syscall(&mut asm, SBRK, &[0x200]).mov(r14, r1);
+        syscall(&mut asm, BRK, &[0x200]).mov(r15, r1);
+        asm.halt();
+        let mut emu = emulator(&asm);
+        emu.run_until_halt(1000).unwrap();
+
+        assert_reg!(emu, r10, 0x100);
+        assert_reg!(emu, r11, 0x100);
+        assert_reg!(emu, r12, 0x140);
+        assert_reg!(emu, r13, ENOMEM.wrapping_neg());
+        assert_reg!(emu, r14, ENOMEM.wrapping_neg());
+        assert_reg!(emu, r15, 0x200);
+        assert_eq!(emu.syscalls.unwrap().brk, 0x200);
+    }
+
+    #[test]
+    fn reverse_execution_restores_the_break() {
+        let mut asm = Asm::new();
+        syscall(&mut asm, SBRK, &[0x40]).halt();
+        let mut emu = emulator(&asm);
+        emu.history = History::new(16);
+        emu.run_until_halt(100).unwrap();
+        assert_reg!(emu, r1, 0x100);
+        assert!(emu.reverse_step());
+        assert!(emu.reverse_step());
+        assert_eq!(emu.syscalls.as_ref().unwrap().brk, 0x100);
+        emu.run_until_halt(100).unwrap();
+        assert_reg!(emu, r1, 0x100);
+    }
+
+    #[test]
+    fn failed_reads_return_eio() {
+        let mut asm = Asm::new();
+        syscall(&mut asm, READ, &[STDIN, 0x100, 4]).halt();
+        let mut emu = emulator(&asm);
+        // the host read behind the TRAP, after four LUI and ADDI pairs, failed
+        let log = "console 8 error\n";
+        emu.memory.inputs = Inputs::replay(Inputs::parse(log).unwrap());
+        emu.run_until_halt(100).unwrap();
+        assert_reg!(emu, r1, EIO.wrapping_neg());
+    }
+}
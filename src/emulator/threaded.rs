@@ -0,0 +1,402 @@
+use super::cpu::Cpu;
+use super::isa::{self, Profile};
+use super::memory::{Memory, MEMORY_SIZE};
+use super::{fetch_word, illegal, Emulator, Type};
+use anyhow::Result;
+use std::collections::HashMap;
+
+const MAX_BLOCK_LENGTH: usize = 64;
+
+type Op = Box<dyn Fn(&mut Cpu, &mut Memory) -> Result<()>>;
+
+// straight-line run of instructions ending at a branch or jump
+pub struct Block {
+    pub start: u32,
+    pub end: u32,
+    // pc of the following instruction and the translated instruction
+    pub ops: Vec<(u32, Op)>,
+    // the block stops in front of a TRAP, which the interpreter executes
+    pub trap: bool,
+    // successors already looked up, (pc, block index)
+    links: Vec<(u32, usize)>,
+}
+
+#[derive(Default)]
+pub struct Threaded {
+    pub blocks: Vec<Block>,
+    index: HashMap<u32, usize>,
+    // blocks never run past the break point they were translated for
+    break_point: u32,
+    // and are translated for one ISA profile
+    isa: Profile,
+}
+
+impl std::fmt::Debug for Threaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Threaded")
+            .field("blocks", &self.blocks.len())
+            .finish()
+    }
+}
+
+impl Threaded {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.index.clear();
+    }
+
+    pub fn prepare(&mut self, break_point: u32, isa: Profile) {
+        if break_point != self.break_point || isa != self.isa {
+            self.flush();
+            self.break_point = break_point;
+            self.isa = isa;
+        }
+    }
+
+    // block starting at pc, translating it when it is not cached yet
+    pub fn lookup(&mut self, memory: &mut Memory, pc: u32, break_point: u32) -> Result<usize> {
+        if let Some(index) = self.index.get(&pc) {
+            return Ok(*index);
+        }
+
+        let mut ops = Vec::new();
+        let mut next_pc = pc;
+        let mut trap = false;
+        loop {
+            let (word, length) = match fetch_word(memory, next_pc) {
+                Ok(fetched) => fetched,
+                Err(error) if ops.is_empty() => return Err(error.into()),
+                Err(_) => break,
+            };
+            let inst = if length == 4 {
+                Emulator::decode_32(word)
+            } else {
+                Emulator::decode_16(word as u16)
+            };
+            if inst.mnemonic() == "TRAP" {
+                trap = true;
+                break;
+            }
+            memory.mark_code(next_pc, length);
+            next_pc += length;
+            ops.push((next_pc, translate(inst, self.isa)));
+
+            if is_terminator(&inst)
+                || next_pc == break_point
+                || next_pc >= MEMORY_SIZE
+                || ops.len() >= MAX_BLOCK_LENGTH
+            {
+                break;
+            }
+        }
+
+        let index = self.blocks.len();
+        self.blocks.push(Block {
+            start: pc,
+            end: next_pc,
+            ops,
+            trap,
+            links: Vec::new(),
+        });
+        self.index.insert(pc, index);
+        Ok(index)
+    }
+
+    // follow a chained successor or look it up and chain it
+    pub fn next(
+        &mut self,
+        memory: &mut Memory,
+        from: usize,
+        pc: u32,
+        break_point: u32,
+    ) -> Result<usize> {
+        if let Some((_, index)) = self.blocks[from].links.iter().find(|link| link.0 == pc) {
+            return Ok(*index);
+        }
+        let index = self.lookup(memory, pc, break_point)?;
+        self.blocks[from].links.push((pc, index));
+        Ok(index)
+    }
+}
+
+fn is_terminator(inst: &Type) -> bool {
+    matches!(
+        inst.mnemonic(),
+        "BEQ" | "BNQ" | "BLT" | "BGE" | "BLTU" | "BGEU" | "JAL" | "JALR"
+    )
+}
+
+fn r(f: impl Fn(&mut Cpu, u8, u8) + 'static, rd: u8, rs: u8) -> Op {
+    Box::new(move |cpu, _| {
+        f(cpu, rd, rs);
+        Ok(())
+    })
+}
+
+fn i16(f: impl Fn(&mut Cpu, u8, u8) + 'static, rd: u8, imm: u8) -> Op {
+    Box::new(move |cpu, _| {
+        f(cpu, rd, imm);
+        Ok(())
+    })
+}
+
+fn i32(f: impl Fn(&mut Cpu, u8, u8, i32) + 'static, rd: u8, rs: u8, imm: i32) -> Op {
+    Box::new(move |cpu, _| {
+        f(cpu, rd, rs, imm);
+        Ok(())
+    })
+}
+
+fn branch(f: impl Fn(u32, u32) -> bool + 'static, rd: u8, rs: u8, imm: i32) -> Op {
+    Box::new(move |cpu, _| {
+        if f(cpu.get_reg(rd), cpu.get_reg(rs)) {
+            cpu.pc = cpu.pc.wrapping_add(imm as u32);
+        }
+        Ok(())
+    })
+}
+
+fn illegal_op(inst: Type, isa: Profile) -> Op {
+    Box::new(move |_, _| Err(illegal(&inst, isa)))
+}
+
+// same semantics as Emulator::execute
+fn translate(inst: Type, isa: Profile) -> Op {
+    if isa::by_mnemonic(inst.mnemonic()).is_some_and(|spec| !isa.implements(spec)) {
+        return illegal_op(inst, isa);
+    }
+    match inst {
+        Type::RFormat { mnemonic, rd, rs } => match mnemonic {
+            "MOV" => r(Cpu::mov, rd, rs),
+            "ADD" => r(Cpu::add, rd, rs),
+            "SUB" => r(Cpu::sub, rd, rs),
+            "AND" => r(Cpu::and, rd, rs),
+            "OR" => r(Cpu::or, rd, rs),
+            "XOR" => r(Cpu::xor, rd, rs),
+            "SLL" => r(Cpu::sll, rd, rs),
+            "SRL" => r(Cpu::srl, rd, rs),
+            "SRA" => r(Cpu::sra, rd, rs),
+            "SLT" => r(Cpu::slt, rd, rs),
+            "SLTU" => r(Cpu::sltu, rd, rs),
+            "MUL" => r(Cpu::mul, rd, rs),
+            "MULH" => r(Cpu::mulh, rd, rs),
+            "MULHU" => r(Cpu::mulhu, rd, rs),
+            "DIV" => r(Cpu::div, rd, rs),
+            "DIVU" => r(Cpu::divu, rd, rs),
+            "REM" => r(Cpu::rem, rd, rs),
+            "REMU" => r(Cpu::remu, rd, rs),
+            _ => illegal_op(inst, isa),
+        },
+
+        Type::I16Format { mnemonic, rd, imm } => match mnemonic {
+            "SLLI" => i16(Cpu::slli, rd, imm),
+            "SRLI" => i16(Cpu::srli, rd, imm),
+            "SRAI" => i16(Cpu::srai, rd, imm),
+            _ => illegal_op(inst, isa),
+        },
+
+        Type::I32Format {
+            mnemonic,
+            rd,
+            rs,
+            imm,
+        } => match mnemonic {
+            "ADDI" => i32(Cpu::addi, rd, rs, imm),
+            "ANDI" => i32(Cpu::andi, rd, rs, imm),
+            "ORI" => i32(Cpu::ori, rd, rs, imm),
+            "XORI" => i32(Cpu::xori, rd, rs, imm),
+            "SLTI" => i32(Cpu::slti, rd, rs, imm),
+            "SLTIU" => i32(Cpu::sltiu, rd, rs, imm),
+            "BEQ" => branch(|a, b| a == b, rd, rs, imm),
+            "BNQ" => branch(|a, b| a != b, rd, rs, imm),
+            "BLT" => branch(|a, b| (a as i32) < (b as i32), rd, rs, imm),
+            "BGE" => branch(|a, b| (a as i32) >= (b as i32), rd, rs, imm),
+            "BLTU" => branch(|a, b| a < b, rd, rs, imm),
+            "BGEU" => branch(|a, b| a >= b, rd, rs, imm),
+            "JALR" => Box::new(move |cpu, _| {
+                let target = cpu.get_reg(rs).wrapping_add(imm as u32);
+                cpu.set_reg(rd, cpu.pc);
+                cpu.pc = target;
+                Ok(())
+            }),
+            "LB" => Box::new(move |cpu, memory| {
+                let data =
+                    memory.read_data_8bit(cpu.register[rs as usize].wrapping_add(imm as u32))?;
+                cpu.set_reg(rd, ((data as i8) as i32) as u32);
+                Ok(())
+            }),
+            "LH" => Box::new(move |cpu, memory| {
+                let data =
+                    memory.read_data_16bit(cpu.register[rs as usize].wrapping_add(imm as u32))?;
+                cpu.set_reg(rd, ((data as i16) as i32) as u32);
+                Ok(())
+            }),
+            "LBU" => Box::new(move |cpu, memory| {
+                let data =
+                    memory.read_data_8bit(cpu.register[rs as usize].wrapping_add(imm as u32))?;
+                cpu.set_reg(rd, data as u32);
+                Ok(())
+            }),
+            "LHU" => Box::new(move |cpu, memory| {
+                let data =
+                    memory.read_data_16bit(cpu.register[rs as usize].wrapping_add(imm as u32))?;
+                cpu.set_reg(rd, data as u32);
+                Ok(())
+            }),
+            "LW" => Box::new(move |cpu, memory| {
+                let data = memory.read_data(cpu.register[rs as usize].wrapping_add(imm as u32))?;
+                cpu.set_reg(rd, data);
+                Ok(())
+            }),
+            "LUI" => Box::new(move |cpu, _| {
+                cpu.set_reg(rd, (imm << 16) as u32);
+                Ok(())
+            }),
+            "SB" => Box::new(move |cpu, memory| {
+                memory.write_data_8bit(
+                    cpu.get_reg(rs).wrapping_add(imm as u32),
+                    cpu.get_reg(rd) as u8,
+                )?;
+                Ok(())
+            }),
+            "SH" => Box::new(move |cpu, memory| {
+                memory.write_data_16bit(
+                    cpu.get_reg(rs).wrapping_add(imm as u32),
+                    cpu.get_reg(rd) as u16,
+                )?;
+                Ok(())
+            }),
+            "SW" => Box::new(move |cpu, memory| {
+                memory.write_data(cpu.get_reg(rs).wrapping_add(imm as u32), cpu.get_reg(rd))?;
+                Ok(())
+            }),
+            _ => illegal_op(inst, isa),
+        },
+
+        Type::JFormat { mnemonic, rd, imm } => match mnemonic {
+            "JAL" => Box::new(move |cpu, _| {
+                cpu.set_reg(rd, cpu.pc);
+                cpu.pc = cpu.pc.wrapping_add(imm as u32);
+                Ok(())
+            }),
+            _ => illegal_op(inst, isa),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::isa::Profile;
+    use crate::emulator::{Emulator, Engine};
+
+    fn xorshift(seed: &mut u32) -> u32 {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        *seed
+    }
+
+    // straight-line code with forward control flow only, so every program terminates
+    fn random_program(seed: &mut u32, count: usize) -> Vec<u8> {
+        let mut kinds = Vec::new();
+        let mut offsets = Vec::new();
+        let mut end = 0;
+        for _ in 0..count {
+            let kind = xorshift(seed) % 6;
+            offsets.push(end);
+            end += if kind < 2 { 2 } else { 4 };
+            kinds.push(kind);
+        }
+        offsets.push(end);
+
+        let mut program = Vec::new();
+        for (i, kind) in kinds.into_iter().enumerate() {
+            let r = xorshift(seed);
+            let rd = (r >> 8) & 0x1F;
+            let rs = (r >> 13) & 0x1F;
+            let next_pc = offsets[i + 1];
+            let target = offsets[i + 1 + (r >> 18) as usize % (count - i)];
+            let word = match kind {
+                0 => (r % 11) | (rd << 6) | (rs << 11),
+                1 => (16 + r % 3) | rd << 6 | rs << 11,
+                2 => {
+                    let opcode = if r % 8 == 6 { 50 } else { 32 + r % 6 };
+                    opcode | rd << 6 | rs << 11 | (r >> 16) << 16
+                }
+                3 => (45 + r % 5) | (rd << 6) | (((r >> 22) % 700) << 16),
+                4 => (51 + r % 3) | rd << 6 | (512 + (r >> 22) % 200) << 16,
+                _ => match r % 4 {
+                    0 => 63 | rd << 6 | (target - next_pc) << 11,
+                    1 => 44 | rd << 6 | target << 16,
+                    _ => (38 + r % 6) | rd << 6 | rs << 11 | (target - next_pc) << 16,
+                },
+            };
+            if kind < 2 {
+                program.extend_from_slice(&(word as u16).to_le_bytes());
+            } else {
+                program.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        program
+    }
+
+    fn run_both(program: Vec<u8>, register: [u32; 32]) -> (Emulator, Emulator) {
+        let mut reference = Emulator::new(program.clone());
+        let mut threaded = Emulator::new(program);
+        threaded.engine = Engine::Threaded;
+        for emu in [&mut reference, &mut threaded] {
+            emu.memory.init();
+            emu.trace = false;
+            emu.cpu.register = register;
+            emu.cpu.register[0] = 0;
+        }
+        assert_eq!(reference.run().is_ok(), threaded.run().is_ok());
+        (reference, threaded)
+    }
+
+    fn assert_same_state(reference: &Emulator, threaded: &Emulator) {
+        assert_eq!(reference.cpu.pc, threaded.cpu.pc);
+        assert_eq!(reference.cpu.register, threaded.cpu.register);
+        assert_eq!(reference.memory.memory_array, threaded.memory.memory_array);
+        assert_eq!(reference.instret, threaded.instret);
+    }
+
+    #[test]
+    fn threaded_matches_interpreter() {
+        let mut seed = 0x1234_5678;
+        for _ in 0..500 {
+            let program = random_program(&mut seed, 48);
+            let mut register = [0; 32];
+            for r in register.iter_mut() {
+                *r = xorshift(&mut seed);
+            }
+            let (reference, threaded) = run_both(program, register);
+            assert_same_state(&reference, &threaded);
+        }
+    }
+
+    #[test]
+    fn unused_opcodes_translate_to_illegal_instructions() {
+        let op = super::translate(Emulator::decode_16(0x003F), Profile::BASE);
+        let mut emu = Emulator::new(vec![]);
+        assert!(op(&mut emu.cpu, &mut emu.memory).is_err());
+    }
+
+    #[test]
+    fn threaded_sees_code_writes() {
+        // SW r1 r0 8; ADDI r2 r0 1; ADDI r3 r0 1
+        let program = [0x0008_0075_u32, 0x0001_00a0, 0x0001_00e0]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut register = [0; 32];
+        register[1] = 0x0002_00e0;
+        let (reference, threaded) = run_both(program, register);
+        assert_same_state(&reference, &threaded);
+        assert_eq!(threaded.cpu.get_reg(3), 2);
+    }
+}
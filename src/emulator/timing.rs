@@ -0,0 +1,197 @@
+use super::Type;
+use std::any::Any;
+use std::fmt::Debug;
+
+pub trait TimingModel: Debug {
+    // cycles spent by one retired instruction, redirected is true when it changed the flow
+    fn cycles(&mut self, inst: &Type, length: u32, redirected: bool) -> u64;
+
+    // snapshot of the state carried from one instruction to the next, kept
+    // in the history so that reverse execution can restore it; None when
+    // there is none
+    fn state(&self) -> Option<Box<dyn Any>> {
+        None
+    }
+    // gets back a snapshot taken by state
+    fn restore(&mut self, _state: Box<dyn Any>) {}
+}
+
+// in-order KTC32 pipeline
+#[derive(Debug)]
+pub struct Pipeline {
+    pub alu: u64,
+    pub mul: u64,
+    pub div: u64,
+    pub load: u64,
+    pub store: u64,
+    pub branch_penalty: u64,
+    pub load_use_stall: u64,
+    pub fetch_16: u64,
+    pub fetch_32: u64,
+    // destination of the previous instruction when it was a load
+    pending_load: Option<u8>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self {
+            alu: 1,
+            mul: 3,
+            div: 32,
+            load: 1,
+            store: 1,
+            branch_penalty: 2,
+            load_use_stall: 1,
+            fetch_16: 0,
+            fetch_32: 1,
+            pending_load: None,
+        }
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimingModel for Pipeline {
+    fn cycles(&mut self, inst: &Type, length: u32, redirected: bool) -> u64 {
+        let mut cycles = if length == 4 {
+            self.fetch_32
+        } else {
+            self.fetch_16
+        };
+
+        if let Some(rd) = self.pending_load.take() {
+            if rd != 0 && sources(inst).contains(&Some(rd)) {
+                cycles += self.load_use_stall;
+            }
+        }
+
+        cycles += match inst.mnemonic() {
+            "LB" | "LH" | "LBU" | "LHU" | "LW" => {
+                if let Type::I32Format { rd, .. } = inst {
+                    self.pending_load = Some(*rd);
+                }
+                self.load
+            }
+            "SB" | "SH" | "SW" => self.store,
+            "MUL" | "MULH" | "MULHU" => self.mul,
+            "DIV" | "DIVU" | "REM" | "REMU" => self.div,
+            _ => self.alu,
+        };
+
+        if redirected {
+            cycles += self.branch_penalty;
+        }
+        cycles
+    }
+
+    fn state(&self) -> Option<Box<dyn Any>> {
+        Some(Box::new(self.pending_load))
+    }
+
+    fn restore(&mut self, state: Box<dyn Any>) {
+        if let Ok(pending_load) = state.downcast() {
+            self.pending_load = *pending_load;
+        }
+    }
+}
+
+// registers read by an instruction
+fn sources(inst: &Type) -> [Option<u8>; 2] {
+    match inst {
+        Type::RFormat { mnemonic, rs, .. } if *mnemonic == "MOV" => [Some(*rs), None],
+        Type::RFormat { rd, rs, .. } => [Some(*rd), Some(*rs)],
+        Type::I16Format { rd, .. } => [Some(*rd), None],
+        Type::I32Format {
+            mnemonic, rd, rs, ..
+        } => match *mnemonic {
+            "LUI" => [None, None],
+            "BEQ" | "BNQ" | "BLT" | "BGE" | "BLTU" | "BGEU" | "SB" | "SH" | "SW" => {
+                [Some(*rd), Some(*rs)]
+            }
+            _ => [Some(*rs), None],
+        },
+        Type::JFormat { .. } => [None, None],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn r(mnemonic: &'static str, rd: u8, rs: u8) -> Type {
+        Type::RFormat { mnemonic, rd, rs }
+    }
+
+    fn i32(mnemonic: &'static str, rd: u8, rs: u8) -> Type {
+        Type::I32Format {
+            mnemonic,
+            rd,
+            rs,
+            imm: 0,
+        }
+    }
+
+    // cycles of each (instruction, redirected) in order
+    fn run(sequence: &[(Type, bool)]) -> Vec<u64> {
+        let mut pipeline = Pipeline::new();
+        sequence
+            .iter()
+            .map(|(inst, redirected)| {
+                let length = if matches!(inst, Type::RFormat { .. }) {
+                    2
+                } else {
+                    4
+                };
+                pipeline.cycles(inst, length, *redirected)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fetch_costs_depend_on_length() {
+        let cycles = run(&[(r("ADD", 1, 2), false), (i32("ADDI", 1, 2), false)]);
+        assert_eq!(cycles, [1, 2]);
+    }
+
+    #[test]
+    fn a_load_stalls_the_next_reader_of_its_destination() {
+        let cycles = run(&[
+            (i32("LW", 1, 2), false),
+            (r("ADD", 3, 1), false),
+            (i32("LW", 1, 2), false),
+            (r("ADD", 3, 4), false),
+            (r("ADD", 3, 1), false),
+            (i32("LW", 0, 2), false),
+            (r("ADD", 3, 0), false),
+        ]);
+        assert_eq!(cycles, [2, 2, 2, 1, 1, 2, 1]);
+    }
+
+    #[test]
+    fn taken_branches_pay_the_penalty() {
+        let cycles = run(&[(i32("BEQ", 1, 2), true), (i32("BEQ", 1, 2), false)]);
+        assert_eq!(cycles, [4, 2]);
+    }
+
+    #[test]
+    fn multiply_and_divide_take_longer() {
+        let cycles = run(&[(r("MUL", 1, 2), false), (r("DIVU", 1, 2), false)]);
+        assert_eq!(cycles, [3, 32]);
+    }
+
+    #[test]
+    fn state_restores_the_pending_load() {
+        let mut pipeline = Pipeline::new();
+        let state = pipeline.state().unwrap();
+        pipeline.cycles(&i32("LW", 5, 2), 4, false);
+        let loaded = pipeline.state().unwrap();
+        pipeline.restore(state);
+        assert_eq!(pipeline.cycles(&r("ADD", 1, 5), 2, false), 1);
+        pipeline.restore(loaded);
+        assert_eq!(pipeline.cycles(&r("ADD", 1, 5), 2, false), 2);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod asm;
+pub mod debugger;
+pub mod emulator;
+pub mod script;
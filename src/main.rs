@@ -1,15 +1,132 @@
 use anyhow::{Context, Result};
 use clap::Parser;
-use std::io::{self, Write};
 
-mod emulator;
-use emulator::Emulator;
+use ktc32_emu::debugger::{Debugger, Flow};
+use ktc32_emu::emulator::{self, Emulator};
+use ktc32_emu::script;
+
+mod tui;
 
 #[derive(Parser)]
 #[clap(version = "0.1", author = "kinpoko", about = "KTC32 emulator")]
 struct Cli {
     #[clap(parse(from_os_str))]
     file_path: std::path::PathBuf,
+    /// Number of executed instructions kept for reverse execution, 10000 by
+    /// default and 0 with --engine threaded
+    #[clap(long)]
+    history: Option<usize>,
+    /// Count executed instructions and print a hotspot report at exit; reverse
+    /// steps do not subtract from the counts
+    #[clap(long)]
+    profile: bool,
+    /// Write the profile as flamegraph folded stacks to this file
+    #[clap(long, parse(from_os_str))]
+    profile_folded: Option<std::path::PathBuf>,
+    /// Do not print executed instructions
+    #[clap(short, long)]
+    quiet: bool,
+    /// Execution engine used by run
+    #[clap(long, default_value = "interpreter", possible_values = ["interpreter", "threaded"])]
+    engine: String,
+    /// Misaligned halfword and word accesses: allowed, trapped or split into bytes
+    #[clap(long, default_value = "allow", possible_values = ["allow", "trap", "split"])]
+    alignment: String,
+    /// Byte order of memory; the program image is loaded byte for byte, so it
+    /// must be assembled for the same order
+    #[clap(long, default_value = "little", possible_values = ["little", "big"])]
+    byte_order: String,
+    /// ISA profile executed, "base" followed by "+extension" for each extension
+    #[clap(long, default_value = "base")]
+    isa: emulator::isa::Profile,
+    /// Number of harts sharing memory, all of them start at address 0
+    #[clap(long, default_value_t = 1)]
+    harts: usize,
+    /// Instructions each hart runs before the next one's turn, one value per
+    /// hart separated by commas; the last value applies to the remaining harts
+    #[clap(long, default_value = "1", use_value_delimiter = true)]
+    quantum: Vec<u64>,
+    /// Count cycles with the KTC32 pipeline timing model
+    #[clap(long)]
+    timing: bool,
+    /// Symbol file with "<hex address> <name>" lines
+    #[clap(long, parse(from_os_str))]
+    symbols: Option<std::path::PathBuf>,
+    /// Record the inputs the program reads from devices and the host to this file
+    #[clap(long, parse(from_os_str), conflicts_with = "replay")]
+    record: Option<std::path::PathBuf>,
+    /// Replay inputs from a file written by --record instead of reading them
+    #[clap(long, parse(from_os_str))]
+    replay: Option<std::path::PathBuf>,
+    /// Full-screen terminal UI instead of the command prompt
+    #[clap(long)]
+    tui: bool,
+    /// Run debugger commands from this file before the prompt
+    #[clap(long, parse(from_os_str))]
+    script: Option<std::path::PathBuf>,
+    /// Do not run ~/.ktc32emurc at startup
+    #[clap(long)]
+    no_rc: bool,
+    /// Run a Rhai script instead of the command prompt
+    #[clap(long, parse(from_os_str))]
+    rhai: Option<std::path::PathBuf>,
+    /// Serve semihosting calls, files are opened inside this directory
+    #[clap(long, parse(from_os_str))]
+    semihost: Option<std::path::PathBuf>,
+    /// Serve newlib-style syscalls made with TRAP 0
+    #[clap(long)]
+    syscalls: bool,
+    /// Stack size below the top of memory for --syscalls, the heap ends there
+    #[clap(long, default_value_t = emulator::memory::DEFAULT_STACK_SIZE, parse(try_from_str = number))]
+    stack_size: u32,
+    /// Start of the heap for --syscalls, defaults to the end of the program
+    #[clap(long, parse(try_from_str = number))]
+    heap_start: Option<u32>,
+    /// Collect coverage and merge it into this file, print a summary at exit
+    #[clap(long, parse(from_os_str))]
+    coverage: Option<std::path::PathBuf>,
+    /// Write disassembly annotated with execution counts to this file
+    #[clap(long, parse(from_os_str))]
+    coverage_report: Option<std::path::PathBuf>,
+    /// Write coverage as an lcov tracefile to this file
+    #[clap(long, parse(from_os_str), requires = "line-map")]
+    lcov: Option<std::path::PathBuf>,
+    /// Line map with "<hex address> <file>:<line>" lines, used by --lcov
+    #[clap(long, parse(from_os_str))]
+    line_map: Option<std::path::PathBuf>,
+}
+
+// decimal or 0x prefixed hexadecimal
+fn number(text: &str) -> Result<u32, std::num::ParseIntError> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+}
+
+// rc file, scripts and then the prompt or TUI
+fn session(args: &Cli, emu: &mut Emulator) -> Result<()> {
+    let mut debugger = Debugger::new();
+    let mut flow = Flow::Continue;
+    if let Some(path) = &args.rhai {
+        script::run_file(emu, path)?;
+        flow = Flow::Finish;
+    }
+    let rc = std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".ktc32emurc"));
+    if let Some(rc) = rc.filter(|rc| flow == Flow::Continue && !args.no_rc && rc.exists()) {
+        flow = debugger.source(emu, &rc)?;
+    }
+    if let Some(script) = args.script.as_ref().filter(|_| flow == Flow::Continue) {
+        flow = debugger.source(emu, script)?;
+    }
+    if flow == Flow::Continue {
+        if args.tui {
+            tui::run(emu)?;
+        } else {
+            debugger.repl(emu)?;
+        }
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -24,230 +141,153 @@ fn main() -> Result<()> {
         program.append(&mut hex);
     }
 
+    let end = program.len() as u32;
     let mut emu = Emulator::new(program);
+    if args.byte_order == "big" {
+        emu.memory.byte_order = emulator::memory::ByteOrder::Big;
+    }
     emu.memory.init();
+    let history = match args.history {
+        Some(history) => history,
+        None if args.engine == "threaded" => 0,
+        None => 10000,
+    };
+    emu.history = emulator::history::History::new(history);
+    emu.trace = !args.quiet;
+    if args.engine == "threaded" {
+        emu.engine = emulator::Engine::Threaded;
+    }
+    emu.alignment = match args.alignment.as_str() {
+        "trap" => emulator::Alignment::Trap,
+        "split" => emulator::Alignment::Split,
+        _ => emulator::Alignment::Allow,
+    };
+    emu.isa = args.isa;
+    for _ in 1..args.harts {
+        emu.add_hart();
+    }
+    emu.quantum = args.quantum.clone();
+    if args.record.is_some() {
+        emu.memory.inputs = emulator::replay::Inputs::record();
+    }
+    if let Some(path) = &args.replay {
+        let f = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read file '{}'", path.display()))?;
+        let events = emulator::replay::Inputs::parse(&f)
+            .with_context(|| format!("could not parse file '{}'", path.display()))?;
+        emu.memory.inputs = emulator::replay::Inputs::replay(events);
+    }
+    if let Some(path) = &args.symbols {
+        let f = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read file '{}'", path.display()))?;
+        emu.symbols = emulator::symbols::Symbols::parse(&f)?;
+    }
+    if args.timing {
+        emu.timing = Some(Box::new(emulator::timing::Pipeline::new()));
+    }
+    if args.profile || args.profile_folded.is_some() {
+        emu.profiler = Some(emulator::profiler::Profiler::new());
+    }
+    if let Some(root) = &args.semihost {
+        emu.semihost = Some(emulator::semihost::Semihost::new(root));
+    }
+    if args.syscalls {
+        let mut layout = emulator::memory::Layout::new(end, args.stack_size);
+        if let Some(heap_start) = args.heap_start {
+            layout.heap_start = heap_start;
+        }
+        emu.memory.layout = layout;
+        let mut syscalls = emulator::syscall::Syscalls::new();
+        syscalls.init(&mut emu.cpu, &emu.memory);
+        emu.syscalls = Some(syscalls);
+    }
 
-    let mut input = String::new();
-    loop {
-        print!("> ");
-        io::stdout().flush().unwrap();
-        io::stdin()
-            .read_line(&mut input)
-            .with_context(|| "failed to read command".to_string())?;
-        let command = input.trim();
-
-        match command {
-            "run" => {
-                emu.run().with_context(|| "stop emulator".to_string())?;
-                input.clear();
-            }
-            "s" => {
-                emu.step().with_context(|| "stop emulator".to_string())?;
-                input.clear();
-            }
-            "step" => {
-                input.clear();
-                print!("num > ");
-                io::stdout().flush().unwrap();
-                io::stdin()
-                    .read_line(&mut input)
-                    .with_context(|| "failed to read command".to_string())?;
-
-                match input.trim().parse::<u32>() {
-                    Ok(n) => {
-                        for _i in 0..n {
-                            emu.step().with_context(|| "stop emulator".to_string())?;
-                        }
-                        input.clear();
-                    }
-                    Err(_) => {
-                        println!("invalid num");
-                        input.clear();
-                    }
-                };
-            }
-            "b" | "breakpoint" => {
-                input.clear();
-                print!("break point address > ");
-                io::stdout().flush().unwrap();
-                io::stdin()
-                    .read_line(&mut input)
-                    .with_context(|| "failed to read command".to_string())?;
-
-                match input.trim().parse::<u32>() {
-                    Ok(n) => {
-                        emu.break_point = n;
-                        input.clear();
-                    }
-                    Err(_) => {
-                        println!("invalid num");
-                        input.clear();
-                    }
-                };
-            }
-            "m" | "mem" => {
-                input.clear();
-                print!("address > ");
-                io::stdout().flush().unwrap();
-                io::stdin()
-                    .read_line(&mut input)
-                    .with_context(|| "failed to read command".to_string())?;
-
-                match input.trim().parse::<u32>() {
-                    Ok(n) => {
-                        if n >= emulator::memory::MEMORY_SIZE {
-                            println!("invalid address");
-                            input.clear();
-                        } else {
-                            println!("mem[{}] = 0x{:02x}", n, emu.memory.memory_array[n as usize]);
-                            input.clear();
-                        }
-                    }
-                    Err(_) => {
-                        println!("invalid address");
-                        input.clear();
-                    }
-                };
-            }
-            "wm" | "writemem" => {
-                input.clear();
-                print!("address > ");
-                io::stdout().flush().unwrap();
-                io::stdin()
-                    .read_line(&mut input)
-                    .with_context(|| "failed to read command".to_string())?;
-
-                match input.trim().parse::<u32>() {
-                    Ok(n) => {
-                        if n >= emulator::memory::MEMORY_SIZE {
-                            println!("invalid address");
-                            input.clear();
-                        } else {
-                            input.clear();
-                            print!("data > ");
-                            io::stdout().flush().unwrap();
-                            io::stdin()
-                                .read_line(&mut input)
-                                .with_context(|| "failed to read command".to_string())?;
-
-                            match input.trim().parse::<u8>() {
-                                Ok(d) => {
-                                    emu.memory.memory_array[n as usize] = d;
-                                    println!(
-                                        "mem[{}] = 0x{:02x}",
-                                        n, emu.memory.memory_array[n as usize]
-                                    );
-                                    input.clear();
-                                }
-                                Err(_) => {
-                                    println!("invalid data");
-                                    input.clear();
-                                }
-                            };
-                        }
-                    }
-                    Err(_) => {
-                        println!("invalid address");
-                        input.clear();
-                    }
-                };
-            }
-
-            "r" | "reg" => {
-                input.clear();
-                print!("register num > ");
-                io::stdout().flush().unwrap();
-                io::stdin()
-                    .read_line(&mut input)
-                    .with_context(|| "failed to read command".to_string())?;
+    let mut lines = None;
+    if let Some(path) = &args.line_map {
+        let f = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read file '{}'", path.display()))?;
+        lines = Some(emulator::coverage::LineMap::parse(&f)?);
+    }
+    let mut coverage = None;
+    if args.coverage.is_some() || args.coverage_report.is_some() || args.lcov.is_some() {
+        let collector = emulator::coverage::Collector::default();
+        coverage = Some(collector.coverage.clone());
+        emu.add_hook(Box::new(collector));
+    }
 
-                match input.trim().parse::<u8>() {
-                    Ok(n) => {
-                        if n >= 32 {
-                            println!("invalid num");
-                            input.clear();
-                        } else {
-                            println!("register[{}] = 0x{:08x}", n, emu.cpu.get_reg(n));
-                            input.clear();
-                        }
-                    }
-                    Err(_) => {
-                        println!("invalid num");
-                        input.clear();
-                    }
-                };
-            }
-            "wr" | "writereg" => {
-                input.clear();
-                print!("register num > ");
-                io::stdout().flush().unwrap();
-                io::stdin()
-                    .read_line(&mut input)
-                    .with_context(|| "failed to read command".to_string())?;
+    if emu.engine == emulator::Engine::Threaded {
+        if let Some(reason) = emu.threaded_blocker() {
+            eprintln!("warning: run uses the interpreter because {}", reason);
+        }
+    }
 
-                match input.trim().parse::<u8>() {
-                    Ok(n) => {
-                        if n >= 32 {
-                            println!("invalid num");
-                            input.clear();
-                        } else {
-                            input.clear();
-                            print!("data > ");
-                            io::stdout().flush().unwrap();
-                            io::stdin()
-                                .read_line(&mut input)
-                                .with_context(|| "failed to read command".to_string())?;
+    // the input log is kept even when a script fails, to replay the failure
+    let result = session(&args, &mut emu);
+    if let Some(path) = &args.record {
+        let mut f = std::fs::File::create(path)
+            .with_context(|| format!("could not create file '{}'", path.display()))?;
+        emu.memory
+            .inputs
+            .save(&mut f)
+            .with_context(|| format!("could not write file '{}'", path.display()))?;
+    }
+    result?;
 
-                            match input.trim().parse::<u32>() {
-                                Ok(d) => {
-                                    emu.cpu.set_reg(n, d);
-                                    println!("register[{}] = 0x{:08x}", n, emu.cpu.get_reg(n));
-                                    input.clear();
-                                }
-                                Err(_) => {
-                                    println!("invalid data");
-                                    input.clear();
-                                }
-                            };
-                        }
-                    }
-                    Err(_) => {
-                        println!("invalid address");
-                        input.clear();
-                    }
-                };
-            }
-            "h" | "help" => {
-                println!("run           : continue to execute until break point");
-                println!();
-                println!("s, step       : step execute");
-                println!();
-                println!("b, breakpoint : set breakpoint");
-                println!();
-                println!("m, mem        : display data in memory");
-                println!();
-                println!("wm, writemem  : write data to memory");
-                println!();
-                println!("r, reg        : display data in register");
-                println!();
-                println!("wr, writereg  : write data to register");
-                println!();
-                println!("h, help       : show this message");
-                println!();
-                println!("finish        : finish emulator");
+    if emu.timing.is_some() {
+        println!(
+            "cycles : {}  instructions : {}  CPI : {:.3}",
+            emu.cycles,
+            emu.instret,
+            emu.cpi()
+        );
+    }
+    if let Some(profiler) = &emu.profiler {
+        profiler.report(&emu.symbols);
+        if let Some(path) = &args.profile_folded {
+            let mut f = std::fs::File::create(path)
+                .with_context(|| format!("could not create file '{}'", path.display()))?;
+            profiler
+                .write_folded(&mut f, &emu.symbols)
+                .with_context(|| format!("could not write file '{}'", path.display()))?;
+        }
+    }
 
-                input.clear();
-            }
-            "finish" => {
-                println!("finish emulator");
-                break;
-            }
-            _ => {
-                println!("command not found {}", command);
-                input.clear();
-            }
+    if let Some(coverage) = coverage {
+        let mut coverage = coverage.borrow().clone();
+        if let Some(path) = args.coverage.as_ref().filter(|path| path.exists()) {
+            let f = std::fs::read_to_string(path)
+                .with_context(|| format!("could not read file '{}'", path.display()))?;
+            coverage.merge(
+                &emulator::coverage::Coverage::parse(&f)
+                    .with_context(|| format!("could not parse file '{}'", path.display()))?,
+            );
+        }
+        coverage.report(&emu, end);
+        if let Some(path) = &args.coverage {
+            let mut f = std::fs::File::create(path)
+                .with_context(|| format!("could not create file '{}'", path.display()))?;
+            coverage
+                .save(&mut f)
+                .with_context(|| format!("could not write file '{}'", path.display()))?;
+        }
+        if let Some(path) = &args.coverage_report {
+            let mut f = std::fs::File::create(path)
+                .with_context(|| format!("could not create file '{}'", path.display()))?;
+            coverage
+                .write_annotated(&mut f, &emu, end)
+                .with_context(|| format!("could not write file '{}'", path.display()))?;
+        }
+        if let (Some(path), Some(lines)) = (&args.lcov, &lines) {
+            let mut f = std::fs::File::create(path)
+                .with_context(|| format!("could not create file '{}'", path.display()))?;
+            coverage
+                .write_lcov(&mut f, &emu, end, lines)
+                .with_context(|| format!("could not write file '{}'", path.display()))?;
         }
     }
 
+    if let Some(code) = emu.exit_code {
+        std::process::exit(code as i32);
+    }
     Ok(())
 }
@@ -0,0 +1,434 @@
+// Rhai scripts driving the emulator, see README "Rhai scripts" for the API
+use anyhow::{anyhow, Context, Result};
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, FuncArgs, NativeCallContext, INT};
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::emulator::hooks::Hook;
+use crate::emulator::memory::{self, Access};
+use crate::emulator::Emulator;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+#[derive(Default)]
+struct Hooks {
+    step: Vec<FnPtr>,
+    // run before the instruction at the address
+    pc: Vec<(u32, FnPtr)>,
+    read: Vec<FnPtr>,
+    write: Vec<FnPtr>,
+}
+
+struct State {
+    emu: Emulator,
+    hooks: Hooks,
+    accesses: Rc<RefCell<Vec<Access>>>,
+    stopped: bool,
+    seed: u64,
+}
+
+type Shared = Rc<RefCell<State>>;
+
+// collects the loads and stores of the current instruction for on_read and on_write
+#[derive(Debug)]
+struct Recorder {
+    accesses: Rc<RefCell<Vec<Access>>>,
+}
+
+impl Hook for Recorder {
+    fn mem_read(&mut self, _: &Emulator, address: u32, size: u32, data: u32) {
+        self.accesses.borrow_mut().push(Access {
+            address,
+            size,
+            data,
+            write: false,
+        });
+    }
+
+    fn mem_write(&mut self, _: &Emulator, address: u32, size: u32, data: u32) {
+        self.accesses.borrow_mut().push(Access {
+            address,
+            size,
+            data,
+            write: true,
+        });
+    }
+}
+
+pub fn run_file(emu: &mut Emulator, path: &Path) -> Result<()> {
+    let script = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read file '{}'", path.display()))?;
+    run(emu, &path.display().to_string(), &script)
+}
+
+// name is only used in error messages
+pub fn run(emu: &mut Emulator, name: &str, script: &str) -> Result<()> {
+    let accesses = Rc::new(RefCell::new(Vec::new()));
+    emu.add_hook(Box::new(Recorder {
+        accesses: accesses.clone(),
+    }));
+    let shared = Rc::new(RefCell::new(State {
+        emu: std::mem::replace(emu, Emulator::new(Vec::new())),
+        hooks: Hooks::default(),
+        accesses,
+        stopped: false,
+        seed: 0x2545_F491_4F6C_DD1D,
+    }));
+
+    let result = {
+        let engine = engine(&shared);
+        engine.run(script)
+    };
+    let state = Rc::try_unwrap(shared)
+        .ok()
+        .expect("script engine is dropped")
+        .into_inner();
+    *emu = state.emu;
+    emu.hooks.pop();
+    result.map_err(|error| anyhow!("{}: {}", name, error))
+}
+
+fn engine(shared: &Shared) -> Engine {
+    let mut engine = Engine::new();
+
+    let s = shared.clone();
+    engine.register_fn("reg", move |n: INT| -> ScriptResult<INT> {
+        Ok(s.borrow().emu.cpu.get_reg(register(n)?) as INT)
+    });
+    let s = shared.clone();
+    engine.register_fn("set_reg", move |n: INT, data: INT| -> ScriptResult<()> {
+        s.borrow_mut().emu.cpu.set_reg(register(n)?, data as u32);
+        Ok(())
+    });
+    let s = shared.clone();
+    engine.register_fn("pc", move || s.borrow().emu.cpu.pc as INT);
+    let s = shared.clone();
+    engine.register_fn("set_pc", move |pc: INT| {
+        s.borrow_mut().emu.cpu.pc = pc as u32
+    });
+    let s = shared.clone();
+    engine.register_fn("instret", move || s.borrow().emu.instret as INT);
+    let s = shared.clone();
+    engine.register_fn("cycles", move || s.borrow().emu.cycles as INT);
+
+    // reads have no side effects on devices, like the debugger's
+    let s = shared.clone();
+    engine.register_fn("read8", move |address: INT| -> ScriptResult<INT> {
+        let data = s.borrow().emu.memory.peek_data(address as u32, 1);
+        data.map(INT::from)
+            .map_err(|error| error.to_string().into())
+    });
+    let s = shared.clone();
+    engine.register_fn("read16", move |address: INT| -> ScriptResult<INT> {
+        let data = s.borrow().emu.memory.peek_data(address as u32, 2);
+        data.map(INT::from)
+            .map_err(|error| error.to_string().into())
+    });
+    let s = shared.clone();
+    engine.register_fn("read32", move |address: INT| -> ScriptResult<INT> {
+        let data = s.borrow().emu.memory.peek_data(address as u32, 4);
+        data.map(INT::from)
+            .map_err(|error| error.to_string().into())
+    });
+    let s = shared.clone();
+    engine.register_fn(
+        "write8",
+        move |address: INT, data: INT| -> ScriptResult<()> {
+            let result = s
+                .borrow_mut()
+                .emu
+                .memory
+                .write_data_8bit(address as u32, data as u8);
+            result.map_err(|error| error.to_string().into())
+        },
+    );
+    let s = shared.clone();
+    engine.register_fn(
+        "write16",
+        move |address: INT, data: INT| -> ScriptResult<()> {
+            let result = s
+                .borrow_mut()
+                .emu
+                .memory
+                .write_data_16bit(address as u32, data as u16);
+            result.map_err(|error| error.to_string().into())
+        },
+    );
+    let s = shared.clone();
+    engine.register_fn(
+        "write32",
+        move |address: INT, data: INT| -> ScriptResult<()> {
+            let result = s
+                .borrow_mut()
+                .emu
+                .memory
+                .write_data(address as u32, data as u32);
+            result.map_err(|error| error.to_string().into())
+        },
+    );
+
+    let s = shared.clone();
+    engine.register_fn("uart_input", move |text: &str| {
+        s.borrow_mut().emu.memory.uart_input.extend(text.bytes())
+    });
+    let s = shared.clone();
+    engine.register_fn("uart_output", move || {
+        String::from_utf8_lossy(&s.borrow().emu.memory.uart).into_owned()
+    });
+
+    let s = shared.clone();
+    engine.register_fn("breakpoint", move |address: INT| {
+        s.borrow_mut().emu.break_point = address as u32
+    });
+    let s = shared.clone();
+    engine.register_fn("clear_breakpoint", move || {
+        s.borrow_mut().emu.break_point = u32::MAX
+    });
+
+    let s = shared.clone();
+    engine.register_fn("on_step", move |f: FnPtr| s.borrow_mut().hooks.step.push(f));
+    let s = shared.clone();
+    engine.register_fn("on_pc", move |address: INT, f: FnPtr| {
+        s.borrow_mut().hooks.pc.push((address as u32, f))
+    });
+    let s = shared.clone();
+    engine.register_fn("on_read", move |f: FnPtr| s.borrow_mut().hooks.read.push(f));
+    let s = shared.clone();
+    engine.register_fn("on_write", move |f: FnPtr| {
+        s.borrow_mut().hooks.write.push(f)
+    });
+
+    let s = shared.clone();
+    engine.register_fn("step", move |ctx: NativeCallContext| step(&ctx, &s));
+    let s = shared.clone();
+    engine.register_fn(
+        "step",
+        move |ctx: NativeCallContext, n: INT| -> ScriptResult<()> {
+            for _ in 0..n {
+                step(&ctx, &s)?;
+            }
+            Ok(())
+        },
+    );
+    let s = shared.clone();
+    engine.register_fn("run", move |ctx: NativeCallContext| {
+        run_until(&ctx, &s, INT::MAX)
+    });
+    let s = shared.clone();
+    engine.register_fn("run", move |ctx: NativeCallContext, max: INT| {
+        run_until(&ctx, &s, max)
+    });
+    let s = shared.clone();
+    engine.register_fn("stop", move || s.borrow_mut().stopped = true);
+
+    // xorshift64*, reproducible unless seeded
+    let s = shared.clone();
+    engine.register_fn("seed", move |seed: INT| {
+        s.borrow_mut().seed = (seed as u64).max(1)
+    });
+    let s = shared.clone();
+    engine.register_fn("random", move || {
+        let mut state = s.borrow_mut();
+        state.seed ^= state.seed >> 12;
+        state.seed ^= state.seed << 25;
+        state.seed ^= state.seed >> 27;
+        (state.seed.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as INT
+    });
+
+    engine.register_fn("assert", |condition: bool| -> ScriptResult<()> {
+        if condition {
+            Ok(())
+        } else {
+            Err("assertion failed".into())
+        }
+    });
+    engine.register_fn(
+        "assert",
+        |condition: bool, message: &str| -> ScriptResult<()> {
+            if condition {
+                Ok(())
+            } else {
+                Err(format!("assertion failed: {}", message).into())
+            }
+        },
+    );
+
+    engine
+}
+
+fn register(n: INT) -> ScriptResult<u8> {
+    if (0..32).contains(&n) {
+        Ok(n as u8)
+    } else {
+        Err(format!("r{} is not a register", n).into())
+    }
+}
+
+// hooks may return anything, the value is ignored
+fn call(ctx: &NativeCallContext, f: &FnPtr, args: impl FuncArgs) -> ScriptResult<()> {
+    f.call_within_context::<Dynamic>(ctx, args).map(|_| ())
+}
+
+// one instruction with its hooks, the state is not borrowed while hooks run
+fn step(ctx: &NativeCallContext, shared: &Shared) -> ScriptResult<()> {
+    let pc = shared.borrow().emu.cpu.pc;
+    let hooks = shared
+        .borrow()
+        .hooks
+        .pc
+        .iter()
+        .filter(|(address, _)| *address == pc)
+        .map(|(_, f)| f.clone())
+        .collect::<Vec<_>>();
+    for f in hooks {
+        call(ctx, &f, ())?;
+    }
+
+    let pc = shared.borrow().emu.cpu.pc;
+    shared.borrow().accesses.borrow_mut().clear();
+    shared
+        .borrow_mut()
+        .emu
+        .step()
+        .map_err(|error| error.to_string())?;
+
+    let accesses = shared.borrow().accesses.take();
+    for access in accesses {
+        let hooks = if access.write {
+            shared.borrow().hooks.write.clone()
+        } else {
+            shared.borrow().hooks.read.clone()
+        };
+        for f in hooks {
+            let args = (
+                access.address as INT,
+                access.size as INT,
+                access.data as INT,
+            );
+            call(ctx, &f, args)?;
+        }
+    }
+
+    let hooks = shared.borrow().hooks.step.clone();
+    for f in hooks {
+        call(ctx, &f, (pc as INT,))?;
+    }
+    Ok(())
+}
+
+// why execution stopped: "breakpoint", "halt", "exit", "stop", "end" of memory
+// or "limit"
+fn run_until(ctx: &NativeCallContext, shared: &Shared, max: INT) -> ScriptResult<String> {
+    for _ in 0..max {
+        let pc = shared.borrow().emu.cpu.pc;
+        step(ctx, shared)?;
+        let mut state = shared.borrow_mut();
+        let reason = if std::mem::take(&mut state.stopped) {
+            "stop"
+        } else if state.emu.exit_code.is_some() {
+            "exit"
+        } else if state.emu.cpu.pc == state.emu.break_point {
+            "breakpoint"
+        } else if state.emu.cpu.pc == pc {
+            "halt"
+        } else if state.emu.cpu.pc >= memory::MEMORY_SIZE {
+            "end"
+        } else {
+            continue;
+        };
+        return Ok(reason.to_string());
+    }
+    Ok("limit".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::reg::*;
+    use crate::asm::Asm;
+
+    // echoes UART input to the output until it reads a zero byte
+    fn echo() -> Emulator {
+        Asm::new()
+            .label("loop")
+            .lbu(r1, r0, -252)
+            .beq(r1, r0, "done")
+            .sb(r1, r0, -256)
+            .j("loop")
+            .label("done")
+            .halt()
+            .emulator()
+            .unwrap()
+    }
+
+    #[test]
+    fn drives_the_emulator() {
+        let mut emu = echo();
+        let script = r#"
+            on_pc(0, || if instret() == 0 { uart_input("hi") });
+            let writes = [];
+            on_write(|address, size, data| writes.push(data));
+            let steps = 0;
+            on_step(|pc| steps += 1);
+            assert(run(1000) == "halt", "halts");
+            assert(uart_output() == "hi");
+            assert(writes == [104, 105]);
+            assert(steps == instret());
+            set_reg(5, 0x1234);
+            write32(0x200, reg(5) + 1);
+            assert(read32(0x200) == 0x1235);
+        "#;
+        run(&mut emu, "test", script).unwrap();
+        assert_eq!(emu.memory.uart, b"hi");
+        assert_eq!(emu.cpu.get_reg(5), 0x1234);
+    }
+
+    #[test]
+    fn hooks_see_accesses_and_can_stop() {
+        let mut emu = echo();
+        let script = r#"
+            uart_input("abc");
+            on_read(|address, size, data| {
+                assert(address == 0xffffff04 && size == 1);
+                if data == 98 { stop() }
+            });
+            assert(run() == "stop");
+            assert(uart_output() == "a");
+            breakpoint(pc() + 8);
+            assert(run() == "breakpoint");
+            assert(uart_output() == "ab");
+        "#;
+        run(&mut emu, "test", script).unwrap();
+    }
+
+    #[test]
+    fn reads_leave_devices_alone() {
+        let mut emu = echo();
+        emu.memory.inputs = crate::emulator::replay::Inputs::record();
+        let script = r#"
+            uart_input("a");
+            let failed = false;
+            try { read8(0xffffff04) } catch { failed = true }
+            assert(failed);
+        "#;
+        run(&mut emu, "test", script).unwrap();
+        assert_eq!(emu.memory.uart_input, [b'a']);
+        assert!(emu.memory.inputs.events.is_empty());
+    }
+
+    #[test]
+    fn errors_name_the_script() {
+        let mut emu = echo();
+        let error = run(
+            &mut emu,
+            "test",
+            "set_reg(1, 5);\nassert(reg(1) == 4, \"r1\");",
+        )
+        .unwrap_err();
+        assert!(error.to_string().starts_with("test: "), "{}", error);
+        assert!(error.to_string().contains("assertion failed: r1"));
+        assert_eq!(emu.cpu.get_reg(1), 5);
+        assert!(run(&mut emu, "test", "reg(32)").is_err());
+    }
+}
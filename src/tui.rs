@@ -0,0 +1,359 @@
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::time::Duration;
+
+use ktc32_emu::emulator::icache::Decoded;
+use ktc32_emu::emulator::memory::MEMORY_SIZE;
+use ktc32_emu::emulator::Emulator;
+
+// instructions executed between redraws while continuing
+const SLICE: usize = 10_000;
+const BYTES_PER_ROW: u32 = 8;
+const HELP: &str =
+    "s step  c continue  r reverse-step  b breakpoint  h next hart  ↑↓ cursor  PgUp/PgDn memory  q quit";
+
+struct App {
+    // disassembly cursor, None follows pc
+    cursor: Option<u32>,
+    memory_base: u32,
+    running: bool,
+    status: String,
+    // state before the last command, for highlighting changes
+    register: [u32; 32],
+    memory: Vec<u8>,
+}
+
+pub fn run(emu: &mut Emulator) -> Result<()> {
+    emu.trace = false;
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, emu);
+    ratatui::restore();
+    result
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, emu: &mut Emulator) -> Result<()> {
+    let mut app = App {
+        cursor: None,
+        memory_base: 0,
+        running: false,
+        status: String::new(),
+        register: emu.cpu.register,
+        memory: emu.memory.memory_array.clone(),
+    };
+
+    loop {
+        terminal.draw(|frame| app.draw(frame, emu))?;
+
+        let timeout = if app.running {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(250)
+        };
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('q') => return Ok(()),
+                    KeyCode::Esc if app.running => {
+                        app.running = false;
+                        app.status = "paused".to_string();
+                    }
+                    KeyCode::Char('s') | KeyCode::F(10) if !app.running => {
+                        app.snapshot(emu);
+                        app.status = match emu.step() {
+                            Ok(_) => String::new(),
+                            Err(error) => error.to_string(),
+                        };
+                    }
+                    KeyCode::Char('c') | KeyCode::F(5) => {
+                        if !app.running {
+                            app.snapshot(emu);
+                            app.status = "running, Esc to pause".to_string();
+                        }
+                        app.running = !app.running;
+                    }
+                    KeyCode::Char('r') if !app.running => {
+                        app.snapshot(emu);
+                        if !emu.reverse_step() {
+                            app.status = "no more history".to_string();
+                        }
+                    }
+                    KeyCode::Char('b') | KeyCode::F(9) => app.toggle_breakpoint(emu),
+                    KeyCode::Char('h') if !app.running => {
+                        let hart = (emu.hart + 1) % emu.harts.len();
+                        emu.select(hart)?;
+                        app.snapshot(emu);
+                        app.cursor = None;
+                    }
+                    KeyCode::Up => app.move_cursor(emu, -1),
+                    KeyCode::Down => app.move_cursor(emu, 1),
+                    KeyCode::PageUp => {
+                        app.memory_base = app.memory_base.saturating_sub(BYTES_PER_ROW * 8)
+                    }
+                    KeyCode::PageDown => {
+                        app.memory_base =
+                            (app.memory_base + BYTES_PER_ROW * 8).min(MEMORY_SIZE - BYTES_PER_ROW)
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if app.running {
+            app.continue_slice(emu);
+        }
+    }
+}
+
+// instruction boundaries found by a linear sweep, restarted at pc when the
+// sweep from address 0 does not land on it
+fn listing(emu: &Emulator) -> Vec<(u32, Decoded)> {
+    let sweep = |mut address: u32| {
+        let mut rows = Vec::new();
+        while let Some(decoded) = emu.disassemble(address) {
+            rows.push((address, decoded));
+            address += decoded.length;
+        }
+        rows
+    };
+    let rows = sweep(0);
+    if rows.iter().any(|(address, _)| *address == emu.cpu.pc) {
+        rows
+    } else {
+        sweep(emu.cpu.pc)
+    }
+}
+
+impl App {
+    fn snapshot(&mut self, emu: &Emulator) {
+        self.cursor = None;
+        self.register = emu.cpu.register;
+        self.memory.clone_from(&emu.memory.memory_array);
+    }
+
+    fn continue_slice(&mut self, emu: &mut Emulator) {
+        for _ in 0..SLICE {
+            let pc = emu.cpu.pc;
+            if let Err(error) = emu.step() {
+                self.running = false;
+                self.status = error.to_string();
+                return;
+            }
+            if let Some(code) = emu.exit_code {
+                self.running = false;
+                self.status = format!("exited with code {}", code);
+                return;
+            }
+            let stop = if emu.cpu.pc == emu.break_point {
+                "breakpoint"
+            } else if emu.cpu.pc == pc {
+                "halted"
+            } else if emu.cpu.pc >= MEMORY_SIZE {
+                "pc is out of range of memory"
+            } else {
+                continue;
+            };
+            self.running = false;
+            self.status = format!("{} at 0x{:08x}", stop, emu.cpu.pc);
+            return;
+        }
+    }
+
+    fn focus(&self, emu: &Emulator) -> u32 {
+        self.cursor.unwrap_or(emu.cpu.pc)
+    }
+
+    fn move_cursor(&mut self, emu: &Emulator, delta: isize) {
+        let rows = listing(emu);
+        let focus = self.focus(emu);
+        if let Some(index) = rows.iter().position(|(address, _)| *address == focus) {
+            let index = index.saturating_add_signed(delta).min(rows.len() - 1);
+            self.cursor = Some(rows[index].0);
+        }
+    }
+
+    fn toggle_breakpoint(&mut self, emu: &mut Emulator) {
+        let address = self.focus(emu);
+        if emu.break_point == address {
+            emu.break_point = u32::MAX;
+            self.status = "breakpoint cleared".to_string();
+        } else {
+            emu.break_point = address;
+            self.status = format!("breakpoint at 0x{:08x}", address);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame, emu: &Emulator) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [code, side] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(main);
+        let [registers, memory, uart] = Layout::vertical([
+            Constraint::Length(11),
+            Constraint::Min(4),
+            Constraint::Length(8),
+        ])
+        .areas(side);
+
+        self.draw_code(frame, code, emu);
+        self.draw_registers(frame, registers, emu);
+        self.draw_memory(frame, memory, emu);
+        draw_uart(frame, uart, emu);
+
+        let status_line = Line::from(vec![
+            Span::styled(HELP, Style::new().fg(Color::DarkGray)),
+            Span::raw("  "),
+            Span::styled(self.status.as_str(), Style::new().fg(Color::Yellow)),
+        ]);
+        frame.render_widget(Paragraph::new(status_line), status);
+    }
+
+    fn draw_code(&self, frame: &mut Frame, area: Rect, emu: &Emulator) {
+        let rows = listing(emu);
+        let focus = self.focus(emu);
+        let height = area.height.saturating_sub(2) as usize;
+        let index = rows
+            .iter()
+            .position(|(address, _)| *address == focus)
+            .unwrap_or(0);
+        let start = index
+            .saturating_sub(height / 2)
+            .min(rows.len().saturating_sub(height));
+
+        let lines = rows
+            .iter()
+            .skip(start)
+            .take(height)
+            .map(|(address, decoded)| {
+                let marker = if *address == emu.break_point {
+                    "●"
+                } else {
+                    " "
+                };
+                let arrow = if *address == emu.cpu.pc { ">" } else { " " };
+                let word = if decoded.length == 4 {
+                    format!("{:08x}", decoded.word)
+                } else {
+                    format!("    {:04x}", decoded.word)
+                };
+                let label = match emu.symbols.lookup(*address) {
+                    Some((name, 0)) => format!(" <{}>", name),
+                    _ => String::new(),
+                };
+                let mut style = Style::new();
+                if *address == emu.cpu.pc {
+                    style = style.fg(Color::Green).add_modifier(Modifier::BOLD);
+                }
+                if self.cursor == Some(*address) {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+                Line::from(vec![
+                    Span::styled(marker, Style::new().fg(Color::Red)),
+                    Span::styled(
+                        format!(
+                            "{} 0x{:08x}  {}  {}{}",
+                            arrow,
+                            address,
+                            word,
+                            emu.format_inst(&decoded.inst),
+                            label
+                        ),
+                        style,
+                    ),
+                ])
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" disassembly ")),
+            area,
+        );
+    }
+
+    fn draw_registers(&self, frame: &mut Frame, area: Rect, emu: &Emulator) {
+        let mut lines = (0..8)
+            .map(|row| {
+                let spans = (0..4)
+                    .map(|column| {
+                        let num = column * 8 + row;
+                        let data = emu.cpu.get_reg(num as u8);
+                        let style = if data != self.register[num] && num != 0 {
+                            Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+                        } else {
+                            Style::new()
+                        };
+                        Span::styled(format!("r{:<2} {:08x}  ", num, data), style)
+                    })
+                    .collect::<Vec<_>>();
+                Line::from(spans)
+            })
+            .collect::<Vec<_>>();
+        lines.push(Line::from(format!(
+            "pc  {:08x}  instructions {}  cycles {}",
+            emu.cpu.pc, emu.instret, emu.cycles
+        )));
+        let title = if emu.harts.len() > 1 {
+            format!(" registers (hart {}) ", emu.hart)
+        } else {
+            " registers ".to_string()
+        };
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect, emu: &Emulator) {
+        let memory = &emu.memory.memory_array;
+        let rows = area.height.saturating_sub(2) as u32;
+        let lines = (0..rows)
+            .map(|row| self.memory_base + row * BYTES_PER_ROW)
+            .filter(|&address| address < memory.len() as u32)
+            .map(|address| {
+                let mut spans = vec![Span::raw(format!("{:08x} ", address))];
+                let mut ascii = String::new();
+                for offset in address..(address + BYTES_PER_ROW).min(memory.len() as u32) {
+                    let byte = memory[offset as usize];
+                    let style = if self.memory.get(offset as usize) != Some(&byte) {
+                        Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD)
+                    } else {
+                        Style::new()
+                    };
+                    spans.push(Span::styled(format!(" {:02x}", byte), style));
+                    ascii.push(if byte.is_ascii_graphic() {
+                        byte as char
+                    } else {
+                        '.'
+                    });
+                }
+                spans.push(Span::raw(format!("  {}", ascii)));
+                Line::from(spans)
+            })
+            .collect::<Vec<_>>();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(" memory ")),
+            area,
+        );
+    }
+}
+
+fn draw_uart(frame: &mut Frame, area: Rect, emu: &Emulator) {
+    let text = String::from_utf8_lossy(&emu.memory.uart);
+    let lines = text.lines().map(Line::from).collect::<Vec<_>>();
+    let scroll = lines
+        .len()
+        .saturating_sub(area.height.saturating_sub(2) as usize);
+    frame.render_widget(
+        Paragraph::new(lines)
+            .scroll((scroll as u16, 0))
+            .block(Block::bordered().title(" uart ")),
+        area,
+    );
+}
@@ -0,0 +1,367 @@
+// Differential fuzzing of the decoder and executor against an independent,
+// table-driven description of every KTC32 instruction.
+use ktc32_emu::emulator::encoder::encode;
+use ktc32_emu::emulator::isa::{Extension, Profile};
+use ktc32_emu::emulator::memory::{HART_ID, LOCK, MEMORY_SIZE, UART_RX, UART_STATUS};
+use ktc32_emu::emulator::{Emulator, Type};
+use proptest::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    R,
+    I16,
+    I32,
+    J,
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    pc: u32,
+    register: [u32; 32],
+    memory: Vec<u8>,
+}
+
+impl State {
+    fn get(&self, num: u32) -> u32 {
+        if num == 0 {
+            0
+        } else {
+            self.register[num as usize]
+        }
+    }
+
+    fn set(&mut self, num: u32, data: u32) {
+        if num != 0 {
+            self.register[num as usize] = data;
+        }
+    }
+
+    fn load(&self, address: u32, size: u32) -> Option<u32> {
+        // device registers, no input is waiting, hart 0 and a free lock
+        if size == 1 && [UART_RX, UART_STATUS, HART_ID, LOCK].contains(&address) {
+            return Some(0);
+        }
+        if address.checked_add(size)? > MEMORY_SIZE {
+            return None;
+        }
+        Some((0..size).fold(0, |data, i| {
+            data | (self.memory[(address + i) as usize] as u32) << (8 * i)
+        }))
+    }
+
+    fn store(&mut self, address: u32, size: u32, data: u32) {
+        if address
+            .checked_add(size)
+            .is_some_and(|end| end <= MEMORY_SIZE)
+        {
+            for i in 0..size {
+                self.memory[(address + i) as usize] = (data >> (8 * i)) as u8;
+            }
+        }
+    }
+
+    fn branch(&mut self, taken: bool, imm: i32) {
+        if taken {
+            self.pc = self.pc.wrapping_add(imm as u32);
+        }
+    }
+}
+
+type Semantics = fn(&mut State, u32, u32, i32);
+
+struct Spec {
+    mnemonic: &'static str,
+    format: Format,
+    opcode: u32,
+    semantics: Semantics,
+}
+
+macro_rules! spec {
+    ($mnemonic:literal, $format:ident, $opcode:literal, $semantics:expr) => {
+        Spec {
+            mnemonic: $mnemonic,
+            format: Format::$format,
+            opcode: $opcode,
+            semantics: $semantics,
+        }
+    };
+}
+
+// pc already points at the next instruction when the semantics run
+const SPECS: &[Spec] = &[
+    spec!("MOV", R, 0b000000, |s, rd, rs, _| s.set(rd, s.get(rs))),
+    spec!("ADD", R, 0b000001, |s, rd, rs, _| {
+        s.set(rd, s.get(rd).wrapping_add(s.get(rs)))
+    }),
+    spec!("SUB", R, 0b000010, |s, rd, rs, _| {
+        s.set(rd, s.get(rd).wrapping_sub(s.get(rs)))
+    }),
+    spec!("AND", R, 0b000011, |s, rd, rs, _| {
+        s.set(rd, s.get(rd) & s.get(rs))
+    }),
+    spec!("OR", R, 0b000100, |s, rd, rs, _| {
+        s.set(rd, s.get(rd) | s.get(rs))
+    }),
+    spec!("XOR", R, 0b000101, |s, rd, rs, _| {
+        s.set(rd, s.get(rd) ^ s.get(rs))
+    }),
+    spec!("SLL", R, 0b000110, |s, rd, rs, _| {
+        s.set(rd, s.get(rd).wrapping_shl(s.get(rs)))
+    }),
+    spec!("SRL", R, 0b000111, |s, rd, rs, _| {
+        s.set(rd, s.get(rd).wrapping_shr(s.get(rs)))
+    }),
+    spec!("SRA", R, 0b001000, |s, rd, rs, _| {
+        s.set(rd, (s.get(rd) as i32).wrapping_shr(s.get(rs)) as u32)
+    }),
+    spec!("SLT", R, 0b001001, |s, rd, rs, _| {
+        s.set(31, ((s.get(rd) as i32) < (s.get(rs) as i32)) as u32)
+    }),
+    spec!("SLTU", R, 0b001010, |s, rd, rs, _| {
+        s.set(31, (s.get(rd) < s.get(rs)) as u32)
+    }),
+    spec!("MUL", R, 0b011000, |s, rd, rs, _| {
+        s.set(rd, s.get(rd).wrapping_mul(s.get(rs)))
+    }),
+    spec!("MULH", R, 0b011001, |s, rd, rs, _| {
+        let product = s.get(rd) as i32 as i64 * s.get(rs) as i32 as i64;
+        s.set(rd, (product >> 32) as u32)
+    }),
+    spec!("MULHU", R, 0b011010, |s, rd, rs, _| {
+        let product = s.get(rd) as u64 * s.get(rs) as u64;
+        s.set(rd, (product >> 32) as u32)
+    }),
+    spec!("DIV", R, 0b011011, |s, rd, rs, _| {
+        let (a, b) = (s.get(rd) as i32, s.get(rs) as i32);
+        let quotient = match b {
+            0 => -1,
+            -1 => a.wrapping_neg(),
+            _ => a / b,
+        };
+        s.set(rd, quotient as u32)
+    }),
+    spec!("DIVU", R, 0b011100, |s, rd, rs, _| {
+        let (a, b) = (s.get(rd), s.get(rs));
+        s.set(rd, a.checked_div(b).unwrap_or(u32::MAX))
+    }),
+    spec!("REM", R, 0b011101, |s, rd, rs, _| {
+        let (a, b) = (s.get(rd) as i32, s.get(rs) as i32);
+        let remainder = match b {
+            0 => a,
+            -1 => 0,
+            _ => a % b,
+        };
+        s.set(rd, remainder as u32)
+    }),
+    spec!("REMU", R, 0b011110, |s, rd, rs, _| {
+        let (a, b) = (s.get(rd), s.get(rs));
+        s.set(rd, if b == 0 { a } else { a % b })
+    }),
+    spec!("SLLI", I16, 0b010000, |s, rd, _, imm| {
+        s.set(rd, s.get(rd) << imm)
+    }),
+    spec!("SRLI", I16, 0b010001, |s, rd, _, imm| {
+        s.set(rd, s.get(rd) >> imm)
+    }),
+    spec!("SRAI", I16, 0b010010, |s, rd, _, imm| {
+        s.set(rd, ((s.get(rd) as i32) >> imm) as u32)
+    }),
+    // unhandled without semihosting, the instruction only advances pc
+    spec!("TRAP", I16, 0b010011, |_, _, _, _| {}),
+    spec!("ADDI", I32, 0b100000, |s, rd, rs, imm| {
+        s.set(rd, s.get(rs).wrapping_add(imm as u32))
+    }),
+    spec!("ANDI", I32, 0b100001, |s, rd, rs, imm| {
+        s.set(rd, s.get(rs) & imm as u32)
+    }),
+    spec!("ORI", I32, 0b100010, |s, rd, rs, imm| {
+        s.set(rd, s.get(rs) | imm as u32)
+    }),
+    spec!("XORI", I32, 0b100011, |s, rd, rs, imm| {
+        s.set(rd, s.get(rs) ^ imm as u32)
+    }),
+    spec!("SLTI", I32, 0b100100, |s, rd, rs, imm| {
+        s.set(rd, ((s.get(rs) as i32) < imm) as u32)
+    }),
+    spec!("SLTIU", I32, 0b100101, |s, rd, rs, imm| {
+        s.set(rd, (s.get(rs) < imm as u32) as u32)
+    }),
+    spec!("BEQ", I32, 0b100110, |s, rd, rs, imm| {
+        s.branch(s.get(rd) == s.get(rs), imm)
+    }),
+    spec!("BNQ", I32, 0b100111, |s, rd, rs, imm| {
+        s.branch(s.get(rd) != s.get(rs), imm)
+    }),
+    spec!("BLT", I32, 0b101000, |s, rd, rs, imm| {
+        s.branch((s.get(rd) as i32) < (s.get(rs) as i32), imm)
+    }),
+    spec!("BGE", I32, 0b101001, |s, rd, rs, imm| {
+        s.branch((s.get(rd) as i32) >= (s.get(rs) as i32), imm)
+    }),
+    spec!("BLTU", I32, 0b101010, |s, rd, rs, imm| {
+        s.branch(s.get(rd) < s.get(rs), imm)
+    }),
+    spec!("BGEU", I32, 0b101011, |s, rd, rs, imm| {
+        s.branch(s.get(rd) >= s.get(rs), imm)
+    }),
+    spec!("JALR", I32, 0b101100, |s, rd, rs, imm| {
+        let target = s.get(rs).wrapping_add(imm as u32);
+        s.set(rd, s.pc);
+        s.pc = target;
+    }),
+    spec!("LB", I32, 0b101101, |s, rd, rs, imm| {
+        if let Some(data) = s.load(s.get(rs).wrapping_add(imm as u32), 1) {
+            s.set(rd, data as u8 as i8 as u32)
+        }
+    }),
+    spec!("LH", I32, 0b101110, |s, rd, rs, imm| {
+        if let Some(data) = s.load(s.get(rs).wrapping_add(imm as u32), 2) {
+            s.set(rd, data as u16 as i16 as u32)
+        }
+    }),
+    spec!("LBU", I32, 0b101111, |s, rd, rs, imm| {
+        if let Some(data) = s.load(s.get(rs).wrapping_add(imm as u32), 1) {
+            s.set(rd, data)
+        }
+    }),
+    spec!("LHU", I32, 0b110000, |s, rd, rs, imm| {
+        if let Some(data) = s.load(s.get(rs).wrapping_add(imm as u32), 2) {
+            s.set(rd, data)
+        }
+    }),
+    spec!("LW", I32, 0b110001, |s, rd, rs, imm| {
+        if let Some(data) = s.load(s.get(rs).wrapping_add(imm as u32), 4) {
+            s.set(rd, data)
+        }
+    }),
+    spec!("LUI", I32, 0b110010, |s, rd, _, imm| {
+        s.set(rd, (imm as u32) << 16)
+    }),
+    spec!("SB", I32, 0b110011, |s, rd, rs, imm| {
+        s.store(s.get(rs).wrapping_add(imm as u32), 1, s.get(rd))
+    }),
+    spec!("SH", I32, 0b110100, |s, rd, rs, imm| {
+        s.store(s.get(rs).wrapping_add(imm as u32), 2, s.get(rd))
+    }),
+    spec!("SW", I32, 0b110101, |s, rd, rs, imm| {
+        s.store(s.get(rs).wrapping_add(imm as u32), 4, s.get(rd))
+    }),
+    spec!("JAL", J, 0b111111, |s, rd, _, imm| {
+        s.set(rd, s.pc);
+        s.pc = s.pc.wrapping_add(imm as u32);
+    }),
+];
+
+fn spec(opcode: u32, long: bool) -> Option<&'static Spec> {
+    SPECS.iter().find(|spec| {
+        spec.opcode == opcode && (spec.format == Format::I32 || spec.format == Format::J) == long
+    })
+}
+
+// fields of a word as the table reads them, (rd, rs, imm)
+fn fields(word: u32, format: Format) -> (u32, u32, i32) {
+    let rd = (word >> 6) & 0x1F;
+    let rs = (word >> 11) & 0x1F;
+    match format {
+        Format::R => (rd, rs, 0),
+        Format::I16 => (rd, 0, rs as i32),
+        Format::I32 => (rd, rs, (word as i32) >> 16),
+        Format::J => (rd, 0, (word as i32) >> 11),
+    }
+}
+
+fn decode(word: u32) -> Type {
+    if word & 0x20 != 0 {
+        Emulator::decode_32(word)
+    } else {
+        Emulator::decode_16(word as u16)
+    }
+}
+
+prop_compose! {
+    fn machine()(
+        register in prop::array::uniform32(prop_oneof![
+            any::<u32>(),
+            0..(MEMORY_SIZE + 4),
+            Just(0x8000_0000),
+            (u32::MAX - 3)..=u32::MAX,
+        ]),
+        memory in prop::collection::vec(any::<u8>(), MEMORY_SIZE as usize),
+        pc in 0..(MEMORY_SIZE - 4),
+    ) -> State {
+        State { pc, register, memory }
+    }
+}
+
+proptest! {
+    #[test]
+    fn decode_encode_round_trip(word in any::<u32>()) {
+        let inst = decode(word);
+        let long = word & 0x20 != 0;
+        match spec(word & 0x3F, long) {
+            Some(spec) => {
+                prop_assert_eq!(inst.mnemonic(), spec.mnemonic);
+                let length = if long { 4 } else { 2 };
+                prop_assert_eq!(encode(&inst).unwrap(), word.to_le_bytes()[..length].to_vec());
+            }
+            None => prop_assert_eq!(inst.mnemonic(), "UNKNOWN"),
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip(
+        index in 0..SPECS.len(),
+        rd in 0..32u8,
+        rs in 0..32u8,
+        imm in -0x10_0000..0x10_0000,
+    ) {
+        let spec = &SPECS[index];
+        let inst = match spec.format {
+            Format::R => Type::RFormat { mnemonic: spec.mnemonic, rd, rs },
+            Format::I16 => Type::I16Format { mnemonic: spec.mnemonic, rd, imm: rs },
+            Format::I32 => Type::I32Format { mnemonic: spec.mnemonic, rd, rs, imm: imm >> 5 },
+            Format::J => Type::JFormat { mnemonic: spec.mnemonic, rd, imm },
+        };
+        let bytes = encode(&inst).unwrap();
+        let mut word = [0; 4];
+        word[..bytes.len()].copy_from_slice(&bytes);
+        prop_assert_eq!(decode(u32::from_le_bytes(word)), inst);
+    }
+
+    #[test]
+    fn execute_matches_reference(word in any::<u32>(), mut state in machine()) {
+        let long = word & 0x20 != 0;
+        let length = if long { 4 } else { 2 };
+        let spec = spec(word & 0x3F, long);
+        let (rd, rs, imm) = spec.map_or((0, 0, 0), |spec| fields(word, spec.format));
+
+        for i in 0..length {
+            state.memory[(state.pc + i) as usize] = (word >> (8 * i)) as u8;
+        }
+
+        let mut emu = Emulator::new(state.memory.clone());
+        emu.trace = false;
+        emu.isa = Profile::BASE.with(Extension::M);
+        emu.cpu.pc = state.pc;
+        emu.cpu.register = state.register;
+        emu.cpu.register[0] = 0;
+        let result = emu.step();
+
+        // illegal instructions stop without retiring
+        let mut expected = state;
+        match spec {
+            Some(spec) => {
+                result.unwrap();
+                expected.pc += length;
+                (spec.semantics)(&mut expected, rd, rs, imm);
+            }
+            None => prop_assert!(result.is_err()),
+        }
+
+        prop_assert_eq!(emu.cpu.pc, expected.pc);
+        for num in 1..32 {
+            prop_assert_eq!(emu.cpu.get_reg(num as u8), expected.get(num), "r{}", num);
+        }
+        prop_assert!(emu.memory.memory_array == expected.memory);
+    }
+}
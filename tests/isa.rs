@@ -0,0 +1,331 @@
+// Self-checking KTC32 programs, one per instruction. Every check folds
+// (result ^ expected) into r30, so a program passes when r30 ends up zero.
+use ktc32_emu::asm::reg::*;
+use ktc32_emu::asm::Asm;
+use ktc32_emu::assert_reg;
+
+const SCRATCH: u8 = r27;
+const RESULT: u8 = r30;
+const DATA: i32 = 0x200;
+
+trait Checks {
+    fn check(&mut self, reg: u8, expected: u32) -> &mut Self;
+    fn binary(
+        &mut self,
+        op: fn(&mut Asm, u8, u8) -> &mut Asm,
+        rd: u32,
+        rs: u32,
+        expected: u32,
+    ) -> &mut Self;
+    fn shift_imm(
+        &mut self,
+        op: fn(&mut Asm, u8, u8) -> &mut Asm,
+        rd: u32,
+        imm: u8,
+        expected: u32,
+    ) -> &mut Self;
+    fn imm(
+        &mut self,
+        op: fn(&mut Asm, u8, u8, i32) -> &mut Asm,
+        rs: u32,
+        imm: i32,
+        expected: u32,
+    ) -> &mut Self;
+    fn branch(
+        &mut self,
+        op: for<'a> fn(&'a mut Asm, u8, u8, &str) -> &'a mut Asm,
+        a: u32,
+        b: u32,
+        taken: bool,
+    ) -> &mut Self;
+    fn run(&mut self);
+}
+
+impl Checks for Asm {
+    fn check(&mut self, reg: u8, expected: u32) -> &mut Self {
+        self.li(SCRATCH, expected)
+            .xor(SCRATCH, reg)
+            .or(RESULT, SCRATCH)
+    }
+
+    // rd op= rs with both operands loaded first
+    fn binary(
+        &mut self,
+        op: fn(&mut Asm, u8, u8) -> &mut Asm,
+        rd: u32,
+        rs: u32,
+        expected: u32,
+    ) -> &mut Self {
+        op(self.li(r1, rd).li(r2, rs), r1, r2).check(r1, expected)
+    }
+
+    fn shift_imm(
+        &mut self,
+        op: fn(&mut Asm, u8, u8) -> &mut Asm,
+        rd: u32,
+        imm: u8,
+        expected: u32,
+    ) -> &mut Self {
+        op(self.li(r1, rd), r1, imm).check(r1, expected)
+    }
+
+    fn imm(
+        &mut self,
+        op: fn(&mut Asm, u8, u8, i32) -> &mut Asm,
+        rs: u32,
+        imm: i32,
+        expected: u32,
+    ) -> &mut Self {
+        op(self.li(r2, rs), r1, r2, imm).check(r1, expected)
+    }
+
+    // r5 stays zero when the branch skips the ADDI after it
+    fn branch(
+        &mut self,
+        op: for<'a> fn(&'a mut Asm, u8, u8, &str) -> &'a mut Asm,
+        a: u32,
+        b: u32,
+        taken: bool,
+    ) -> &mut Self {
+        let skip = format!("skip_{}", self.pc());
+        op(self.li(r1, a).li(r2, b).li(r5, 0), r1, r2, &skip)
+            .addi(r5, r0, 1)
+            .label(&skip)
+            .check(r5, u32::from(!taken))
+    }
+
+    fn run(&mut self) {
+        let end = self.pc();
+        let mut emu = self.emulator().unwrap();
+        emu.run().unwrap();
+        assert_eq!(emu.cpu.pc, end);
+        assert_reg!(emu, RESULT, 0);
+    }
+}
+
+#[test]
+fn mov() {
+    Asm::new()
+        .li(r2, 0xDEAD_BEEF)
+        .mov(r1, r2)
+        .check(r1, 0xDEAD_BEEF)
+        .mov(r0, r2)
+        .check(r0, 0)
+        .run();
+}
+
+#[test]
+fn add() {
+    Asm::new()
+        .binary(Asm::add, 2, 3, 5)
+        .binary(Asm::add, 0x7FFF_FFFF, 1, 0x8000_0000)
+        .binary(Asm::add, 0xFFFF_FFFF, 2, 1)
+        .run();
+}
+
+#[test]
+fn sub() {
+    Asm::new()
+        .binary(Asm::sub, 5, 3, 2)
+        .binary(Asm::sub, 0, 1, 0xFFFF_FFFF)
+        .binary(Asm::sub, 0x8000_0000, 1, 0x7FFF_FFFF)
+        .run();
+}
+
+#[test]
+fn logic() {
+    Asm::new()
+        .binary(Asm::and, 0xF0F0_1234, 0xFF00_FFFF, 0xF000_1234)
+        .binary(Asm::or, 0xF0F0_0000, 0x0F00_00FF, 0xFFF0_00FF)
+        .binary(Asm::xor, 0xFFFF_0000, 0xF0F0_F0F0, 0x0F0F_F0F0)
+        .run();
+}
+
+#[test]
+fn shifts() {
+    Asm::new()
+        .binary(Asm::sll, 0x8000_0001, 4, 0x0000_0010)
+        .binary(Asm::sll, 1, 33, 2)
+        .binary(Asm::srl, 0x8000_0000, 4, 0x0800_0000)
+        .binary(Asm::srl, 0x8000_0000, 63, 1)
+        .binary(Asm::sra, 0x8000_0000, 4, 0xF800_0000)
+        .binary(Asm::sra, 0x4000_0000, 36, 0x0400_0000)
+        .run();
+}
+
+#[test]
+fn shift_immediates() {
+    Asm::new()
+        .shift_imm(Asm::slli, 0x0000_0003, 31, 0x8000_0000)
+        .shift_imm(Asm::srli, 0xF000_0000, 28, 0xF)
+        .shift_imm(Asm::srai, 0xF000_0000, 28, 0xFFFF_FFFF)
+        .shift_imm(Asm::srai, 0x7000_0000, 28, 0x7)
+        .run();
+}
+
+#[test]
+fn set_less_than() {
+    // SLT and SLTU report into r31
+    Asm::new()
+        .li(r1, 0xFFFF_FFFF)
+        .li(r2, 1)
+        .slt(r1, r2)
+        .check(r31, 1)
+        .sltu(r1, r2)
+        .check(r31, 0)
+        .slt(r2, r1)
+        .check(r31, 0)
+        .sltu(r2, r1)
+        .check(r31, 1)
+        .slt(r2, r2)
+        .check(r31, 0)
+        .run();
+}
+
+#[test]
+fn alu_immediates() {
+    Asm::new()
+        .imm(Asm::addi, 10, -11, 0xFFFF_FFFF)
+        .imm(Asm::addi, 0x7FFF_FFFF, 1, 0x8000_0000)
+        .imm(Asm::andi, 0xFFFF_FFFF, -2, 0xFFFF_FFFE)
+        .imm(Asm::andi, 0xFFFF_FFFF, 0x7FFF, 0x7FFF)
+        .imm(Asm::ori, 0x10, -0x8000, 0xFFFF_8010)
+        .imm(Asm::xori, 0x0F, -1, 0xFFFF_FFF0)
+        .imm(Asm::slti, 0xFFFF_FFFF, 0, 1)
+        .imm(Asm::slti, 5, -1, 0)
+        .imm(Asm::sltiu, 0xFFFF_FFFF, 0, 0)
+        .imm(Asm::sltiu, 5, -1, 1)
+        .run();
+}
+
+#[test]
+fn lui() {
+    Asm::new()
+        .lui(r1, 0x1234)
+        .check(r1, 0x1234_0000)
+        .lui(r1, -1)
+        .check(r1, 0xFFFF_0000)
+        .run();
+}
+
+#[test]
+fn branches() {
+    Asm::new()
+        .branch(Asm::beq, 3, 3, true)
+        .branch(Asm::beq, 3, 4, false)
+        .branch(Asm::bnq, 3, 4, true)
+        .branch(Asm::bnq, 3, 3, false)
+        .branch(Asm::blt, 0xFFFF_FFFF, 0, true)
+        .branch(Asm::blt, 0, 0xFFFF_FFFF, false)
+        .branch(Asm::blt, 2, 2, false)
+        .branch(Asm::bge, 0, 0xFFFF_FFFF, true)
+        .branch(Asm::bge, 2, 2, true)
+        .branch(Asm::bge, 0xFFFF_FFFF, 0, false)
+        .branch(Asm::bltu, 0, 0xFFFF_FFFF, true)
+        .branch(Asm::bltu, 0xFFFF_FFFF, 0, false)
+        .branch(Asm::bgeu, 0xFFFF_FFFF, 0, true)
+        .branch(Asm::bgeu, 0, 0xFFFF_FFFF, false)
+        .run();
+}
+
+#[test]
+fn backward_branch_loop() {
+    Asm::new()
+        .li(r1, 10)
+        .li(r2, 0)
+        .label("loop")
+        .addi(r2, r2, 3)
+        .addi(r1, r1, -1)
+        .bnq(r1, r0, "loop")
+        .check(r2, 30)
+        .run();
+}
+
+#[test]
+fn jal() {
+    let mut program = Asm::new();
+    program.li(r5, 0);
+    let link = program.pc() + 4;
+    program
+        .jal(r1, "linked")
+        .addi(r5, r0, 1)
+        .label("linked")
+        .check(r5, 0)
+        .check(r1, link)
+        .jal(r0, "unlinked")
+        .addi(r5, r0, 1)
+        .label("unlinked")
+        .check(r5, 0)
+        .check(r0, 0)
+        .run();
+}
+
+#[test]
+fn jalr() {
+    // the target skips the ADDI right after the JALR
+    let mut program = Asm::new();
+    program.li(r5, 0);
+    let base = program.pc() + 8;
+    program.li(r2, base);
+    let link = program.pc() + 4;
+    program
+        .jalr(r3, r2, (link + 4 - base) as i32)
+        .addi(r5, r0, 1)
+        .check(r5, 0)
+        .check(r3, link);
+
+    // rd == rs jumps through the old value
+    let target = program.pc() + 8 + 4 + 4;
+    program.li(r4, target);
+    let link = program.pc() + 4;
+    program
+        .jalr(r4, r4, 0)
+        .addi(r5, r0, 1)
+        .check(r5, 0)
+        .check(r4, link)
+        .run();
+}
+
+#[test]
+fn loads() {
+    Asm::new()
+        .li(r1, 0x8081_F0FF)
+        .sw(r1, r0, DATA)
+        .li(r2, DATA as u32 + 0x10)
+        .lb(r3, r2, -0x10)
+        .check(r3, 0xFFFF_FFFF)
+        .lbu(r3, r2, -0x10)
+        .check(r3, 0x0000_00FF)
+        .lb(r3, r2, -0x0D)
+        .check(r3, 0xFFFF_FF80)
+        .lh(r3, r2, -0x10)
+        .check(r3, 0xFFFF_F0FF)
+        .lhu(r3, r2, -0x10)
+        .check(r3, 0x0000_F0FF)
+        .lh(r3, r2, -0x0E)
+        .check(r3, 0xFFFF_8081)
+        .lw(r3, r2, -0x10)
+        .check(r3, 0x8081_F0FF)
+        .lw(r0, r2, -0x10)
+        .check(r0, 0)
+        .run();
+}
+
+#[test]
+fn stores() {
+    Asm::new()
+        .li(r1, 0x1122_3344)
+        .li(r2, DATA as u32)
+        .sw(r0, r2, 0)
+        .sw(r0, r2, 4)
+        .sb(r1, r2, 1)
+        .sh(r1, r2, 4)
+        .lw(r3, r2, 0)
+        .check(r3, 0x0000_4400)
+        .lw(r3, r2, 4)
+        .check(r3, 0x0000_3344)
+        .sw(r1, r2, 8)
+        .lw(r3, r2, 8)
+        .check(r3, 0x1122_3344)
+        .run();
+}